@@ -0,0 +1,15 @@
+CREATE TABLE channel_store_path (
+    channel    TEXT NOT NULL,
+    store_path TEXT NOT NULL,
+
+    PRIMARY KEY(channel, store_path)
+);
+
+CREATE INDEX channel_store_path_channel_index ON channel_store_path(channel);
+
+CREATE TABLE channel_sync (
+    channel     TEXT     NOT NULL UNIQUE PRIMARY KEY,
+    last_synced DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    num_paths   INTEGER  NOT NULL,
+    num_new     INTEGER  NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE channel_mirror (
+    channel    TEXT     NOT NULL UNIQUE PRIMARY KEY,
+    started    DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated    DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished   DATETIME,
+    num_paths  INTEGER  NOT NULL,
+    num_done   INTEGER  NOT NULL DEFAULT 0,
+    num_failed INTEGER  NOT NULL DEFAULT 0
+);
@@ -0,0 +1,4 @@
+CREATE TABLE locks (
+    hash      TEXT     NOT NULL UNIQUE PRIMARY KEY,
+    locked_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
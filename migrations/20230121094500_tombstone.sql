@@ -0,0 +1,5 @@
+CREATE TABLE tombstone (
+    hash    TEXT     NOT NULL UNIQUE PRIMARY KEY,
+    purged  DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires DATETIME NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE raw_narinfo (
+    hash    TEXT     NOT NULL UNIQUE PRIMARY KEY,
+    raw     TEXT     NOT NULL,
+    fetched DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY(hash) REFERENCES cache(hash)
+        ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Lets the largest entries be read off the end of the index instead of sorting every narinfo
+CREATE INDEX narinfo_file_size_index ON narinfo(file_size);
@@ -0,0 +1,3 @@
+-- Byte-identical nar files of different paths share a single file, see `count_nar_file_sharers`
+DROP INDEX narinfo_file_hash_index;
+CREATE INDEX narinfo_file_hash_index ON narinfo(file_hash);
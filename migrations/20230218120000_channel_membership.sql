@@ -0,0 +1 @@
+CREATE INDEX channel_store_path_store_path_index ON channel_store_path(store_path);
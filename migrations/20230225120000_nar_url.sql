@@ -0,0 +1,9 @@
+-- Where the nar file of each narinfo was fetched from, which its `URL` no longer says once an
+-- alternative compression is fetched, or once it is rewritten to the one served by this cache
+CREATE TABLE nar_url (
+    hash TEXT NOT NULL UNIQUE PRIMARY KEY,
+    url  TEXT NOT NULL,
+
+    FOREIGN KEY(hash) REFERENCES narinfo(hash)
+        ON DELETE CASCADE
+);
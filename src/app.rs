@@ -1,6 +1,8 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
-use crate::{cache, config, http, jobs};
+use anyhow::Context as _;
+
+use crate::{cache, config, fetch, http, jobs, nix};
 
 #[derive(Debug)]
 pub struct App {
@@ -8,6 +10,8 @@ pub struct App {
     server: http::Server,
     cache: cache::Cache,
     workers: jobs::Workers,
+    secret_key: Option<Arc<nix::SecretKey>>,
+    started: Instant,
 }
 
 #[derive(Clone, Debug)]
@@ -15,6 +19,8 @@ pub struct State {
     pub config: Arc<config::Config>,
     pub cache: cache::Cache,
     pub workers: jobs::Workers,
+    pub serve_stats: http::stats::ServeStats,
+    pub secret_key: Option<Arc<nix::SecretKey>>,
 }
 
 impl App {
@@ -22,16 +28,63 @@ impl App {
     pub async fn new() -> anyhow::Result<Self> {
         let config = config::Config::get();
 
-        let server = http::Server::new();
+        let errors = config.validate();
+        if !errors.is_empty() {
+            for e in &errors {
+                tracing::error!("{e:#}");
+            }
+            anyhow::bail!("Invalid config, found {} errors", errors.len());
+        }
+
+        fetch::init(&config)?;
+
+        let secret_key = config
+            .secret_key_file
+            .as_deref()
+            .map(nix::SecretKey::from_file)
+            .transpose()?
+            .map(Arc::new);
+
+        let server = http::Server::new(&config)?;
 
         let cache = cache::Cache::new(&config).await?;
-        let workers = jobs::Workers::new().await?;
+
+        let workers = jobs::Workers::new(&config).await?;
+
+        // before the server is run, so nothing is served off a bad nar file in the meantime
+        if config.verify_on_start != config::VerifyOnStart::Off && !config.proxy_only {
+            let sample_size = (config.verify_on_start == config::VerifyOnStart::Sampled)
+                .then_some(config.verify_on_start_sample_size);
+
+            let started = Instant::now();
+            let report = cache::verify_nar_files(&config, &cache, sample_size)
+                .await
+                .context("Failed to verify nar files")?;
+
+            let num_purged =
+                jobs::purge_and_refetch(&config, &cache, &mut workers.clone(), report.bad_hashes)
+                    .await
+                    .context("Failed to purge entries of bad nar files")?;
+
+            tracing::info!(
+                verified = report.num_verified,
+                corrupted = report.num_corrupted,
+                missing = report.num_missing,
+                failed = report.num_failed,
+                unchecked = report.num_unchecked,
+                entries_purged = num_purged,
+                elapsed = ?started.elapsed(),
+                "Verified nar files on start"
+            );
+        }
 
         Ok(Self {
             config,
             server,
             cache,
             workers,
+            secret_key,
+            started: Instant::now(),
         })
     }
 
@@ -40,16 +93,93 @@ impl App {
             config: Arc::new(self.config),
             cache: self.cache.clone(),
             workers: self.workers.clone(),
+            serve_stats: Default::default(),
+            secret_key: self.secret_key.clone(),
         };
 
+        jobs::enqueue_prefetch(&state.config, &state.cache, &mut state.workers.clone()).await?;
+        jobs::resume_channel_mirrors(&state.config, &state.cache, &mut state.workers.clone())
+            .await?;
+
+        tokio::spawn(
+            state
+                .cache
+                .clone()
+                .flush_accessed_periodically(state.config.clone()),
+        );
+
+        if state.config.statsd.is_some() {
+            tokio::spawn(http::statsd::push_periodically(state.clone()));
+        }
+
         tokio::try_join!(
             self.server.run(state.clone()),
             self.workers.run(state.clone()),
         )?;
 
+        tracing::info!("Flushing last_accessed times");
+        if let Err(e) = self.cache.flush_accessed().await {
+            tracing::error!("Failed to flush last_accessed times: {e:#}");
+        }
+
+        tracing::info!("Resetting entries left in-flight by the stopped workers");
+        let num_reset = match self.cache.reset_in_flight().await {
+            Ok(num_reset) => num_reset,
+            Err(e) => {
+                tracing::error!("Failed to reset in-flight entries: {e:#}");
+                0
+            }
+        };
+
+        let report = shutdown_report(&state, num_reset).await;
+
         tracing::info!("Cleaning up cache database");
         self.cache.db.cleanup().await;
 
+        match report {
+            Ok(report) => tracing::info!(
+                jobs_done = report.num_jobs_done,
+                jobs_pending = report.num_jobs_pending,
+                entries_reset = report.num_reset,
+                cache_size = report.cache_size,
+                uptime = ?self.started.elapsed(),
+                requests_served = report.num_requests,
+                "Shutdown report"
+            ),
+            Err(e) => tracing::error!("Failed to gather shutdown report: {e:#}"),
+        }
+
         Ok(())
     }
 }
+
+#[derive(Debug)]
+struct ShutdownReport {
+    num_jobs_done: i64,
+    num_jobs_pending: i64,
+    num_reset: u64,
+    cache_size: usize,
+    num_requests: u64,
+}
+
+async fn shutdown_report(state: &State, num_reset: u64) -> anyhow::Result<ShutdownReport> {
+    let (num_jobs_done, num_jobs_pending) = state
+        .workers
+        .clone()
+        .job_counts()
+        .await
+        .context("Failed to count jobs")?;
+
+    let cache_size = cache::db::get_reported_total_nar_size(state.cache.db.pool()).await?;
+
+    let num_requests =
+        state.serve_stats.nar_info.summary().total + state.serve_stats.nar_file.summary().total;
+
+    Ok(ShutdownReport {
+        num_jobs_done,
+        num_jobs_pending,
+        num_reset,
+        cache_size,
+        num_requests,
+    })
+}
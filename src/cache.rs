@@ -1,70 +1,740 @@
+mod bloom;
 pub mod db;
+pub mod responses;
 
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Context as _;
-use futures::TryStreamExt as _;
+use futures::{stream, StreamExt as _, TryStreamExt as _};
+use tokio::sync::Notify;
 
-use crate::{config, fetch, nix};
+use crate::{config, fetch, nix, transaction};
 
 const NAR_FILE_DIR: &str = "nar";
+const WRITE_PROBE_FILE: &str = ".write_probe";
+const WRITE_PROBE_LOCK: &str = ".db_write_probe";
+const TMP_FILE_EXT: &str = "tmp";
+const STORE_PATHS_BLOOM_FILE: &str = "store_paths.bloom";
+// Long enough to spare the db from repeated page loads, short enough to follow syncs and caching
+const CHANNEL_COVERAGE_TTL: Duration = Duration::from_secs(60);
 
 #[derive(Clone, Debug)]
 pub struct Cache {
     pub db: db::Database,
+    storage_writable: Arc<AtomicBool>,
+    db_writable: Arc<AtomicBool>,
+    accessed: Arc<Mutex<HashSet<String>>>,
+    miss_attempts: Arc<Mutex<HashMap<String, Instant>>>,
+    nar_serves: Arc<AtomicU64>,
+    nar_verified: Arc<Mutex<HashMap<String, Instant>>>,
+    in_flight: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+    // Partial nar files being written to, which nothing else may write to or resume meanwhile
+    partial_writes: Arc<Mutex<HashSet<PathBuf>>>,
+    channel_coverage: Arc<Mutex<Option<(Instant, ChannelCoverages)>>>,
+    last_wal_checkpoint: Arc<Mutex<Option<WalCheckpoint>>>,
+    pub nar_info_responses: responses::NarInfoResponses,
+}
+
+// How many of the store paths a channel listed at its last sync are cached
+#[derive(Clone, Debug)]
+pub struct ChannelCoverage {
+    pub channel: String,
+    pub num_cached: usize,
+    pub num_paths: usize,
+}
+
+type ChannelCoverages = Vec<ChannelCoverage>;
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub num_verified: usize,
+    pub num_corrupted: usize,
+    pub num_missing: usize,
+    // could not be read for other reasons, and are left as they are
+    pub num_failed: usize,
+    // FileHash not in sha256, which is all that can be checked
+    pub num_unchecked: usize,
+    // entries backed by a corrupted or missing nar file
+    pub bad_hashes: Vec<nix::Hash>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WalCheckpoint {
+    pub finished: Instant,
+    // not every frame could be checkpointed, as some reader was still using them
+    pub is_busy: bool,
+    pub wal_size_before: u64,
+    pub wal_size_after: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Local data path {0:?} is on a read-only filesystem")]
+    ReadOnly(PathBuf),
+    #[error("Local data path {0:?} is not writable due to insufficient permissions")]
+    PermissionDenied(PathBuf),
+    #[error("No space left on device for local data path {0:?}")]
+    Full(PathBuf),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Nar file hashes to {actual}, not the stated FileHash {expected}")]
+pub struct FileHashMismatch {
+    pub expected: nix::Hash,
+    pub actual: nix::Hash,
+}
+
+impl StorageError {
+    fn from_io_error(path: &Path, err: &io::Error) -> Option<Self> {
+        match err.kind() {
+            io::ErrorKind::ReadOnlyFilesystem => Some(Self::ReadOnly(path.to_owned())),
+            io::ErrorKind::PermissionDenied => Some(Self::PermissionDenied(path.to_owned())),
+            io::ErrorKind::StorageFull => Some(Self::Full(path.to_owned())),
+            _ => None,
+        }
+    }
 }
 
 impl Cache {
     #[tracing::instrument(name = "cache_init", skip(config))]
     pub async fn new(config: &config::Config) -> anyhow::Result<Self> {
-        {
+        if !config.proxy_only {
             tracing::trace!("Creating directory structure in data path");
-            tokio::fs::create_dir_all(config.local_data_path.join(NAR_FILE_DIR)).await?;
+            let nar_dir = config.local_data_path.join(NAR_FILE_DIR);
+            tokio::fs::create_dir_all(&nar_dir)
+                .await
+                .with_context(|| format!("Failed to create {}", nar_dir.display()))?;
         }
 
         let db = db::Database::new(config).await?;
 
-        Ok(Self { db })
+        // locks are only held while a job runs, so any left over are from a previous process
+        let num_cleared = db::clear_locks(db.pool()).await?;
+        if num_cleared > 0 {
+            tracing::warn!("Cleared {num_cleared} stale locks");
+        }
+
+        let cache = Self {
+            db,
+            storage_writable: Arc::new(AtomicBool::new(true)),
+            db_writable: Arc::new(AtomicBool::new(true)),
+            accessed: Arc::default(),
+            miss_attempts: Arc::default(),
+            nar_serves: Arc::default(),
+            nar_verified: Arc::default(),
+            in_flight: Arc::default(),
+            partial_writes: Arc::default(),
+            channel_coverage: Arc::default(),
+            last_wal_checkpoint: Arc::default(),
+            nar_info_responses: responses::NarInfoResponses::new(
+                config.nar_info_invalidation_history,
+            ),
+        };
+
+        // a previous process that crashed or was killed never reset the entries it was fetching
+        let num_reset = cache.reset_in_flight().await?;
+        if num_reset > 0 {
+            tracing::warn!("Reset {num_reset} entries left in-flight");
+        }
+
+        if config.proxy_only {
+            tracing::info!("Not using the local data path in proxy_only mode");
+        } else if let Err(e) = cache.probe_storage(config).await {
+            tracing::error!("Starting in degraded mode, nar files cannot be cached: {e:#}");
+        } else {
+            let (num_removed, num_kept) = remove_tmp_files(config)
+                .await
+                .context("Failed to remove leftover temporary nar files")?;
+
+            if num_removed > 0 {
+                tracing::warn!("Removed {num_removed} leftover temporary nar files");
+            }
+            if num_kept > 0 {
+                tracing::info!("Keeping {num_kept} partially downloaded nar files to resume");
+            }
+        }
+
+        Ok(cache)
+    }
+
+    // Number of entries dropped from each in-memory cache, `None` for those disabled by the config
+    pub fn flush_caches(&self, config: &config::Config) -> Vec<(&'static str, Option<usize>)> {
+        let nar_info_responses = self.nar_info_responses.clear();
+        let miss_attempts = std::mem::take(&mut *self.miss_attempts.lock().unwrap()).len();
+        let nar_verified = std::mem::take(&mut *self.nar_verified.lock().unwrap()).len();
+        let channel_coverage = self
+            .channel_coverage
+            .lock()
+            .unwrap()
+            .take()
+            .map_or(0, |(_, coverage)| coverage.len());
+
+        vec![
+            (
+                "narinfo responses",
+                (config.nar_info_cache_size > 0).then_some(nar_info_responses),
+            ),
+            (
+                "miss grace periods",
+                (config.miss_grace_period_secs > 0).then_some(miss_attempts),
+            ),
+            (
+                "nar verifications",
+                config.nar_revalidate_after_secs.map(|_| nar_verified),
+            ),
+            ("channel coverage", Some(channel_coverage)),
+        ]
+    }
+
+    // Only safe once no worker is fetching anything, i.e. before they start or after they stop
+    pub async fn reset_in_flight(&self) -> anyhow::Result<u64> {
+        let mut tx = transaction!(begin: self)?;
+        let num_reset = db::reset_in_flight(&mut tx).await?;
+        transaction!(commit: tx)?;
+
+        Ok(num_reset)
+    }
+
+    pub fn last_wal_checkpoint(&self) -> Option<WalCheckpoint> {
+        self.last_wal_checkpoint.lock().unwrap().clone()
+    }
+
+    pub async fn record_access(
+        &self,
+        config: &config::Config,
+        hash: &nix::Hash,
+    ) -> anyhow::Result<()> {
+        let num_accessed = {
+            let mut accessed = self.accessed.lock().unwrap();
+            accessed.insert(hash.string.clone());
+            accessed.len()
+        };
+
+        if num_accessed >= config.last_accessed_flush_threshold {
+            self.flush_accessed().await?;
+        }
+
+        Ok(())
+    }
+
+    // Debounces client-triggered fetches of the same hash, whatever the outcome of the last one
+    pub fn try_record_miss_attempt(&self, config: &config::Config, hash: &nix::Hash) -> bool {
+        let grace_period = Duration::from_secs(config.miss_grace_period_secs);
+        let now = Instant::now();
+
+        let mut attempts = self.miss_attempts.lock().unwrap();
+        attempts.retain(|_, attempted| now.duration_since(*attempted) < grace_period);
+
+        if attempts.contains_key(&hash.string) {
+            false
+        } else {
+            if !grace_period.is_zero() {
+                attempts.insert(hash.string.clone(), now);
+            }
+            true
+        }
+    }
+
+    // Registered by the job fetching `hash` before it starts, so that only fetches running in this
+    // process are waited on, never entries some earlier process left in-flight
+    pub fn start_in_flight(&self, hash: &nix::Hash) {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .insert(hash.string.clone(), Arc::default());
+    }
+
+    // Shared by every request waiting on the fetch of `hash`, notified once it is done with
+    pub fn in_flight_waiter(&self, hash: &nix::Hash) -> Option<Arc<Notify>> {
+        self.in_flight.lock().unwrap().get(&hash.string).cloned()
+    }
+
+    // Whether it succeeded or not, waiters look the nar file up again
+    pub fn finish_in_flight(&self, hash: &nix::Hash) {
+        if let Some(notify) = self.in_flight.lock().unwrap().remove(&hash.string) {
+            notify.notify_waiters();
+        }
+    }
+
+    // Re-hashes a sample of nar files older than `nar_revalidate_after_secs` before they are
+    // served, returning `false` only if the file no longer matches its file hash
+    #[tracing::instrument(skip(self, config))]
+    pub async fn revalidate_nar_file(
+        &self,
+        config: &config::Config,
+        nar_file: &nix::NarFileInfo,
+    ) -> anyhow::Result<bool> {
+        let Some(max_age) = config.nar_revalidate_after_secs.map(Duration::from_secs) else {
+            return Ok(true);
+        };
+
+        let num_served = self.nar_serves.fetch_add(1, Ordering::Relaxed);
+        if !num_served.is_multiple_of(config.nar_revalidate_sample_every) {
+            return Ok(true);
+        }
+
+        {
+            let now = Instant::now();
+            let mut verified = self.nar_verified.lock().unwrap();
+            verified.retain(|_, verified| now.duration_since(*verified) < max_age);
+
+            if verified.contains_key(&nar_file.hash.string) {
+                return Ok(true);
+            }
+        }
+
+        let path = nar_file_path_from_nar_file(config, nar_file);
+
+        let age = tokio::fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to get modified time of {}", path.display()))?
+            .elapsed()
+            .unwrap_or_default();
+        if age < max_age {
+            return Ok(true);
+        }
+
+        tracing::debug!("Revalidating {}", path.display());
+
+        let file_hash = hash_nar_file(path)
+            .await?
+            .context("Failed to hash nar file")?;
+
+        if file_hash.string != nar_file.hash.string {
+            return Ok(false);
+        }
+
+        self.nar_verified
+            .lock()
+            .unwrap()
+            .insert(nar_file.hash.string.clone(), Instant::now());
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn flush_accessed(&self) -> anyhow::Result<()> {
+        let accessed = std::mem::take(&mut *self.accessed.lock().unwrap());
+
+        if accessed.is_empty() {
+            return Ok(());
+        }
+
+        tracing::debug!("Flushing last_accessed of {} entries", accessed.len());
+
+        let res = async {
+            let mut tx = transaction!(begin: self)?;
+
+            for hash in accessed {
+                db::set_last_accessed(&mut tx, &nix::Hash::from_hash(hash)).await?;
+            }
+
+            transaction!(commit: tx)?;
+
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = &res {
+            self.check_db_error(e);
+        }
+
+        res
+    }
+
+    pub async fn flush_accessed_periodically(self, config: Arc<config::Config>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            config.last_accessed_flush_interval_secs.max(1),
+        ));
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.flush_accessed().await {
+                tracing::error!("Failed to flush last_accessed times: {e:#}");
+            }
+        }
+    }
+
+    pub fn is_storage_writable(&self) -> bool {
+        self.storage_writable.load(Ordering::Relaxed)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn probe_storage(&self, config: &config::Config) -> anyhow::Result<()> {
+        let probe_path = config
+            .local_data_path
+            .join(NAR_FILE_DIR)
+            .join(WRITE_PROBE_FILE);
+
+        tracing::debug!("Probing writability of {}", probe_path.display());
+
+        let res = async {
+            tokio::fs::write(&probe_path, b"nicacher").await?;
+            tokio::fs::remove_file(&probe_path).await
+        }
+        .await;
+
+        self.check_storage_result(config, res)
+            .with_context(|| format!("Failed to write probe file {}", probe_path.display()))
+    }
+
+    pub fn is_db_writable(&self) -> bool {
+        self.db_writable.load(Ordering::Relaxed)
+    }
+
+    // Enters degraded mode on `SQLITE_FULL`, only a successful `probe_db` leaves it
+    pub fn check_db_error(&self, err: &anyhow::Error) {
+        if db::is_full_error(err) && self.db_writable.swap(false, Ordering::Relaxed) {
+            tracing::error!("Cache database is full, entering degraded mode: {err:#}");
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn probe_db(&self) -> anyhow::Result<()> {
+        tracing::debug!("Probing writability of cache database");
+
+        let probe = nix::Hash::from_hash(WRITE_PROBE_LOCK.to_owned());
+        let res = async {
+            db::try_lock(self.db.pool(), &probe).await?;
+            db::unlock(self.db.pool(), &probe).await
+        }
+        .await;
+
+        match &res {
+            Ok(()) => {
+                if !self.db_writable.swap(true, Ordering::Relaxed) {
+                    tracing::info!("Cache database is writable again, leaving degraded mode");
+                }
+            }
+            Err(e) => self.check_db_error(e),
+        }
+
+        res.context("Failed to write probe lock to cache database")
+    }
+
+    fn check_storage_result<T>(
+        &self,
+        config: &config::Config,
+        res: io::Result<T>,
+    ) -> anyhow::Result<T> {
+        match res {
+            Ok(v) => {
+                if !self.storage_writable.swap(true, Ordering::Relaxed) {
+                    tracing::info!("Local data path is writable again, leaving degraded mode");
+                }
+
+                Ok(v)
+            }
+            Err(e) => match StorageError::from_io_error(&config.local_data_path, &e) {
+                Some(storage_err) => {
+                    if self.storage_writable.swap(false, Ordering::Relaxed) {
+                        tracing::error!("{storage_err}, entering degraded mode");
+                    }
+
+                    Err(anyhow::Error::new(e).context(storage_err))
+                }
+                None => Err(e.into()),
+            },
+        }
     }
 }
 
+// Returns the hash the file is stored under and its size. Each chunk is written before the next is
+// pulled from the upstream, so a slow disk slows the download down instead of piling it up in memory.
+// Fails with `FileHashMismatch` if it does not match its known hash, unless configured to accept it.
+// A nar file with a known hash is left partially written if its download is interrupted, for the
+// next attempt to resume
 #[tracing::instrument(skip_all)]
 pub async fn write_nar_file(
     config: &config::Config,
-    nar_file: &nix::NarFile,
-) -> anyhow::Result<()> {
+    cache: &Cache,
+    nar_file: nix::NarFile,
+) -> anyhow::Result<(nix::Hash, usize)> {
+    use sha2::Digest as _;
     use tokio::io::AsyncWriteExt as _;
 
-    let file_path = nar_file_path_from_nar_file(config, &nar_file.info);
+    if config.proxy_only {
+        anyhow::bail!("Not writing nar files in proxy_only mode");
+    }
+
+    let nix::NarFile {
+        compression,
+        hash,
+        offset,
+        mut body,
+        ..
+    } = nar_file;
+
+    // the final name of one fetched in an alternative compression is only known once it is hashed
+    let partial_path = hash
+        .as_ref()
+        .map(|hash| partial_nar_file_path(config, hash, &compression))
+        .filter(|path| cache.partial_writes.lock().unwrap().insert(path.clone()));
+
+    let tmp_file_path = match &partial_path {
+        Some(path) => path.clone(),
+        None if offset > 0 => anyhow::bail!("Partial nar file is already being written to"),
+        None => tmp_download_path(config),
+    };
+
+    tracing::debug!("Writing nar file to {}", tmp_file_path.display());
+
+    let mut hasher = sha2::Sha256::new();
+    let mut file_size = 0;
 
-    tracing::debug!("Writing nar file to {}", file_path.display());
+    // Written to a temporary file first so that a crash never leaves a truncated nar file
+    let res = async {
+        let file = if offset > 0 {
+            tracing::info!("Resuming {} from {offset} bytes", tmp_file_path.display());
 
-    tokio::fs::File::create(&file_path)
+            let (partial_hasher, len) = hash_partial_file(tmp_file_path.clone()).await?;
+            if len != offset {
+                return Err(io::Error::other(format!(
+                    "Partial nar file is {len} bytes, not the {offset} resumed from"
+                )));
+            }
+
+            hasher = partial_hasher;
+            file_size = len as usize;
+
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&tmp_file_path)
+                .await?
+        } else {
+            tokio::fs::File::create(&tmp_file_path).await?
+        };
+        let mut file = tokio::io::BufWriter::with_capacity(config.nar_write_buffer_size, file);
+
+        let downloaded = loop {
+            match body.try_next().await {
+                Ok(Some(chunk)) => {
+                    hasher.update(&chunk);
+                    file_size += chunk.len();
+                    file.write_all(&chunk).await?;
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(io::Error::other(e)),
+            }
+        };
+
+        // an interrupted download is kept up to where it got, to be resumed
+        file.flush().await?;
+        downloaded?;
+        file.get_ref().sync_all().await?;
+
+        let actual = nix::Hash::from_sha256_digest(&hasher.finalize());
+        let hash = match hash {
+            Some(expected) if expected.method != actual.method => {
+                tracing::debug!("Not verifying FileHash {expected}, only sha256 is supported");
+                expected
+            }
+            Some(expected) if expected.clone().normalized().string == actual.string => expected,
+            Some(expected)
+                if config.on_file_hash_mismatch == config::OnFileHashMismatch::Accept =>
+            {
+                tracing::warn!(
+                    "Nar file hashes to {actual}, not the stated FileHash {expected}, keeping it"
+                );
+                actual
+            }
+            Some(expected) => return Ok(Err(FileHashMismatch { expected, actual })),
+            None => actual,
+        };
+
+        let file_path = nar_file_path_from_parts(config, &hash, &compression);
+
+        tracing::debug!(
+            "Moving {} to {}",
+            tmp_file_path.display(),
+            file_path.display()
+        );
+
+        tokio::fs::rename(&tmp_file_path, &file_path).await?;
+
+        Ok(Ok((hash, file_size)))
+    }
+    .await;
+
+    // anything but an interrupted download of a partial file is started over next time
+    let is_resumable = partial_path.is_some() && res.is_err();
+    if !matches!(res, Ok(Ok(_))) && !is_resumable {
+        let _ = tokio::fs::remove_file(&tmp_file_path).await;
+    }
+
+    if let Some(path) = &partial_path {
+        cache.partial_writes.lock().unwrap().remove(path);
+    }
+
+    Ok(cache
+        .check_storage_result(config, res)
+        .context("Failed to write nar file")??)
+}
+
+// Partial nar files are kept to be resumed, returning how many were removed and kept
+#[tracing::instrument(skip_all)]
+pub async fn remove_tmp_files(config: &config::Config) -> anyhow::Result<(usize, usize)> {
+    let nar_dir = config.local_data_path.join(NAR_FILE_DIR);
+
+    let mut num_removed = 0;
+    let mut num_kept = 0;
+    let mut read_dir = tokio::fs::read_dir(&nar_dir)
         .await
-        .with_context(|| {
-            format!(
-                "Failed to create/open {} for writing nar file",
-                file_path.display()
-            )
-        })?
-        .write_all(&nar_file.data)
+        .with_context(|| format!("Failed to read {}", nar_dir.display()))?;
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+
+        if path.extension().is_some_and(|ext| ext == TMP_FILE_EXT) {
+            let is_partial = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| stem.parse::<nix::NarFileInfo>().is_ok());
+            if is_partial {
+                tracing::debug!("Keeping partial {}", path.display());
+                num_kept += 1;
+                continue;
+            }
+
+            tracing::debug!("Removing leftover {}", path.display());
+
+            tokio::fs::remove_file(&path)
+                .await
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+
+            num_removed += 1;
+        }
+    }
+
+    Ok((num_removed, num_kept))
+}
+
+pub fn partial_nar_file_path(
+    config: &config::Config,
+    file_hash: &nix::Hash,
+    compression: &nix::CompressionType,
+) -> PathBuf {
+    tmp_file_path(&nar_file_path_from_parts(config, file_hash, compression))
+}
+
+// Bytes of a nar file already downloaded by an earlier, interrupted attempt
+pub async fn partial_nar_file_len(
+    config: &config::Config,
+    file_hash: &nix::Hash,
+    compression: &nix::CompressionType,
+) -> u64 {
+    if config.proxy_only {
+        return 0;
+    }
+
+    tokio::fs::metadata(partial_nar_file_path(config, file_hash, compression))
+        .await
+        .map_or(0, |metadata| metadata.len())
+}
+
+async fn hash_partial_file(path: PathBuf) -> io::Result<(sha2::Sha256, u64)> {
+    tokio::task::spawn_blocking(move || {
+        use sha2::Digest as _;
+
+        let mut hasher = sha2::Sha256::new();
+        let len = io::copy(&mut std::fs::File::open(&path)?, &mut hasher)?;
+
+        Ok((hasher, len))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn tmp_download_path(config: &config::Config) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    config.local_data_path.join(NAR_FILE_DIR).join(format!(
+        "download-{}.{TMP_FILE_EXT}",
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn tmp_file_path(file_path: &Path) -> PathBuf {
+    let mut tmp_file_path = file_path.as_os_str().to_owned();
+    tmp_file_path.push(".");
+    tmp_file_path.push(TMP_FILE_EXT);
+    tmp_file_path.into()
+}
+
+// Of every configured channel, intersecting its last synced listing with the cached store paths.
+// Kept for `CHANNEL_COVERAGE_TTL`, as both sides are read in full
+#[tracing::instrument(skip_all)]
+pub async fn channel_coverage(
+    config: &config::Config,
+    cache: &Cache,
+) -> anyhow::Result<Vec<ChannelCoverage>> {
+    if let Some((computed, coverage)) = &*cache.channel_coverage.lock().unwrap() {
+        if computed.elapsed() < CHANNEL_COVERAGE_TTL {
+            return Ok(coverage.clone());
+        }
+    }
+
+    // entries cached before `canonicalize_store_paths` was enabled may not be canonical yet
+    let cached_store_paths = db::get_store_paths(cache.db.pool())
+        .map_ok(|store_path| config.ingest_store_path(store_path).to_string())
+        .try_collect::<HashSet<_>>()
         .await
-        .with_context(|| format!("Failed to write nar file to {}", file_path.display()))
+        .context("Failed to get cached store paths")?;
+
+    let mut coverage = Vec::with_capacity(config.channels.len());
+    for channel in &config.channels {
+        let store_paths = db::get_channel_store_paths(cache.db.pool(), channel)
+            .await
+            .with_context(|| format!("Failed to get last synced store paths of {channel}"))?;
+
+        coverage.push(ChannelCoverage {
+            channel: channel.to_string(),
+            num_cached: store_paths.intersection(&cached_store_paths).count(),
+            num_paths: store_paths.len(),
+        });
+    }
+
+    *cache.channel_coverage.lock().unwrap() = Some((Instant::now(), coverage.clone()));
+
+    Ok(coverage)
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn missing_from_channel_upstreams(
     config: &config::Config,
     cache: &Cache,
+    channel: Option<&nix::Channel>,
 ) -> anyhow::Result<HashSet<nix::StorePath>> {
+    if let Some(fp_rate) = config.diff_bloom_false_positive_rate {
+        return missing_from_channel_upstreams_bloom(config, cache, channel, fp_rate).await;
+    }
+
+    // entries cached before `canonicalize_store_paths` was enabled may not be canonical yet
     let cached_store_paths = db::get_store_paths(cache.db.pool())
+        .map_ok(|store_path| config.ingest_store_path(store_path))
         .try_collect::<HashSet<_>>()
         .await
         .context("Failed to get cached store paths")?;
 
-    let upstream_store_paths = fetch::request_all_channel_stores(config)
-        .await
-        .context("Failed to request up-to-date store paths from channel upstreams")?;
+    let upstream_store_paths = match channel {
+        Some(channel) => fetch::request_channel_store(config, channel)
+            .await
+            .with_context(|| format!("Failed to request up-to-date store paths of {channel}"))?,
+        None => fetch::request_all_channel_stores(config)
+            .await
+            .context("Failed to request up-to-date store paths from channel upstreams")?,
+    };
 
     tracing::debug!("Proccessing difference between local cache and upstream");
     Ok(upstream_store_paths
@@ -73,8 +743,230 @@ pub async fn missing_from_channel_upstreams(
         .collect())
 }
 
-pub fn nar_file_path(config: &config::Config, nar_info: &nix::NarInfo) -> PathBuf {
-    nar_file_path_from_parts(config, &nar_info.file_hash, &nar_info.compression)
+// Streams the upstream listings against a bloom filter of the cached paths, so neither side is
+// held in memory as a whole. A false positive leaves a missing path out of the diff
+async fn missing_from_channel_upstreams_bloom(
+    config: &config::Config,
+    cache: &Cache,
+    channel: Option<&nix::Channel>,
+    fp_rate: f64,
+) -> anyhow::Result<HashSet<nix::StorePath>> {
+    let cached_store_paths = cached_store_paths_bloom(config, cache, fp_rate)
+        .await
+        .context("Failed to build bloom filter of cached store paths")?;
+
+    let channels = match channel {
+        Some(channel) => vec![channel],
+        None => config.channels.iter().collect(),
+    };
+
+    tracing::debug!("Proccessing difference between local cache and upstream");
+
+    let mut missing = HashSet::new();
+    for channel in channels {
+        fetch::for_each_channel_store_path(config, channel, |store_path| {
+            if !cached_store_paths.contains(&store_path.to_string()) {
+                missing.insert(store_path);
+            }
+        })
+        .await
+        .with_context(|| format!("Failed to request up-to-date store paths of {channel}"))?;
+    }
+
+    Ok(missing)
+}
+
+// Reuses the filter persisted by a previous diff, unless the cached paths have changed since
+async fn cached_store_paths_bloom(
+    config: &config::Config,
+    cache: &Cache,
+    fp_rate: f64,
+) -> anyhow::Result<bloom::BloomFilter> {
+    let fingerprint = format!(
+        "{}:{}",
+        db::get_store_paths_fingerprint(cache.db.pool()).await?,
+        config.canonicalize_store_paths
+    );
+    let bloom_path = config.local_data_path.join(STORE_PATHS_BLOOM_FILE);
+
+    if let Ok(bytes) = tokio::fs::read(&bloom_path).await {
+        if let Some(bloom) = bloom::BloomFilter::from_bytes(&bytes, &fingerprint, fp_rate) {
+            tracing::debug!("Reusing bloom filter of cached store paths from {bloom_path:?}");
+            return Ok(bloom);
+        }
+    }
+
+    let num_store_paths = db::get_num_store_paths(cache.db.pool()).await?;
+    let mut bloom = bloom::BloomFilter::new(fingerprint, num_store_paths, fp_rate);
+
+    // entries cached before `canonicalize_store_paths` was enabled may not be canonical yet
+    let mut store_paths = db::get_store_paths(cache.db.pool());
+    while let Some(store_path) = store_paths.try_next().await? {
+        bloom.insert(&config.ingest_store_path(store_path).to_string());
+    }
+
+    tracing::info!(
+        "Built bloom filter of {num_store_paths} cached store paths in {} bytes",
+        bloom.num_bytes()
+    );
+
+    // only an optimisation, the next diff rebuilds it if this fails
+    let tmp_path = tmp_file_path(&bloom_path);
+    if let Err(e) = async {
+        tokio::fs::write(&tmp_path, bloom.to_bytes()).await?;
+        tokio::fs::rename(&tmp_path, &bloom_path).await
+    }
+    .await
+    {
+        tracing::warn!("Failed to persist bloom filter to {bloom_path:?}: {e}");
+    }
+
+    Ok(bloom)
+}
+
+// The inverse of `missing_from_channel_upstreams`: cached paths that are neither in any channel
+// nor in the closure of a cached path that is
+pub async fn stale_from_channel_upstreams(
+    config: &config::Config,
+    cache: &Cache,
+) -> anyhow::Result<Vec<nix::StorePath>> {
+    // every cached path would be stale otherwise
+    if config.channels.is_empty() {
+        anyhow::bail!("No channels configured to compare the cache against");
+    }
+
+    // fails if any channel fails, a partial listing must never be taken as the live set
+    let upstream_store_paths = fetch::request_all_channel_stores(config)
+        .await
+        .context("Failed to request up-to-date store paths from channel upstreams")?;
+
+    let entries = get_available_refs(cache).await?;
+
+    tracing::debug!("Proccessing closures of cached paths still in channels");
+
+    let live = closure_of(
+        &entries,
+        entries
+            .values()
+            .filter(|entry| {
+                ingested_store_path(config, entry)
+                    .is_ok_and(|store_path| upstream_store_paths.contains(&store_path))
+            })
+            .map(|entry| entry.hash.as_str()),
+    );
+
+    sorted_store_paths(
+        entries
+            .values()
+            .filter(|entry| !live.contains(entry.hash.as_str())),
+    )
+}
+
+// Cached paths listed by `channel` and by no other channel at their last sync, less those still in
+// the closure of a cached path that is not. Every configured channel must have a recorded listing,
+// as one without would count as listing nothing
+pub async fn only_in_channel(
+    config: &config::Config,
+    cache: &Cache,
+    channel: &nix::Channel,
+) -> anyhow::Result<Vec<nix::StorePath>> {
+    let recorded = db::get_recorded_channels(cache.db.pool())
+        .await
+        .context("Failed to get channels with recorded store paths")?;
+
+    let unrecorded = config
+        .channels
+        .iter()
+        .map(ToString::to_string)
+        .filter(|channel| !recorded.contains(channel))
+        .collect::<Vec<_>>();
+    if !unrecorded.is_empty() {
+        anyhow::bail!(
+            "No store paths recorded for {}, sync them first",
+            unrecorded.join(", ")
+        );
+    }
+
+    let only_in_channel = db::get_store_paths_only_in_channel(cache.db.pool(), channel)
+        .await
+        .with_context(|| format!("Failed to get store paths only in {channel}"))?;
+
+    let entries = get_available_refs(cache).await?;
+
+    let is_only_in_channel = |entry: &db::EntryRefs| {
+        ingested_store_path(config, entry)
+            .is_ok_and(|store_path| only_in_channel.contains(&store_path.to_string()))
+    };
+
+    let live = closure_of(
+        &entries,
+        entries
+            .values()
+            .filter(|entry| !is_only_in_channel(entry))
+            .map(|entry| entry.hash.as_str()),
+    );
+
+    sorted_store_paths(
+        entries
+            .values()
+            .filter(|entry| is_only_in_channel(entry) && !live.contains(entry.hash.as_str())),
+    )
+}
+
+async fn get_available_refs(cache: &Cache) -> anyhow::Result<HashMap<String, db::EntryRefs>> {
+    Ok(db::get_available_refs(cache.db.pool())
+        .await
+        .context("Failed to get references of cached entries")?
+        .into_iter()
+        .map(|entry| (entry.hash.clone(), entry))
+        .collect())
+}
+
+// entries cached before `canonicalize_store_paths` was enabled may not be canonical yet
+fn ingested_store_path(
+    config: &config::Config,
+    entry: &db::EntryRefs,
+) -> anyhow::Result<nix::StorePath> {
+    Ok(config.ingest_store_path(entry.store_path.parse()?))
+}
+
+// Hashes of `roots` and of every cached path they reference, directly or not
+fn closure_of<'a>(
+    entries: &'a HashMap<String, db::EntryRefs>,
+    roots: impl Iterator<Item = &'a str>,
+) -> HashSet<&'a str> {
+    let mut queue = roots.collect::<Vec<_>>();
+    let mut closure = queue.iter().copied().collect::<HashSet<_>>();
+
+    while let Some(hash) = queue.pop() {
+        let references = entries[hash]
+            .refs
+            .split_whitespace()
+            .filter_map(|reference| reference.split_once('-'))
+            .map(|(hash, _)| hash);
+
+        for reference in references {
+            if let Some((hash, _)) = entries.get_key_value(reference) {
+                if closure.insert(hash) {
+                    queue.push(hash);
+                }
+            }
+        }
+    }
+
+    closure
+}
+
+fn sorted_store_paths<'a>(
+    entries: impl Iterator<Item = &'a db::EntryRefs>,
+) -> anyhow::Result<Vec<nix::StorePath>> {
+    let mut store_paths = entries
+        .map(|entry| entry.store_path.parse::<nix::StorePath>())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid store path in cache db")?;
+    store_paths.sort_unstable_by_key(nix::StorePath::to_string);
+
+    Ok(store_paths)
 }
 
 pub fn nar_file_path_from_nar_file(
@@ -94,6 +986,147 @@ pub async fn nar_disk_size(config: &config::Config) -> tokio::io::Result<u64> {
     folder_size(&config.local_data_path.join(NAR_FILE_DIR)).await
 }
 
+// 0 when there is none, such as right after a truncating checkpoint or in proxy_only mode
+pub async fn wal_file_size(config: &config::Config) -> tokio::io::Result<u64> {
+    match tokio::fs::metadata(db::wal_file_path(config)).await {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+async fn hash_nar_file(path: PathBuf) -> anyhow::Result<io::Result<nix::Hash>> {
+    Ok(tokio::task::spawn_blocking(move || {
+        use sha2::Digest as _;
+
+        let mut hasher = sha2::Sha256::new();
+        io::copy(&mut std::fs::File::open(&path)?, &mut hasher)?;
+
+        Ok(nix::Hash::from_sha256_digest(&hasher.finalize()))
+    })
+    .await?)
+}
+
+// Nar files shared by several entries are only hashed once, reporting all of them if it is bad
+#[tracing::instrument(skip_all)]
+pub async fn verify_nar_files(
+    config: &config::Config,
+    cache: &Cache,
+    sample_size: Option<usize>,
+) -> anyhow::Result<VerifyReport> {
+    let entries = db::get_available_nar_files(cache.db.pool(), sample_size.map(|size| size as i64))
+        .await
+        .context("Failed to get nar files of available entries")?;
+
+    let mut nar_files = HashMap::<_, Vec<_>>::new();
+    for entry in entries {
+        nar_files
+            .entry((entry.file_hash_method, entry.file_hash, entry.compression))
+            .or_default()
+            .push(nix::Hash::from_hash(entry.hash));
+    }
+
+    let mut report = VerifyReport::default();
+
+    nar_files.retain(|(method, file_hash, _), _| {
+        let is_checked = nix::HashMethod::from(method.as_str()) == nix::HashMethod::Sha256();
+        if !is_checked {
+            tracing::debug!(
+                "Not verifying FileHash {method}:{file_hash}, only sha256 is supported"
+            );
+            report.num_unchecked += 1;
+        }
+        is_checked
+    });
+
+    tracing::info!("Verifying {} nar files", nar_files.len());
+
+    let mut results = stream::iter(nar_files)
+        .map(|((_, file_hash, compression), hashes)| async move {
+            let compression: nix::CompressionType = compression
+                .parse()
+                .context("Failed to parse compression type from cache db")?;
+            let path = nar_file_path_from_parts(
+                config,
+                &nix::Hash::from_hash(file_hash.clone()),
+                &compression,
+            );
+
+            let res = hash_nar_file(path.clone()).await?;
+            Ok::<_, anyhow::Error>((file_hash, path, hashes, res))
+        })
+        .buffer_unordered(config.verify_on_start_concurrency.max(1));
+
+    while let Some(ret) = results.next().await {
+        let (file_hash, path, hashes, res) = ret?;
+
+        match res {
+            Ok(actual) if nix::Hash::from_hash(file_hash).normalized().string == actual.string => {
+                report.num_verified += 1;
+                continue;
+            }
+            Ok(actual) => {
+                tracing::error!("{} is corrupted, hashing to {actual}", path.display());
+                report.num_corrupted += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                tracing::error!("{} is missing from disk", path.display());
+                report.num_missing += 1;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to hash {}: {e}", path.display());
+                report.num_failed += 1;
+                continue;
+            }
+        }
+
+        report.bad_hashes.extend(hashes);
+    }
+
+    Ok(report)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn checkpoint_wal(
+    config: &config::Config,
+    cache: &Cache,
+) -> anyhow::Result<WalCheckpoint> {
+    if config.proxy_only {
+        anyhow::bail!("The cache database is kept in memory without a WAL in proxy_only mode");
+    }
+
+    let wal_size_before = wal_file_size(config)
+        .await
+        .context("Failed to get WAL file size")?;
+
+    let frames = db::checkpoint_wal(config).await?;
+
+    let wal_size_after = wal_file_size(config)
+        .await
+        .context("Failed to get WAL file size")?;
+
+    let checkpoint = WalCheckpoint {
+        finished: Instant::now(),
+        is_busy: frames.busy != 0,
+        wal_size_before,
+        wal_size_after,
+    };
+
+    if checkpoint.is_busy {
+        tracing::warn!(
+            "Checkpointed {} of {} WAL frames, the rest are still in use",
+            frames.checkpointed,
+            frames.log
+        );
+    } else {
+        tracing::info!("Checkpointed WAL from {wal_size_before} to {wal_size_after} bytes");
+    }
+
+    *cache.last_wal_checkpoint.lock().unwrap() = Some(checkpoint.clone());
+
+    Ok(checkpoint)
+}
+
 #[async_recursion::async_recursion]
 async fn folder_size(path: &std::path::Path) -> tokio::io::Result<u64> {
     use tokio::fs;
@@ -103,12 +1136,13 @@ async fn folder_size(path: &std::path::Path) -> tokio::io::Result<u64> {
     if path.is_dir() {
         let mut read_dir = fs::read_dir(&path).await?;
 
+        // symlinks below `path` are not followed and count as their own size, so they can
+        // neither loop nor count files twice or outside of the data path
         while let Some(entry) = read_dir.next_entry().await? {
-            let p = entry.path();
-            if p.is_file() {
-                result += fs::metadata(p).await?.len();
+            if entry.file_type().await?.is_dir() {
+                result += folder_size(&entry.path()).await?;
             } else {
-                result += folder_size(&p).await?;
+                result += entry.metadata().await?.len();
             }
         }
     } else {
@@ -118,13 +1152,142 @@ async fn folder_size(path: &std::path::Path) -> tokio::io::Result<u64> {
     Ok(result)
 }
 
-fn nar_file_path_from_parts(
+pub fn nar_file_path_from_parts(
     config: &config::Config,
     file_hash: &nix::Hash,
     compression: &nix::CompressionType,
 ) -> PathBuf {
-    config
-        .local_data_path
-        .join(NAR_FILE_DIR)
-        .join(format!("{}.nar.{compression}", file_hash.string))
+    config.local_data_path.join(NAR_FILE_DIR).join(format!(
+        "{}.nar.{}",
+        file_hash.string,
+        compression.extension()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn cache(name: &str) -> (config::Config, Cache) {
+        let local_data_path =
+            std::env::temp_dir().join(format!("nicacher-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&local_data_path);
+        std::fs::create_dir_all(&local_data_path).unwrap();
+
+        let config = config::Config {
+            local_data_path,
+            ..Default::default()
+        };
+        let cache = Cache::new(&config).await.unwrap();
+
+        (config, cache)
+    }
+
+    fn nar_file(
+        hash: &nix::Hash,
+        offset: u64,
+        body: Vec<anyhow::Result<bytes::Bytes>>,
+    ) -> nix::NarFile {
+        nix::NarFile {
+            compression: nix::CompressionType::Xz,
+            hash: Some(hash.clone()),
+            url: "https://cache.nixos.org/nar/test.nar.xz".parse().unwrap(),
+            offset,
+            body: stream::iter(body).boxed(),
+        }
+    }
+
+    #[tokio::test]
+    async fn write_nar_file_resumes_from_partial_file() {
+        let (config, cache) = cache("resume-partial").await;
+
+        let data = (0..=u8::MAX).cycle().take(256 * 1024).collect::<Vec<_>>();
+        let hash = nix::Hash::sha256(&data);
+        let (written, rest) = data.split_at(100_000);
+
+        let partial_path = partial_nar_file_path(&config, &hash, &nix::CompressionType::Xz);
+        std::fs::write(&partial_path, written).unwrap();
+        assert_eq!(
+            partial_nar_file_len(&config, &hash, &nix::CompressionType::Xz).await,
+            written.len() as u64
+        );
+
+        let (file_hash, file_size) = write_nar_file(
+            &config,
+            &cache,
+            nar_file(
+                &hash,
+                written.len() as u64,
+                vec![Ok(bytes::Bytes::copy_from_slice(rest))],
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(file_hash.string, hash.string);
+        assert_eq!(file_size, data.len());
+        assert_eq!(
+            std::fs::read(nar_file_path_from_parts(
+                &config,
+                &hash,
+                &nix::CompressionType::Xz
+            ))
+            .unwrap(),
+            data
+        );
+        assert!(!partial_path.exists());
+    }
+
+    #[tokio::test]
+    async fn interrupted_write_keeps_partial_file() {
+        let (config, cache) = cache("keep-partial").await;
+
+        let data = (0..=u8::MAX).cycle().take(256 * 1024).collect::<Vec<_>>();
+        let hash = nix::Hash::sha256(&data);
+
+        let res = write_nar_file(
+            &config,
+            &cache,
+            nar_file(
+                &hash,
+                0,
+                vec![
+                    Ok(bytes::Bytes::copy_from_slice(&data[..100_000])),
+                    Err(anyhow::anyhow!("Connection reset")),
+                ],
+            ),
+        )
+        .await;
+        assert!(res.is_err());
+
+        assert_eq!(
+            partial_nar_file_len(&config, &hash, &nix::CompressionType::Xz).await,
+            100_000
+        );
+        assert_eq!(remove_tmp_files(&config).await.unwrap(), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn mismatched_partial_file_is_removed() {
+        let (config, cache) = cache("mismatched-partial").await;
+
+        let hash = nix::Hash::sha256(b"expected");
+
+        let partial_path = partial_nar_file_path(&config, &hash, &nix::CompressionType::Xz);
+        std::fs::write(&partial_path, b"not what").unwrap();
+
+        let res = write_nar_file(
+            &config,
+            &cache,
+            nar_file(
+                &hash,
+                8,
+                vec![Ok(bytes::Bytes::from_static(b" was expected"))],
+            ),
+        )
+        .await;
+
+        assert!(res.unwrap_err().is::<FileHashMismatch>());
+        assert!(!partial_path.exists());
+    }
 }
@@ -0,0 +1,114 @@
+use std::f64::consts::LN_2;
+
+use sha2::{Digest as _, Sha256};
+
+const MAGIC: &[u8; 8] = b"ncbloom1";
+
+// Set of strings that may report false positives at roughly `fp_rate`, but never false negatives.
+// Hashed with sha256 rather than std's hasher, whose output may change between releases, as the
+// filter is persisted across restarts
+#[derive(Debug)]
+pub struct BloomFilter {
+    fingerprint: String,
+    fp_rate: f64,
+    num_hashes: u32,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    pub fn new(fingerprint: String, num_items: usize, fp_rate: f64) -> Self {
+        let num_items = num_items.max(1) as f64;
+        let num_bits = (-num_items * fp_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0);
+        let num_hashes = (num_bits / num_items * LN_2).round().max(1.0) as u32;
+
+        Self {
+            fingerprint,
+            fp_rate,
+            num_hashes,
+            bits: vec![0; (num_bits as usize).div_ceil(64)],
+        }
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        for i in self.indices(item) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        self.indices(item)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    // Double hashing, deriving all `num_hashes` indices from two halves of one digest
+    fn indices(&self, item: &str) -> impl Iterator<Item = usize> {
+        let digest = Sha256::digest(item.as_bytes());
+        let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let num_bits = self.bits.len() as u64 * 64;
+
+        (0..self.num_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(32 + self.fingerprint.len() + self.num_bytes());
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.fingerprint.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.fingerprint.as_bytes());
+        bytes.extend_from_slice(&self.fp_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.num_hashes.to_le_bytes());
+        for word in &self.bits {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    // `None` if malformed, or if built for another fingerprint or false positive rate
+    pub fn from_bytes(bytes: &[u8], fingerprint: &str, fp_rate: f64) -> Option<Self> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            let (head, tail) = bytes.split_at_checked(n)?;
+            *bytes = tail;
+            Some(head)
+        }
+
+        let mut bytes = bytes;
+
+        if take(&mut bytes, MAGIC.len())? != MAGIC {
+            return None;
+        }
+
+        let fingerprint_len = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+        if take(&mut bytes, fingerprint_len as usize)? != fingerprint.as_bytes() {
+            return None;
+        }
+
+        if f64::from_le_bytes(take(&mut bytes, 8)?.try_into().ok()?) != fp_rate {
+            return None;
+        }
+
+        let num_hashes = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+
+        if bytes.is_empty() || !bytes.len().is_multiple_of(8) {
+            return None;
+        }
+
+        let bits = bytes
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        Some(Self {
+            fingerprint: fingerprint.to_owned(),
+            fp_rate,
+            num_hashes,
+            bits,
+        })
+    }
+}
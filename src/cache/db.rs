@@ -1,7 +1,8 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
 
 use anyhow::Context as _;
 use futures::StreamExt as _;
+use serde::{Deserialize, Serialize};
 
 use crate::{cache, config, nix};
 
@@ -10,14 +11,164 @@ const CACHE_DB_FILE: &str = "cache.db";
 #[derive(Clone, Debug)]
 pub struct Database(sqlx::SqlitePool);
 
+#[allow(dead_code)]
 #[derive(Debug, sqlx::FromRow)]
 pub struct Entry {
     status: Status,
     last_cached: chrono::NaiveDateTime,
     last_accessed: Option<chrono::NaiveDateTime>,
+    file_size: Option<i64>,
+    nar_size: Option<i64>,
+    // `nar_size / file_size`, how many times smaller the compressed nar file is
+    compression_ratio: Option<f64>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct ChannelSync {
+    pub channel: String,
+    pub last_synced: chrono::NaiveDateTime,
+    pub num_paths: i64,
+    pub num_new: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct ChannelMirror {
+    pub channel: String,
+    pub started: chrono::NaiveDateTime,
+    pub updated: chrono::NaiveDateTime,
+    pub finished: Option<chrono::NaiveDateTime>,
+    pub num_paths: i64,
+    pub num_done: i64,
+    pub num_failed: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct Tombstone {
+    pub hash: String,
+    pub purged: chrono::NaiveDateTime,
+    pub expires: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct NarFileEntry {
+    pub hash: String,
+    pub compression: String,
+    pub file_hash_method: String,
+    pub file_hash: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct NarSizes {
+    pub hash: String,
+    pub compression: String,
+    pub file_hash_method: String,
+    pub file_hash: String,
+    pub file_size: i64,
+    pub nar_hash: String,
+    pub nar_size: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LargestEntry {
+    pub hash: String,
+    pub store_path: String,
+    pub file_size: i64,
+    pub nar_size: i64,
+    pub compression_ratio: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct CompressionRatio {
+    // total nar size over total file size, dominated by the largest entries
+    pub overall: Option<f64>,
+    pub mean: Option<f64>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct EntryRefs {
+    pub hash: String,
+    pub store_path: String,
+    pub refs: String,
+}
+
+#[derive(Debug)]
+pub struct DbInfo {
+    pub migration: Option<(i64, String)>,
+    pub page_count: i64,
+    pub page_size: i64,
+    pub cache_size: i64,
+}
+
+// As reported by `PRAGMA wal_checkpoint`, in WAL frames
+#[derive(Debug, sqlx::FromRow)]
+pub struct CheckpointFrames {
+    pub busy: i64,
+    pub log: i64,
+    pub checkpointed: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct EntrySummary {
+    pub hash: String,
+    pub store_path: Option<String>,
+    pub status: Status,
+    pub system: Option<String>,
+    pub file_size: Option<i64>,
+    pub nar_size: Option<i64>,
+    pub last_cached: chrono::NaiveDateTime,
+    pub last_accessed: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct LruCandidate {
+    pub hash: String,
+    pub file_size: Option<i64>,
+    pub last_accessed: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct NarIndexEntry {
+    pub store_path: String,
+    pub file_hash: String,
+    pub compression: String,
+    pub file_size: i64,
+    pub nar_hash: String,
+    pub compression_ratio: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct EntryFilter {
+    pub status: Option<Status>,
+    pub name_contains: Option<String>,
+    pub system: Option<String>,
+    pub after: Option<String>,
+    pub limit: u32,
+}
+
+impl Default for EntryFilter {
+    fn default() -> Self {
+        Self {
+            status: None,
+            name_contains: None,
+            system: None,
+            after: None,
+            limit: 100,
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Default, num_enum::IntoPrimitive, num_enum::FromPrimitive)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Serialize,
+    Deserialize,
+    num_enum::IntoPrimitive,
+    num_enum::FromPrimitive,
+)]
+#[serde(rename_all = "snake_case")]
 #[repr(i64)]
 pub enum Status {
     #[default]
@@ -25,6 +176,8 @@ pub enum Status {
     Fetching,
     Available,
     Purging,
+    // narinfo is stored and served, nar file is still being fetched
+    MetadataOnly,
 }
 
 impl<DB> sqlx::Type<DB> for Status
@@ -78,26 +231,73 @@ impl Database {
 
         tracing::info!("Establishing connection to SQLite cache database");
 
-        let database_url = format!(
-            "sqlite://{}",
-            config.local_data_path.join(CACHE_DB_FILE).display()
-        );
+        let mut connection_options = if config.proxy_only {
+            // shared by every connection in the pool, unlike a `:memory:` database
+            SqliteConnectOptions::new()
+                .filename("/nicacher-cache")
+                .vfs("memdb")
+        } else {
+            let database_url = format!("sqlite://{}", db_file_path(config).display());
+            SqliteConnectOptions::from_str(&database_url)?.journal_mode(SqliteJournalMode::Wal)
+        }
+        .create_if_missing(true)
+        .synchronous(SqliteSynchronous::Normal);
 
-        let connection_options = SqliteConnectOptions::from_str(&database_url)?
-            .create_if_missing(true)
-            .journal_mode(SqliteJournalMode::Wal)
-            .synchronous(SqliteSynchronous::Normal);
+        // `page_size` is only applied when the database file is first created, as switching it
+        // for an existing database in WAL mode requires a VACUUM
+        if let Some(page_size) = config.database_page_size {
+            connection_options = connection_options.page_size(page_size);
+        }
 
-        let db_pool = SqlitePoolOptions::new()
-            .max_connections(config.database_max_connections)
-            .connect_with(connection_options)
-            .await?;
+        if let Some(cache_size) = config.database_cache_size {
+            connection_options = connection_options.pragma("cache_size", cache_size.to_string());
+        }
+
+        let mut pool_options =
+            SqlitePoolOptions::new().max_connections(config.database_max_connections);
+
+        // an in-memory database is gone once its last connection is closed
+        if config.proxy_only {
+            pool_options = pool_options
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+
+        let db_pool = pool_options.connect_with(connection_options).await?;
 
         tracing::info!("Migrating cache database");
         sqlx::query!(r#"PRAGMA temp_store = MEMORY;"#)
             .execute(&db_pool)
             .await?;
-        sqlx::migrate!().run(&db_pool).await?;
+
+        // the migrations table does not exist yet on a fresh database
+        let applied: HashSet<i64> =
+            sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1;")
+                .fetch_all(&db_pool)
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+
+        let migrator = sqlx::migrate!();
+        migrator.run(&db_pool).await?;
+
+        let mut num_applied = 0;
+        for migration in migrator.iter() {
+            if !applied.contains(&migration.version) {
+                tracing::info!(
+                    "Applied migration {} ({})",
+                    migration.version,
+                    migration.description
+                );
+                num_applied += 1;
+            }
+        }
+
+        if num_applied == 0 {
+            tracing::info!("Cache database is up to date");
+        }
 
         Ok(Self(db_pool))
     }
@@ -115,6 +315,27 @@ impl Database {
     }
 }
 
+// `SQLITE_FULL`, matched against the primary result code of extended codes too
+const SQLITE_FULL: i32 = 13;
+
+pub fn is_full_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_err)) => db_err
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| code & 0xff == SQLITE_FULL),
+        _ => false,
+    })
+}
+
+pub fn db_file_path(config: &config::Config) -> PathBuf {
+    config.local_data_path.join(CACHE_DB_FILE)
+}
+
+pub fn wal_file_path(config: &config::Config) -> PathBuf {
+    config.local_data_path.join(format!("{CACHE_DB_FILE}-wal"))
+}
+
 #[macro_export]
 macro_rules! transaction {
     (begin: $cache:expr) => {
@@ -184,45 +405,6 @@ where
     }
 }
 
-#[tracing::instrument]
-pub async fn get_nar_info_with_upstream<'c, E>(
-    executor: E,
-    hash: &nix::Hash,
-) -> anyhow::Result<Option<(nix::NarInfo, nix::Upstream)>>
-where
-    E: sqlx::SqliteExecutor<'c>,
-{
-    tracing::info!(
-        "Getting {}.narinfo and upstream from cache database",
-        hash.string
-    );
-
-    let entry: Option<NarInfoWithUpstreamEntry> = sqlx::query_as(
-        r#"
-            SELECT *
-            FROM narinfo
-            WHERE hash = ?;
-        "#,
-    )
-    .bind(&hash.string)
-    .fetch_optional(executor)
-    .await?;
-
-    if let Some(entry) = entry {
-        tracing::debug!("Found narinfo entry in database");
-        let upstream = nix::Upstream::new(entry.upstream_url.parse()?);
-        let nar_info = nix::NarInfo::try_from(entry)?;
-        Ok(Some((nar_info, upstream)))
-    } else {
-        tracing::debug!(
-            "Unable to find entry for {}.narinfo in database",
-            hash.string
-        );
-
-        Ok(None)
-    }
-}
-
 #[tracing::instrument(skip(config))]
 pub async fn get_nar_file_path<'c, E>(
     executor: E,
@@ -345,6 +527,92 @@ where
     Ok(())
 }
 
+#[tracing::instrument(level = "debug", skip(raw))]
+pub async fn set_raw_nar_info<'c, E>(executor: E, hash: &nix::Hash, raw: &str) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Storing raw {}.narinfo", hash.string);
+
+    sqlx::query!(
+        r#"
+            REPLACE INTO raw_narinfo (hash, raw)
+            VALUES (?, ?);
+        "#,
+        hash.string,
+        raw
+    )
+    .execute(executor)
+    .await
+    .context("Failed to insert raw narinfo into cache database")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn set_nar_url<'c, E>(executor: E, hash: &nix::Hash, url: &url::Url) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Storing nar file url of {}.narinfo", hash.string);
+
+    let url = url.to_string();
+
+    sqlx::query!(
+        r#"
+            REPLACE INTO nar_url (hash, url)
+            VALUES (?, ?);
+        "#,
+        hash.string,
+        url
+    )
+    .execute(executor)
+    .await
+    .context("Failed to insert nar file url into cache database")?;
+
+    Ok(())
+}
+
+// `None` for narinfos cached before nar file urls were stored
+#[tracing::instrument(level = "debug")]
+pub async fn get_nar_url<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<Option<url::Url>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    sqlx::query_scalar!(
+        r#"
+            SELECT url
+            FROM nar_url
+            WHERE hash = ?;
+        "#,
+        hash.string
+    )
+    .fetch_optional(executor)
+    .await?
+    .map(|url| url.parse().context("Invalid nar file url in cache db"))
+    .transpose()
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_raw_nar_info<'c, E>(
+    executor: E,
+    hash: &nix::Hash,
+) -> anyhow::Result<Option<String>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT raw
+            FROM raw_narinfo
+            WHERE hash = ?;
+        "#,
+        hash.string
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
 #[tracing::instrument(level = "debug")]
 pub fn get_store_paths<'c, E>(
     executor: E,
@@ -374,6 +642,29 @@ where
     )
 }
 
+// Same as `get_store_paths`, but not tied to a borrow of the pool, for streaming response bodies
+pub fn stream_store_paths(
+    pool: &sqlx::SqlitePool,
+) -> futures::stream::BoxStream<'static, anyhow::Result<nix::StorePath>> {
+    use sqlx::{Executor as _, Row as _};
+
+    tracing::debug!("Streaming all cached store paths");
+
+    pool.fetch(
+        sqlx::query(
+            r#"
+                SELECT narinfo.store_path
+                FROM cache
+                INNER JOIN narinfo ON cache.hash = narinfo.hash
+                WHERE cache.status = ?;
+            "#,
+        )
+        .bind(Status::Available),
+    )
+    .map(|row| Ok(nix::StorePath::from_str(row?.try_get(0)?)?))
+    .boxed()
+}
+
 #[tracing::instrument(level = "debug")]
 pub async fn get_num_store_paths<'c, E>(executor: E) -> anyhow::Result<usize>
 where
@@ -393,6 +684,99 @@ where
     .await? as usize)
 }
 
+// Changes along with the set of cached store paths, short of a purge and a re-cache landing within
+// the same second
+#[tracing::instrument(level = "debug")]
+pub async fn get_store_paths_fingerprint<'c, E>(executor: E) -> anyhow::Result<String>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) || ':' || IFNULL(MAX(last_cached), '') || ':' || TOTAL(rowid)
+                AS "fingerprint!: String"
+            FROM cache
+            WHERE status = ?;
+        "#,
+        Status::Available
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug", skip(pool))]
+pub fn get_entries(
+    pool: &sqlx::SqlitePool,
+    filter: EntryFilter,
+) -> futures::stream::BoxStream<'static, anyhow::Result<EntrySummary>> {
+    use sqlx::{Executor as _, FromRow as _};
+
+    tracing::debug!("Getting cache entries");
+
+    // `Executor::fetch` on the pool doesn't borrow it for the lifetime of the stream
+    pool.fetch(
+        sqlx::query(
+            r#"
+                SELECT
+                    cache.hash,
+                    narinfo.store_path,
+                    cache.status,
+                    narinfo.system,
+                    narinfo.file_size,
+                    narinfo.nar_size,
+                    cache.last_cached,
+                    cache.last_accessed
+                FROM cache
+                LEFT JOIN narinfo ON cache.hash = narinfo.hash
+                WHERE
+                    (?1 IS NULL OR cache.status = ?1) AND
+                    (?2 IS NULL OR instr(narinfo.store_path, ?2) > 0) AND
+                    (?3 IS NULL OR narinfo.system = ?3) AND
+                    (?4 IS NULL OR cache.hash > ?4)
+                ORDER BY cache.hash
+                LIMIT ?5;
+            "#,
+        )
+        .bind(filter.status)
+        .bind(filter.name_contains)
+        .bind(filter.system)
+        .bind(filter.after)
+        .bind(filter.limit),
+    )
+    .map(|row| Ok(EntrySummary::from_row(&row?)?))
+    .boxed()
+}
+
+pub fn get_nar_index(
+    pool: &sqlx::SqlitePool,
+) -> futures::stream::BoxStream<'static, anyhow::Result<NarIndexEntry>> {
+    use sqlx::{Executor as _, FromRow as _};
+
+    tracing::debug!("Getting index of cached nar files");
+
+    pool.fetch(
+        sqlx::query(
+            r#"
+                SELECT
+                    narinfo.store_path,
+                    narinfo.file_hash_method || ':' || narinfo.file_hash AS file_hash,
+                    narinfo.compression,
+                    narinfo.file_size,
+                    narinfo.nar_hash_method || ':' || narinfo.nar_hash AS nar_hash,
+                    CAST(narinfo.nar_size AS REAL) / NULLIF(narinfo.file_size, 0)
+                        AS compression_ratio
+                FROM cache
+                INNER JOIN narinfo ON cache.hash = narinfo.hash
+                WHERE cache.status = ?
+                ORDER BY narinfo.file_hash;
+            "#,
+        )
+        .bind(Status::Available),
+    )
+    .map(|row| Ok(NarIndexEntry::from_row(&row?)?))
+    .boxed()
+}
+
 #[tracing::instrument]
 pub async fn purge_nar_info<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<()>
 where
@@ -424,11 +808,16 @@ where
         Entry,
         r#"
             SELECT
-                status as "status: Status",
-                last_cached,
-                last_accessed
+                cache.status as "status: Status",
+                cache.last_cached,
+                cache.last_accessed,
+                narinfo.file_size as "file_size?",
+                narinfo.nar_size as "nar_size?",
+                CAST(narinfo.nar_size AS REAL) / NULLIF(narinfo.file_size, 0)
+                    as "compression_ratio?: f64"
             FROM cache
-            WHERE hash = ?;
+            LEFT JOIN narinfo ON cache.hash = narinfo.hash
+            WHERE cache.hash = ?;
         "#,
         hash.string
     )
@@ -547,6 +936,31 @@ where
     .unwrap_or_default() as usize)
 }
 
+#[tracing::instrument(level = "debug")]
+pub async fn get_compression_ratio<'c, E>(executor: E) -> anyhow::Result<CompressionRatio>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting compression ratio of available entries");
+
+    Ok(sqlx::query_as!(
+        CompressionRatio,
+        r#"
+            SELECT
+                CAST(SUM(narinfo.nar_size) AS REAL) / NULLIF(SUM(narinfo.file_size), 0)
+                    as "overall?: f64",
+                AVG(CAST(narinfo.nar_size AS REAL) / NULLIF(narinfo.file_size, 0))
+                    as "mean?: f64"
+            FROM cache
+            INNER JOIN narinfo ON cache.hash = narinfo.hash
+            WHERE cache.status = ?;
+        "#,
+        Status::Available
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
 #[tracing::instrument(level = "debug")]
 pub async fn is_cached_by_hash<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<bool>
 where
@@ -567,58 +981,893 @@ where
 }
 
 #[tracing::instrument(level = "debug")]
-pub async fn is_nar_file_cached<'c, E>(
+pub async fn get_cached_nar_file<'c, E>(
     executor: E,
-    nar_file: &nix::NarFileInfo,
-) -> anyhow::Result<bool>
+    file_hash: &nix::Hash,
+    compression: Option<&nix::CompressionType>,
+) -> anyhow::Result<Option<nix::NarFileInfo>>
 where
     E: sqlx::SqliteExecutor<'c>,
 {
-    let compression = nar_file.compression.to_string();
+    // `None` is an uncompressed nar file, which is named `none` like in narinfos
+    let compression = compression.map_or_else(|| "none".to_owned(), ToString::to_string);
 
-    Ok(sqlx::query_scalar!(
+    let compression = sqlx::query_scalar!(
         r#"
-            SELECT 1
+            SELECT narinfo.compression
             FROM cache
             INNER JOIN narinfo on cache.hash = narinfo.hash
             WHERE
                 narinfo.file_hash = ? AND
                 narinfo.compression = ? AND
-                cache.status = ?;
+                cache.status = ?
+            LIMIT 1;
         "#,
-        nar_file.hash.string,
+        file_hash.string,
         compression,
         Status::Available
     )
     .fetch_optional(executor)
-    .await?
-    .is_some())
+    .await?;
+
+    compression
+        .map(|compression| {
+            Ok(nix::NarFileInfo {
+                hash: nix::Hash::from_hash(file_hash.string.clone()),
+                compression: compression
+                    .parse()
+                    .context("Failed to parse compression type from cache db")?,
+            })
+        })
+        .transpose()
 }
 
-#[allow(dead_code)]
-#[derive(Debug, sqlx::FromRow)]
-struct NarInfoEntry {
-    hash: String,
-    store_path: String,
-    compression: String,
-    file_hash_method: String,
-    file_hash: String,
-    file_size: i64,
-    nar_hash_method: String,
-    nar_hash: String,
-    nar_size: i64,
-    deriver: Option<String>,
-    system: Option<String>,
-    refs: String,
-    signature: Option<String>,
+#[tracing::instrument(level = "debug")]
+pub async fn get_hashes_by_file_hash<'c, E>(
+    executor: E,
+    file_hash: &nix::Hash,
+) -> anyhow::Result<Vec<nix::Hash>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT hash
+            FROM narinfo
+            WHERE file_hash = ?;
+        "#,
+        file_hash.string
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(nix::Hash::from_hash)
+    .collect())
 }
 
-impl NarInfoEntry {
-    fn from_nar_info(hash: &nix::Hash, nar_info: &nix::NarInfo) -> Self {
-        Self {
-            hash: hash.string.clone(),
-            store_path: nar_info.store_path.path().to_string_lossy().to_string(),
-            compression: nar_info.compression.to_string(),
+// An entry whose narinfo is already known but whose nar file is still being fetched
+#[tracing::instrument(level = "debug")]
+pub async fn get_in_flight_hash_by_file_hash<'c, E>(
+    executor: E,
+    file_hash: &nix::Hash,
+) -> anyhow::Result<Option<nix::Hash>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT cache.hash
+            FROM cache
+            INNER JOIN narinfo on cache.hash = narinfo.hash
+            WHERE
+                narinfo.file_hash = ? AND
+                cache.status IN (?, ?)
+            LIMIT 1;
+        "#,
+        file_hash.string,
+        Status::Fetching,
+        Status::MetadataOnly
+    )
+    .fetch_optional(executor)
+    .await?
+    .map(nix::Hash::from_hash))
+}
+
+// Other entries referencing the same nar file as `hash`, other than those already being purged
+#[tracing::instrument(level = "debug")]
+pub async fn count_nar_file_sharers<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<usize>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*)
+            FROM narinfo AS this
+            INNER JOIN narinfo AS other ON
+                other.file_hash = this.file_hash AND
+                other.compression = this.compression
+            INNER JOIN cache ON cache.hash = other.hash
+            WHERE
+                this.hash = ?1 AND
+                other.hash != ?1 AND
+                cache.status != ?2;
+        "#,
+        hash.string,
+        Status::Purging
+    )
+    .fetch_one(executor)
+    .await? as usize)
+}
+
+#[tracing::instrument(level = "debug", skip(pool))]
+pub async fn get_db_info(pool: &sqlx::SqlitePool) -> anyhow::Result<DbInfo> {
+    tracing::debug!("Getting cache database info");
+
+    let migration = sqlx::query!(
+        r#"
+            SELECT version AS "version!", description
+            FROM _sqlx_migrations
+            WHERE success = 1
+            ORDER BY version DESC
+            LIMIT 1;
+        "#
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get applied migration version")?
+    .map(|row| (row.version, row.description));
+
+    let page_count = sqlx::query_scalar::<_, i64>("PRAGMA page_count;")
+        .fetch_one(pool)
+        .await
+        .context("Failed to get page count")?;
+
+    let page_size = sqlx::query_scalar::<_, i64>("PRAGMA page_size;")
+        .fetch_one(pool)
+        .await
+        .context("Failed to get page size")?;
+
+    let cache_size = sqlx::query_scalar::<_, i64>("PRAGMA cache_size;")
+        .fetch_one(pool)
+        .await
+        .context("Failed to get cache size")?;
+
+    Ok(DbInfo {
+        migration,
+        page_count,
+        page_size,
+        cache_size,
+    })
+}
+
+// On a connection of its own rather than one from the pool, so the workers are not kept waiting
+// for a connection while the checkpoint waits on readers
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn checkpoint_wal(config: &config::Config) -> anyhow::Result<CheckpointFrames> {
+    use sqlx::{
+        sqlite::{SqliteConnectOptions, SqliteJournalMode},
+        ConnectOptions as _, Connection as _,
+    };
+
+    tracing::debug!("Checkpointing WAL of cache database");
+
+    let mut connection = SqliteConnectOptions::new()
+        .filename(db_file_path(config))
+        .journal_mode(SqliteJournalMode::Wal)
+        .connect()
+        .await
+        .context("Failed to connect to cache database")?;
+
+    let frames = sqlx::query_as::<_, CheckpointFrames>("PRAGMA wal_checkpoint(TRUNCATE);")
+        .fetch_one(&mut connection)
+        .await
+        .context("Failed to checkpoint WAL")?;
+
+    connection.close().await?;
+
+    Ok(frames)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_channel_store_paths<'c, E>(
+    executor: E,
+    channel: &nix::Channel,
+) -> anyhow::Result<HashSet<String>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting last synced store paths of {channel}");
+
+    let channel = channel.to_string();
+
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT store_path
+            FROM channel_store_path
+            WHERE channel = ?;
+        "#,
+        channel
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .collect())
+}
+
+#[tracing::instrument(level = "debug", skip(tx, store_paths))]
+pub async fn set_channel_store_paths<'a, I>(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    channel: &nix::Channel,
+    store_paths: I,
+) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = &'a nix::StorePath>,
+{
+    tracing::debug!("Replacing last synced store paths of {channel}");
+
+    let channel = channel.to_string();
+
+    sqlx::query!(
+        r#"
+            DELETE FROM channel_store_path
+            WHERE channel = ?;
+        "#,
+        channel
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to clear last synced store paths")?;
+
+    for store_path in store_paths {
+        let store_path = store_path.to_string();
+
+        sqlx::query!(
+            r#"
+                INSERT INTO channel_store_path (channel, store_path)
+                VALUES (?,?);
+            "#,
+            channel,
+            store_path
+        )
+        .execute(&mut *tx)
+        .await
+        .context("Failed to insert last synced store path")?;
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_store_path_channels<'c, E>(
+    executor: E,
+    store_path: &nix::StorePath,
+) -> anyhow::Result<Vec<String>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting channels listing {store_path} at their last sync");
+
+    let store_path = store_path.to_string();
+
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT channel
+            FROM channel_store_path
+            WHERE store_path = ?
+            ORDER BY channel;
+        "#,
+        store_path
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+// Channels with store paths recorded at their last sync or mirror
+#[tracing::instrument(level = "debug")]
+pub async fn get_recorded_channels<'c, E>(executor: E) -> anyhow::Result<HashSet<String>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting channels with recorded store paths");
+
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT DISTINCT channel
+            FROM channel_store_path;
+        "#
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .collect())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_store_paths_only_in_channel<'c, E>(
+    executor: E,
+    channel: &nix::Channel,
+) -> anyhow::Result<HashSet<String>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting store paths listed by {channel} and no other channel");
+
+    let channel = channel.to_string();
+
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT this.store_path
+            FROM channel_store_path AS this
+            WHERE
+                this.channel = ? AND
+                NOT EXISTS (
+                    SELECT 1
+                    FROM channel_store_path AS other
+                    WHERE
+                        other.store_path = this.store_path AND
+                        other.channel != this.channel
+                );
+        "#,
+        channel
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .collect())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn set_channel_sync<'c, E>(
+    executor: E,
+    channel: &nix::Channel,
+    num_paths: usize,
+    num_new: usize,
+) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Recording sync of {channel}");
+
+    let channel = channel.to_string();
+    let num_paths = num_paths as i64;
+    let num_new = num_new as i64;
+
+    sqlx::query!(
+        r#"
+            REPLACE INTO channel_sync (channel, num_paths, num_new)
+            VALUES (?,?,?);
+        "#,
+        channel,
+        num_paths,
+        num_new
+    )
+    .execute(executor)
+    .await
+    .context("Failed to record channel sync")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_channel_syncs<'c, E>(executor: E) -> anyhow::Result<Vec<ChannelSync>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting channel sync records");
+
+    Ok(sqlx::query_as!(
+        ChannelSync,
+        r#"
+            SELECT
+                channel,
+                last_synced,
+                num_paths,
+                num_new
+            FROM channel_sync;
+        "#
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn add_tombstone<'c, E>(
+    executor: E,
+    hash: &nix::Hash,
+    ttl_secs: u64,
+) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Tombstoning {} for {ttl_secs}s", hash.string);
+
+    let ttl = format!("+{ttl_secs} seconds");
+
+    sqlx::query!(
+        r#"
+            REPLACE INTO tombstone (hash, expires)
+            VALUES (?, datetime('now', ?));
+        "#,
+        hash.string,
+        ttl
+    )
+    .execute(executor)
+    .await
+    .context("Failed to add tombstone")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn is_tombstoned<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<bool>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Checking for tombstone of {}", hash.string);
+
+    Ok(sqlx::query!(
+        r#"
+            SELECT hash
+            FROM tombstone
+            WHERE hash = ? AND expires > datetime('now');
+        "#,
+        hash.string
+    )
+    .fetch_optional(executor)
+    .await?
+    .is_some())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_tombstones<'c, E>(executor: E) -> anyhow::Result<Vec<Tombstone>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting active tombstones");
+
+    Ok(sqlx::query_as!(
+        Tombstone,
+        r#"
+            SELECT
+                hash,
+                purged,
+                expires
+            FROM tombstone
+            WHERE expires > datetime('now')
+            ORDER BY purged DESC;
+        "#
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn clear_tombstones<'c, E>(executor: E, hash: Option<&nix::Hash>) -> anyhow::Result<u64>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Clearing tombstones");
+
+    let hash = hash.map(|hash| hash.string.as_str());
+
+    Ok(sqlx::query!(
+        r#"
+            DELETE FROM tombstone
+            WHERE ?1 IS NULL OR hash = ?1;
+        "#,
+        hash
+    )
+    .execute(executor)
+    .await
+    .context("Failed to clear tombstones")?
+    .rows_affected())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn try_lock<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<bool>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Claiming lock on {}", hash.string);
+
+    let res = sqlx::query!(
+        r#"
+            INSERT OR IGNORE INTO locks (hash)
+            VALUES (?);
+        "#,
+        hash.string
+    )
+    .execute(executor)
+    .await
+    .context("Failed to claim lock")?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn unlock<'c, E>(executor: E, hash: &nix::Hash) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Releasing lock on {}", hash.string);
+
+    sqlx::query!(
+        r#"
+            DELETE FROM locks
+            WHERE hash = ?;
+        "#,
+        hash.string
+    )
+    .execute(executor)
+    .await
+    .context("Failed to release lock")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn clear_locks<'c, E>(executor: E) -> anyhow::Result<u64>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Clearing all locks");
+
+    Ok(sqlx::query!("DELETE FROM locks;")
+        .execute(executor)
+        .await
+        .context("Failed to clear locks")?
+        .rows_affected())
+}
+
+// Narinfos of `MetadataOnly` entries are dropped too, as their nar files will never arrive
+#[tracing::instrument(level = "debug", skip(tx))]
+pub async fn reset_in_flight(tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> anyhow::Result<u64> {
+    tracing::debug!("Resetting in-flight entries");
+
+    sqlx::query!(
+        r#"
+            DELETE FROM narinfo
+            WHERE hash IN (SELECT hash FROM cache WHERE status = ?);
+        "#,
+        Status::MetadataOnly
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to delete narinfo of in-flight entries")?;
+
+    Ok(sqlx::query!(
+        r#"
+            UPDATE cache
+            SET status = ?
+            WHERE status IN (?, ?);
+        "#,
+        Status::NotAvailable,
+        Status::Fetching,
+        Status::MetadataOnly
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to reset status of in-flight entries")?
+    .rows_affected())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn start_channel_mirror<'c, E>(
+    executor: E,
+    channel: &nix::Channel,
+    num_paths: usize,
+) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Recording start of mirroring {channel}");
+
+    let channel = channel.to_string();
+    let num_paths = num_paths as i64;
+
+    // keep `started` of an unfinished mirror so resumed runs are reported as one
+    sqlx::query!(
+        r#"
+            INSERT INTO channel_mirror (channel, num_paths)
+            VALUES (?,?)
+            ON CONFLICT (channel)
+            DO UPDATE SET
+                started = CASE
+                    WHEN finished IS NULL THEN started
+                    ELSE CURRENT_TIMESTAMP
+                END,
+                updated = CURRENT_TIMESTAMP,
+                finished = NULL,
+                num_paths = excluded.num_paths,
+                num_done = 0,
+                num_failed = 0;
+        "#,
+        channel,
+        num_paths
+    )
+    .execute(executor)
+    .await
+    .context("Failed to record start of channel mirror")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn update_channel_mirror<'c, E>(
+    executor: E,
+    channel: &nix::Channel,
+    num_done: usize,
+    num_failed: usize,
+    is_finished: bool,
+) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Recording progress of mirroring {channel}");
+
+    let channel = channel.to_string();
+    let num_done = num_done as i64;
+    let num_failed = num_failed as i64;
+
+    sqlx::query!(
+        r#"
+            UPDATE channel_mirror
+            SET
+                updated = CURRENT_TIMESTAMP,
+                finished = CASE WHEN ?4 THEN CURRENT_TIMESTAMP ELSE NULL END,
+                num_done = ?2,
+                num_failed = ?3
+            WHERE channel = ?1;
+        "#,
+        channel,
+        num_done,
+        num_failed,
+        is_finished
+    )
+    .execute(executor)
+    .await
+    .context("Failed to record channel mirror progress")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_channel_mirrors<'c, E>(executor: E) -> anyhow::Result<Vec<ChannelMirror>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting channel mirror records");
+
+    Ok(sqlx::query_as!(
+        ChannelMirror,
+        r#"
+            SELECT
+                channel,
+                started,
+                updated,
+                finished,
+                num_paths,
+                num_done,
+                num_failed
+            FROM channel_mirror;
+        "#
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_available_refs<'c, E>(executor: E) -> anyhow::Result<Vec<EntryRefs>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting references of available entries");
+
+    Ok(sqlx::query_as!(
+        EntryRefs,
+        r#"
+            SELECT
+                narinfo.hash,
+                narinfo.store_path,
+                narinfo.refs
+            FROM cache
+            INNER JOIN narinfo ON cache.hash = narinfo.hash
+            WHERE cache.status = ?;
+        "#,
+        Status::Available
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+// Least recently accessed available entries first, with never accessed ones before all others
+#[tracing::instrument(level = "debug")]
+pub async fn get_lru_candidates<'c, E>(executor: E, limit: i64) -> anyhow::Result<Vec<LruCandidate>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting {limit} least recently accessed available entries");
+
+    Ok(sqlx::query_as!(
+        LruCandidate,
+        r#"
+            SELECT
+                cache.hash as "hash!",
+                narinfo.file_size as "file_size?: i64",
+                cache.last_accessed as "last_accessed?: chrono::NaiveDateTime"
+            FROM cache
+            LEFT JOIN narinfo ON cache.hash = narinfo.hash
+            WHERE cache.status = ?1
+            ORDER BY cache.last_accessed ASC NULLS FIRST, cache.last_cached ASC
+            LIMIT ?2;
+        "#,
+        Status::Available,
+        limit
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_largest_entries<'c, E>(
+    executor: E,
+    limit: i64,
+) -> anyhow::Result<Vec<LargestEntry>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting {limit} largest available entries");
+
+    // `CROSS JOIN` pins narinfo as the outer loop, so the walk is down `narinfo_file_size_index`
+    // and stops after `limit` rows instead of sorting every entry
+    Ok(sqlx::query_as!(
+        LargestEntry,
+        r#"
+            SELECT
+                narinfo.hash,
+                narinfo.store_path,
+                narinfo.file_size,
+                narinfo.nar_size,
+                CAST(narinfo.nar_size AS REAL) / NULLIF(narinfo.file_size, 0)
+                    as "compression_ratio?: f64"
+            FROM narinfo
+            CROSS JOIN cache ON cache.hash = narinfo.hash
+            WHERE cache.status = ?1
+            ORDER BY narinfo.file_size DESC
+            LIMIT ?2;
+        "#,
+        Status::Available,
+        limit
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+// Nar files of available entries, in random order so a `limit` takes a random sample of them.
+// `None` returns all of them
+#[tracing::instrument(level = "debug")]
+pub async fn get_available_nar_files<'c, E>(
+    executor: E,
+    limit: Option<i64>,
+) -> anyhow::Result<Vec<NarFileEntry>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting nar files of available entries");
+
+    // a negative `LIMIT` is none at all
+    let limit = limit.unwrap_or(-1);
+
+    Ok(sqlx::query_as!(
+        NarFileEntry,
+        r#"
+            SELECT
+                narinfo.hash as "hash!",
+                narinfo.compression as "compression!",
+                narinfo.file_hash_method as "file_hash_method!",
+                narinfo.file_hash as "file_hash!"
+            FROM cache
+            INNER JOIN narinfo ON cache.hash = narinfo.hash
+            WHERE cache.status = ?1
+            ORDER BY RANDOM()
+            LIMIT ?2;
+        "#,
+        Status::Available,
+        limit
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn get_available_nar_sizes<'c, E>(executor: E) -> anyhow::Result<Vec<NarSizes>>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Getting sizes of available narinfo entries");
+
+    Ok(sqlx::query_as!(
+        NarSizes,
+        r#"
+            SELECT
+                narinfo.hash,
+                narinfo.compression,
+                narinfo.file_hash_method,
+                narinfo.file_hash,
+                narinfo.file_size,
+                narinfo.nar_hash,
+                narinfo.nar_size
+            FROM cache
+            INNER JOIN narinfo ON cache.hash = narinfo.hash
+            WHERE cache.status = ?;
+        "#,
+        Status::Available
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn set_nar_sizes<'c, E>(
+    executor: E,
+    hash: &str,
+    file_size: u64,
+    nar_hash: &nix::Hash,
+    nar_size: u64,
+) -> anyhow::Result<()>
+where
+    E: sqlx::SqliteExecutor<'c>,
+{
+    tracing::debug!("Setting sizes of {hash}.narinfo");
+
+    let file_size = file_size as i64;
+    let nar_hash_method = nar_hash.method.clone().unwrap_or_default().to_string();
+    let nar_size = nar_size as i64;
+
+    sqlx::query!(
+        r#"
+            UPDATE narinfo
+            SET file_size = ?, nar_hash_method = ?, nar_hash = ?, nar_size = ?
+            WHERE hash = ?;
+        "#,
+        file_size,
+        nar_hash_method,
+        nar_hash.string,
+        nar_size,
+        hash
+    )
+    .execute(executor)
+    .await
+    .context("Failed to update narinfo sizes")?;
+
+    Ok(())
+}
+
+#[allow(dead_code)]
+#[derive(Debug, sqlx::FromRow)]
+struct NarInfoEntry {
+    hash: String,
+    store_path: String,
+    compression: String,
+    file_hash_method: String,
+    file_hash: String,
+    file_size: i64,
+    nar_hash_method: String,
+    nar_hash: String,
+    nar_size: i64,
+    deriver: Option<String>,
+    system: Option<String>,
+    refs: String,
+    // space separated, as signatures never contain any
+    signature: Option<String>,
+}
+
+impl NarInfoEntry {
+    fn from_nar_info(hash: &nix::Hash, nar_info: &nix::NarInfo) -> Self {
+        Self {
+            hash: hash.string.clone(),
+            store_path: nar_info.store_path.path().to_string_lossy().to_string(),
+            compression: nar_info.compression.to_string(),
             file_hash_method: nar_info
                 .file_hash
                 .method
@@ -628,7 +1877,7 @@ impl NarInfoEntry {
             file_hash: nar_info.file_hash.string.clone(),
             file_size: nar_info.file_size as i64,
             nar_hash_method: nar_info
-                .file_hash
+                .nar_hash
                 .method
                 .clone()
                 .unwrap_or_default()
@@ -642,7 +1891,7 @@ impl NarInfoEntry {
                 .iter()
                 .map(nix::DerivationInfo::to_string)
                 .fold(String::new(), |a, v| a + " " + &v),
-            signature: nar_info.signature.clone(),
+            signature: (!nar_info.signatures.is_empty()).then(|| nar_info.signatures.join(" ")),
         }
     }
 }
@@ -658,7 +1907,7 @@ impl TryFrom<NarInfoEntry> for nix::NarInfo {
             .compression
             .parse::<CompressionType>()
             .map_err(|e| Self::Error::InvalidFieldValue("Compression".to_owned(), e.to_string()))?;
-        let url = format!("nar/{}.nar.{compression}", file_hash.string);
+        let url = format!("nar/{}.nar.{}", file_hash.string, compression.extension());
 
         nix::NarInfoBuilder::default()
             .store_path(value.store_path.parse::<StorePath>().map_err(|e| {
@@ -683,30 +1932,83 @@ impl TryFrom<NarInfoEntry> for nix::NarInfo {
                     .collect::<Result<Vec<_>, _>>()
                     .map_err(Self::Error::InvalidReference)?,
             )
-            .signature(value.signature.clone())
+            .signatures(
+                value
+                    .signature
+                    .iter()
+                    .flat_map(|signature| signature.split_whitespace())
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>(),
+            )
             .build()
             .map_err(Self::Error::MissingField)
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, sqlx::FromRow)]
-struct NarInfoWithUpstreamEntry {
-    #[sqlx(flatten)]
-    nar_info_entry: NarInfoEntry,
-    upstream_url: String,
-}
-
-impl From<NarInfoWithUpstreamEntry> for NarInfoEntry {
-    fn from(NarInfoWithUpstreamEntry { nar_info_entry, .. }: NarInfoWithUpstreamEntry) -> Self {
-        nar_info_entry
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0a9p2mhq7sjbrn5gkw10wnwnmjvhvmbi";
+    const NAR_INFO: &str = "\
+StorePath: /nix/store/0a9p2mhq7sjbrn5gkw10wnwnmjvhvmbi-hello-2.12.1
+URL: nar/1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.xz
+Compression: xz
+FileHash: sha256:1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3
+FileSize: 50088
+NarHash: sha256:0yb5nnjkiy3d0ncfj3ddmq5ri1ccqa6k5vzv8ydidr1qs3mw9fa2
+NarSize: 226488
+References: 0a9p2mhq7sjbrn5gkw10wnwnmjvhvmbi-hello-2.12.1
+";
+
+    async fn database(name: &str) -> Database {
+        let local_data_path =
+            std::env::temp_dir().join(format!("nicacher-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&local_data_path);
+        std::fs::create_dir_all(&local_data_path).unwrap();
+
+        Database::new(&config::Config {
+            local_data_path,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
     }
-}
 
-impl TryFrom<NarInfoWithUpstreamEntry> for nix::NarInfo {
-    type Error = <nix::NarInfo as FromStr>::Err;
+    #[tokio::test]
+    async fn uncompressed_nar_file_is_never_a_compressed_one() {
+        let db = database("uncompressed-nar-file").await;
+
+        let hash = nix::Hash::from_hash(HASH.to_owned());
+        let nar_info = NAR_INFO.parse::<nix::NarInfo>().unwrap();
+        let upstream =
+            nix::PriorityUpstream::from_url("https://cache.nixos.org".parse().unwrap()).into();
 
-    fn try_from(value: NarInfoWithUpstreamEntry) -> Result<Self, Self::Error> {
-        NarInfoEntry::from(value).try_into()
+        set_status(db.pool(), &hash, Status::Available)
+            .await
+            .unwrap();
+        insert_nar_info(db.pool(), &hash, &nar_info, &upstream, false)
+            .await
+            .unwrap();
+
+        let file_hash = &nar_info.file_hash;
+
+        assert!(get_cached_nar_file(db.pool(), file_hash, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(
+            get_cached_nar_file(db.pool(), file_hash, Some(&nix::CompressionType::Zstd))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            get_cached_nar_file(db.pool(), file_hash, Some(&nix::CompressionType::Xz))
+                .await
+                .unwrap()
+                .map(|nar_file| nar_file.compression),
+            Some(nix::CompressionType::Xz)
+        );
     }
 }
@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::nix;
+
+// Rendered narinfo responses of recently served hashes, so that hot paths skip the cache db
+#[derive(Clone, Debug)]
+pub struct NarInfoResponses(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    lru: Mutex<Lru>,
+    invalidation_history: usize,
+    num_hits: AtomicU64,
+    num_misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    // Bumped on every invalidation. Responses are only inserted if their hash was not invalidated
+    // since the generation their render started at, so concurrent updates to other hashes do not
+    // keep hot paths from being cached
+    generation: u64,
+    invalidated: HashMap<String, u64>,
+    // Renders started before this may have missed an invalidation that was since dropped
+    floor: u64,
+    tick: u64,
+    entries: HashMap<String, (NarInfoResponse, u64)>,
+    recency: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn is_current(&self, hash: &str, generation: u64) -> bool {
+        generation >= self.floor
+            && self
+                .invalidated
+                .get(hash)
+                .is_none_or(|invalidated| *invalidated <= generation)
+    }
+
+    // Past `history` hashes, their generations are dropped for `floor` instead
+    fn bump(&mut self, hash: Option<&str>, history: usize) {
+        self.generation += 1;
+
+        match hash {
+            Some(hash) if self.invalidated.len() < history => {
+                self.invalidated.insert(hash.to_owned(), self.generation);
+            }
+            _ => {
+                self.floor = self.generation;
+                self.invalidated.clear();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NarInfoResponse {
+    pub body: Arc<str>,
+    pub etag: String,
+}
+
+impl NarInfoResponse {
+    pub fn new(body: String) -> Self {
+        let etag = format!("\"{}\"", nix::Hash::sha256(body.as_bytes()).string);
+
+        Self {
+            body: body.into(),
+            etag,
+        }
+    }
+}
+
+impl NarInfoResponses {
+    pub fn new(invalidation_history: usize) -> Self {
+        Self(Arc::new(Inner {
+            lru: Default::default(),
+            invalidation_history,
+            num_hits: Default::default(),
+            num_misses: Default::default(),
+        }))
+    }
+
+    pub fn get(&self, hash: &str) -> Option<NarInfoResponse> {
+        let mut lru = self.0.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        let Some((response, last_used)) = lru.entries.get_mut(hash) else {
+            self.0.num_misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+
+        let response = response.clone();
+        let last_used = std::mem::replace(last_used, tick);
+
+        lru.recency.remove(&last_used);
+        lru.recency.insert(tick, hash.to_owned());
+
+        self.0.num_hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(response)
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.0.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn num_hits(&self) -> u64 {
+        self.0.num_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn num_misses(&self) -> u64 {
+        self.0.num_misses.load(Ordering::Relaxed)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.0.lru.lock().unwrap().generation
+    }
+
+    pub fn insert(
+        &self,
+        capacity: usize,
+        hash: String,
+        response: NarInfoResponse,
+        generation: u64,
+    ) {
+        let mut lru = self.0.lru.lock().unwrap();
+
+        if !lru.is_current(&hash, generation) || capacity == 0 {
+            return;
+        }
+
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        if let Some((_, last_used)) = lru.entries.insert(hash.clone(), (response, tick)) {
+            lru.recency.remove(&last_used);
+        }
+        lru.recency.insert(tick, hash);
+
+        while lru.entries.len() > capacity {
+            let Some((_, evicted)) = lru.recency.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&evicted);
+        }
+    }
+
+    // Returns the number of responses dropped
+    pub fn clear(&self) -> usize {
+        let mut lru = self.0.lru.lock().unwrap();
+        lru.bump(None, 0);
+        lru.recency.clear();
+
+        std::mem::take(&mut lru.entries).len()
+    }
+
+    pub fn invalidate(&self, hash: &nix::Hash) {
+        let mut lru = self.0.lru.lock().unwrap();
+        lru.bump(Some(&hash.string), self.0.invalidation_history);
+
+        if let Some((_, last_used)) = lru.entries.remove(&hash.string) {
+            lru.recency.remove(&last_used);
+        }
+    }
+}
+
+impl fmt::Display for NarInfoResponses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let num_hits = self.num_hits();
+        let num_misses = self.num_misses();
+        let num_entries = self.num_entries();
+
+        write!(
+            f,
+            "{num_entries} entries, {num_hits} hits, {num_misses} misses ({:.1}% hit rate)",
+            num_hits as f64 * 100.0 / (num_hits + num_misses).max(1) as f64
+        )
+    }
+}
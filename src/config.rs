@@ -1,23 +1,133 @@
-use std::{collections::BTreeSet, fmt, marker::PhantomData, path::PathBuf, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    marker::PhantomData,
+    path::PathBuf,
+    str::FromStr,
+};
 
 use serde::{Deserialize, Deserializer, Serialize};
 use url::Url;
 
 use anyhow::Context as _;
 
-use crate::nix;
+use crate::{fetch, http, nix};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
     #[serde(deserialize_with = "set_string_or_struct")]
     pub upstreams: BTreeSet<nix::PriorityUpstream>,
+    pub offline_mode: bool,
+    // Narinfos and nar files are streamed from the upstreams on every request, and nothing is
+    // written to the local data path. The cache db is kept in memory
+    pub proxy_only: bool,
+    pub store_dir: PathBuf,
+    pub canonicalize_store_paths: bool,
+    pub want_mass_query: bool,
+    pub priority: u32,
+    pub user_agent: String,
+    pub user_agent_contact: Option<String>,
 
     pub channel_url: Url,
     pub channels: Vec<nix::Channel>,
+    pub channel_sync_schedule: Option<String>,
+    pub purge_stale_after_sync: bool,
+    pub record_channel_membership: bool,
+    pub store_paths_max_size: u64,
+    pub diff_bloom_false_positive_rate: Option<f64>,
+    pub mirror_concurrency: usize,
+    // Narinfos fetched at once across all closure walks. Those of client requests are not held up
+    pub max_concurrent_narinfo_fetches: usize,
+    // Bytes of a nar download buffered before each write to disk. Larger means fewer, bigger writes
+    // at the cost of that much more memory per concurrent download. 0 writes chunks as they come
+    pub nar_write_buffer_size: usize,
+    // Times in a row an interrupted nar download is resumed without getting any further before
+    // giving up, 0 to never resume
+    pub nar_resume_attempts: usize,
+    pub on_file_hash_mismatch: OnFileHashMismatch,
+    pub worker_count: usize,
+    pub sync_concurrency: usize,
+    pub export_zstd_level: u32,
+    pub queue_high_water_mark: Option<i64>,
+    // Pending jobs across both queues past which `queue_overflow_policy` applies to new ones
+    pub max_queue_size: Option<i64>,
+    pub queue_overflow_policy: QueueOverflowPolicy,
+    pub queue_retry_after_secs: u64,
+
+    pub external_url: Option<Url>,
+    pub expose_error_detail: bool,
+    pub error_body_template: String,
+    pub extra_headers: BTreeMap<String, String>,
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    pub statsd: Option<StatsdConfig>,
 
     pub local_data_path: PathBuf,
     pub database_max_connections: u32,
+    pub database_page_size: Option<u32>,
+    pub database_cache_size: Option<i64>,
+    pub database_full_retry_secs: u64,
+    // Truncates the WAL on top of SQLite's own checkpoints, which never shrink it
+    pub wal_checkpoint_schedule: Option<String>,
+    // Total size of the local data path. Once past `gc_high_water` of it, the least recently
+    // accessed nar files are evicted until it is back under `gc_low_water` of it
+    pub max_cache_size_bytes: Option<u64>,
+    pub gc_high_water: f64,
+    pub gc_low_water: f64,
+    // Walking the whole data path to size it is slow, so not much more often than the default
+    pub gc_check_schedule: String,
+    pub last_accessed_flush_interval_secs: u64,
+    pub size_drift_warn_percent: f64,
+    pub last_accessed_flush_threshold: usize,
+    pub miss_grace_period_secs: u64,
+    pub tombstone_ttl_secs: Option<u64>,
+    pub nar_info_cache_size: usize,
+    // Number of recently invalidated hashes whose generations are tracked, past which any
+    // narinfo render in flight is dropped rather than just those of the invalidated hashes
+    pub nar_info_invalidation_history: usize,
+    pub nar_revalidate_after_secs: Option<u64>,
+    pub nar_revalidate_sample_every: u64,
+    pub verify_on_start: VerifyOnStart,
+    // Nar files hashed at random with `verify_on_start = "sampled"`
+    pub verify_on_start_sample_size: usize,
+    pub verify_on_start_concurrency: usize,
+
+    pub prefetch_on_start: Option<PrefetchList>,
+    pub golden_paths: Option<PrefetchList>,
+    pub golden_refresh_schedule: Option<String>,
+
+    pub cache_systems: Option<BTreeSet<String>>,
+    pub cache_without_system: bool,
+
+    pub normalize_unknown_deriver: bool,
+    // Narinfos missing any of these are rejected, trying the next upstream instead
+    pub required_narinfo_fields: BTreeSet<nix::NarInfoField>,
+    pub store_raw_narinfo: bool,
+    pub narinfo_trailing_newline: bool,
+    pub require_signature_on_serve: bool,
+    // A Nix secret key, as made by `nix-store --generate-binary-cache-key`. Served narinfos are
+    // signed with it, so clients can trust this cache by its public key
+    pub secret_key_file: Option<PathBuf>,
+    pub resign_policy: ResignPolicy,
+    pub serve_narinfo_while_fetching: bool,
+    // How long requests for a nar file still being fetched wait for it rather than missing, 0 to
+    // not wait. Only such files can be requested, as with `serve_narinfo_while_fetching`
+    pub nar_coalesce_timeout_secs: u64,
+    // Nar files not cached (yet) are redirected to on the upstream their narinfo came from, rather
+    // than waited on or missed, with a job caching them pushed if none is in flight already. Only
+    // those of narinfos in the cache db are known, as with `nar_coalesce_timeout_secs`
+    pub redirect_uncached_nar: bool,
+    pub cache_closure_on_miss: bool,
+    pub closure_max_paths: usize,
+    // Levels of references walked, the missed path itself being the first
+    pub closure_max_depth: usize,
+    pub max_references: usize,
+
+    pub allow_admin_writes_via_get: bool,
+    // Holds a token every `/admin` request must carry as `Authorization: Bearer <token>`. Without
+    // one, the admin endpoints are open to anyone who can reach the server
+    pub admin_token_file: Option<PathBuf>,
 }
 
 impl Config {
@@ -26,14 +136,7 @@ impl Config {
     pub fn get() -> Self {
         tracing::info!("Reading config from env");
 
-        let config = (|| -> anyhow::Result<Config> {
-            let config_path = std::env::var(Self::ENV_VAR)?;
-            let config_str = std::fs::read_to_string(&config_path)
-                .with_context(|| format!("Unable to read config from {config_path:?}"))?;
-
-            Ok(toml::from_str::<Config>(&config_str)?)
-        })()
-        .unwrap_or_else(|e| {
+        let config = Self::load().unwrap_or_else(|e| {
             tracing::warn!("Unable to read config from env: {e}");
             tracing::info!("Using default config");
             Config::default()
@@ -43,6 +146,326 @@ impl Config {
 
         config
     }
+
+    pub fn load() -> anyhow::Result<Self> {
+        let config_path = std::env::var(Self::ENV_VAR)
+            .with_context(|| format!("{} is not set", Self::ENV_VAR))?;
+        let config_str = std::fs::read_to_string(&config_path)
+            .with_context(|| format!("Unable to read config from {config_path:?}"))?;
+
+        toml::from_str::<Config>(&config_str)
+            .with_context(|| format!("Unable to parse config from {config_path:?}"))
+    }
+
+    pub fn validate(&self) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+
+        if self.upstreams.is_empty() {
+            errors.push(anyhow::anyhow!("No upstreams configured"));
+        }
+
+        for upstream in &self.upstreams {
+            if let Err(e) = fetch::check_url(upstream.url()) {
+                errors.push(e.context("Invalid upstream"));
+            }
+        }
+
+        if self.proxy_only && self.offline_mode {
+            errors.push(anyhow::anyhow!(
+                "proxy_only and offline_mode cannot both be set"
+            ));
+        }
+
+        if let Err(e) = fetch::check_url(&self.channel_url) {
+            errors.push(e.context("Invalid channel_url"));
+        }
+
+        if let Some(schedule) = &self.channel_sync_schedule {
+            if let Err(e) = apalis::cron::Schedule::from_str(schedule) {
+                errors.push(anyhow::anyhow!(
+                    "Invalid channel_sync_schedule {schedule:?}: {e}"
+                ));
+            }
+        }
+
+        if let Some(schedule) = &self.wal_checkpoint_schedule {
+            if let Err(e) = apalis::cron::Schedule::from_str(schedule) {
+                errors.push(anyhow::anyhow!(
+                    "Invalid wal_checkpoint_schedule {schedule:?}: {e}"
+                ));
+            }
+        }
+
+        if self.max_cache_size_bytes == Some(0) {
+            errors.push(anyhow::anyhow!("max_cache_size_bytes must be at least 1"));
+        }
+
+        if !(self.gc_high_water > 0.0 && self.gc_high_water <= 1.0) {
+            errors.push(anyhow::anyhow!(
+                "gc_high_water {} is not in (0, 1]",
+                self.gc_high_water
+            ));
+        }
+
+        if !(self.gc_low_water > 0.0 && self.gc_low_water < self.gc_high_water) {
+            errors.push(anyhow::anyhow!(
+                "gc_low_water {} is not in (0, gc_high_water)",
+                self.gc_low_water
+            ));
+        }
+
+        if let Err(e) = apalis::cron::Schedule::from_str(&self.gc_check_schedule) {
+            errors.push(anyhow::anyhow!(
+                "Invalid gc_check_schedule {:?}: {e}",
+                self.gc_check_schedule
+            ));
+        }
+
+        if let Some(url) = &self.external_url {
+            if !matches!(url.scheme(), "http" | "https") {
+                errors.push(anyhow::anyhow!("external_url {url} is not an http(s) url"));
+            }
+        }
+
+        if !self
+            .error_body_template
+            .contains(http::REQUEST_ID_PLACEHOLDER)
+        {
+            errors.push(anyhow::anyhow!(
+                "error_body_template does not contain {}",
+                http::REQUEST_ID_PLACEHOLDER
+            ));
+        }
+
+        if self.user_agent.is_empty() {
+            errors.push(anyhow::anyhow!("user_agent must not be empty"));
+        } else if let Err(e) = axum::http::HeaderValue::from_str(&self.user_agent()) {
+            errors.push(anyhow::anyhow!(
+                "Invalid user_agent {:?}: {e}",
+                self.user_agent()
+            ));
+        }
+
+        if let Err(e) = self.extra_headers() {
+            errors.push(e);
+        }
+
+        if let Err(e) = self.admin_token() {
+            errors.push(e);
+        }
+
+        if self
+            .max_queue_size
+            .is_some_and(|max_queue_size| max_queue_size < 1)
+        {
+            errors.push(anyhow::anyhow!("max_queue_size must be at least 1"));
+        }
+
+        if let Some(statsd) = &self.statsd {
+            if !statsd
+                .address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+            {
+                errors.push(anyhow::anyhow!(
+                    "statsd address {:?} is not a host:port",
+                    statsd.address
+                ));
+            }
+
+            if statsd.interval_secs == 0 {
+                errors.push(anyhow::anyhow!("statsd interval_secs must be at least 1"));
+            }
+
+            if statsd.prefix.contains([':', '|', '@', '\n']) {
+                errors.push(anyhow::anyhow!(
+                    "statsd prefix {:?} must not contain ':', '|', '@' or newlines",
+                    statsd.prefix
+                ));
+            }
+        }
+
+        if let Some(path) = &self.secret_key_file {
+            if let Err(e) = nix::SecretKey::from_file(path) {
+                errors.push(e);
+            }
+        } else if self.resign_policy == ResignPolicy::StripAndResign {
+            errors.push(anyhow::anyhow!(
+                "resign_policy strip_and_resign requires a secret_key_file"
+            ));
+        }
+
+        if !self.store_dir.is_absolute() {
+            errors.push(anyhow::anyhow!(
+                "store_dir {:?} is not an absolute path",
+                self.store_dir
+            ));
+        }
+
+        if let Some(PrefetchList::File(path)) = &self.prefetch_on_start {
+            if !path.is_file() {
+                errors.push(anyhow::anyhow!(
+                    "prefetch_on_start file {path:?} does not exist"
+                ));
+            }
+        }
+
+        if let Some(PrefetchList::File(path)) = &self.golden_paths {
+            if !path.is_file() {
+                errors.push(anyhow::anyhow!("golden_paths file {path:?} does not exist"));
+            }
+        }
+
+        match (&self.golden_paths, &self.golden_refresh_schedule) {
+            (Some(_), None) => errors.push(anyhow::anyhow!(
+                "golden_paths is set without a golden_refresh_schedule"
+            )),
+            (_, Some(schedule)) => {
+                if let Err(e) = apalis::cron::Schedule::from_str(schedule) {
+                    errors.push(anyhow::anyhow!(
+                        "Invalid golden_refresh_schedule {schedule:?}: {e}"
+                    ));
+                }
+            }
+            (None, None) => {}
+        }
+
+        if let Some(rate) = self.diff_bloom_false_positive_rate {
+            if !(rate > 0.0 && rate < 1.0) {
+                errors.push(anyhow::anyhow!(
+                    "diff_bloom_false_positive_rate {rate} is not between 0 and 1"
+                ));
+            }
+        }
+
+        if self.mirror_concurrency == 0 {
+            errors.push(anyhow::anyhow!("mirror_concurrency must be at least 1"));
+        }
+
+        if self.max_concurrent_narinfo_fetches == 0 {
+            errors.push(anyhow::anyhow!(
+                "max_concurrent_narinfo_fetches must be at least 1"
+            ));
+        }
+
+        if self.worker_count == 0 {
+            errors.push(anyhow::anyhow!("worker_count must be at least 1"));
+        }
+
+        if self.sync_concurrency == 0 {
+            errors.push(anyhow::anyhow!("sync_concurrency must be at least 1"));
+        }
+
+        if self.verify_on_start == VerifyOnStart::Sampled && self.verify_on_start_sample_size == 0 {
+            errors.push(anyhow::anyhow!(
+                "verify_on_start_sample_size must be at least 1"
+            ));
+        }
+
+        if self.verify_on_start_concurrency == 0 {
+            errors.push(anyhow::anyhow!(
+                "verify_on_start_concurrency must be at least 1"
+            ));
+        }
+
+        if self.nar_revalidate_sample_every == 0 {
+            errors.push(anyhow::anyhow!(
+                "nar_revalidate_sample_every must be at least 1"
+            ));
+        }
+
+        if self.max_references == 0 {
+            errors.push(anyhow::anyhow!("max_references must be at least 1"));
+        }
+
+        if !(1..=22).contains(&self.export_zstd_level) {
+            errors.push(anyhow::anyhow!(
+                "export_zstd_level {} is not between 1 and 22",
+                self.export_zstd_level
+            ));
+        }
+
+        if self.last_accessed_flush_threshold == 0 {
+            errors.push(anyhow::anyhow!(
+                "last_accessed_flush_threshold must be at least 1"
+            ));
+        }
+
+        errors
+    }
+
+    // Ensures the path ends with `/` so that joining keeps the last segment (e.g. `/cache/`)
+    pub fn external_base_url(&self) -> Option<Url> {
+        self.external_url.clone().map(|mut url| {
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+            url
+        })
+    }
+
+    // e.g. `nicacher/0.1.0 (+https://cache.example.org/contact)`, so upstream admins can reach out
+    pub fn user_agent(&self) -> String {
+        match &self.user_agent_contact {
+            Some(contact) => format!("{} ({contact})", self.user_agent),
+            None => self.user_agent.clone(),
+        }
+    }
+
+    // Static headers added to narinfo and nar responses, for fronting proxies and CDNs
+    pub fn extra_headers(
+        &self,
+    ) -> anyhow::Result<Vec<(axum::http::header::HeaderName, axum::http::HeaderValue)>> {
+        use axum::http::{header, header::HeaderName, HeaderValue};
+
+        self.extra_headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid extra_headers name {name:?}"))?;
+                let value = HeaderValue::from_str(value)
+                    .with_context(|| format!("Invalid extra_headers value {value:?} of {name}"))?;
+
+                if name == header::CONTENT_TYPE {
+                    anyhow::bail!("extra_headers must not set {name}");
+                }
+
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    pub fn admin_token(&self) -> anyhow::Result<Option<String>> {
+        let Some(path) = &self.admin_token_file else {
+            return Ok(None);
+        };
+
+        let token = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read admin_token_file {path:?}"))?;
+        let token = token.trim();
+
+        if token.is_empty() {
+            anyhow::bail!("admin_token_file {path:?} is empty");
+        }
+
+        Ok(Some(token.to_owned()))
+    }
+
+    pub fn ingest_store_path(&self, store_path: nix::StorePath) -> nix::StorePath {
+        if self.canonicalize_store_paths {
+            store_path.canonicalized(&self.store_dir)
+        } else {
+            store_path
+        }
+    }
+
+    pub fn is_system_cached(&self, system: Option<&str>) -> bool {
+        match (&self.cache_systems, system) {
+            (None, _) => true,
+            (Some(_), None) => self.cache_without_system,
+            (Some(systems), Some(system)) => systems.contains(system),
+        }
+    }
 }
 
 impl Default for Config {
@@ -52,14 +475,180 @@ impl Default for Config {
                 Url::parse("https://cache.nixos.org/").unwrap(),
             )]
             .into(),
+            offline_mode: false,
+            proxy_only: false,
+            store_dir: "/nix/store".into(),
+            canonicalize_store_paths: false,
+            want_mass_query: false,
+            priority: 30,
+            user_agent: concat!("nicacher/", env!("CARGO_PKG_VERSION")).to_owned(),
+            user_agent_contact: None,
             channel_url: Url::parse("https://channels.nixos.org/").unwrap(),
             channels: vec![nix::Channel::NixpkgsUnstable()],
+            channel_sync_schedule: None,
+            purge_stale_after_sync: false,
+            record_channel_membership: false,
+            store_paths_max_size: 64 * 1024 * 1024,
+            diff_bloom_false_positive_rate: None,
+            mirror_concurrency: 4,
+            max_concurrent_narinfo_fetches: 32,
+            nar_write_buffer_size: 64 * 1024,
+            nar_resume_attempts: 3,
+            on_file_hash_mismatch: OnFileHashMismatch::NextUpstream,
+            worker_count: 2,
+            sync_concurrency: 4,
+            export_zstd_level: 3,
+            queue_high_water_mark: None,
+            max_queue_size: None,
+            queue_overflow_policy: QueueOverflowPolicy::Reject,
+            queue_retry_after_secs: 30,
+            external_url: None,
+            expose_error_detail: true,
+            error_body_template: "Internal server error, see request id {request_id}".to_owned(),
+            extra_headers: BTreeMap::new(),
+            access_log: None,
+            access_log_format: AccessLogFormat::Combined,
+            statsd: None,
             local_data_path: ".".into(),
             database_max_connections: 20,
+            database_page_size: None,
+            database_cache_size: None,
+            database_full_retry_secs: 60,
+            wal_checkpoint_schedule: None,
+            max_cache_size_bytes: None,
+            gc_high_water: 0.9,
+            gc_low_water: 0.8,
+            gc_check_schedule: "0 */5 * * * *".to_owned(),
+            last_accessed_flush_interval_secs: 30,
+            size_drift_warn_percent: 5.0,
+            last_accessed_flush_threshold: 1000,
+            miss_grace_period_secs: 5,
+            tombstone_ttl_secs: None,
+            nar_info_cache_size: 0,
+            nar_info_invalidation_history: 4096,
+            nar_revalidate_after_secs: None,
+            nar_revalidate_sample_every: 10,
+            verify_on_start: VerifyOnStart::Off,
+            verify_on_start_sample_size: 1000,
+            verify_on_start_concurrency: 4,
+            prefetch_on_start: None,
+            golden_paths: None,
+            golden_refresh_schedule: None,
+            cache_systems: None,
+            cache_without_system: true,
+            normalize_unknown_deriver: false,
+            required_narinfo_fields: BTreeSet::new(),
+            store_raw_narinfo: false,
+            narinfo_trailing_newline: true,
+            require_signature_on_serve: false,
+            secret_key_file: None,
+            resign_policy: ResignPolicy::Keep,
+            serve_narinfo_while_fetching: false,
+            redirect_uncached_nar: false,
+            nar_coalesce_timeout_secs: 30,
+            cache_closure_on_miss: false,
+            closure_max_paths: 1000,
+            closure_max_depth: 32,
+            max_references: 10_000,
+            allow_admin_writes_via_get: false,
+            admin_token_file: None,
+        }
+    }
+}
+
+// `access_log` of "-" writes to stdout
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+}
+
+// `drop_oldest_low` makes room by dropping the longest pending low priority job, rejecting the new
+// one only when there is none to drop
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflowPolicy {
+    Reject,
+    DropOldestLow,
+}
+
+// What is done with the upstream `Sig` lines of served narinfos. `keep` adds the signature by
+// `secret_key_file`, if any, after them. `strip_and_resign` replaces them with it, for chaining
+// caches whose clients only trust that key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResignPolicy {
+    Keep,
+    StripAndResign,
+}
+
+// Which cached nar files are hashed against their `FileHash` before anything is served. Those that
+// no longer match, or are gone, are purged and fetched again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyOnStart {
+    Off,
+    Sampled,
+    Full,
+}
+
+// When a downloaded nar file does not hash to the `FileHash` of its narinfo. With
+// `serve_narinfo_while_fetching`, the narinfo is already being served, so `next_upstream` fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFileHashMismatch {
+    NextUpstream,
+    Fail,
+    // Kept under the hash it was downloaded with, which the cached narinfo is updated to state
+    Accept,
+}
+
+// Pushes the metrics of /admin/stats over UDP, e.g. to a local statsd or Datadog agent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    pub address: String,
+    pub interval_secs: u64,
+    // Prepended to every metric name with a `.`, unless empty
+    pub prefix: String,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:8125".to_owned(),
+            interval_secs: 10,
+            prefix: "nicacher".to_owned(),
         }
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PrefetchList {
+    File(PathBuf),
+    Inline(Vec<String>),
+}
+
+impl PrefetchList {
+    pub async fn entries(&self) -> anyhow::Result<Vec<String>> {
+        let entries = match self {
+            Self::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Unable to read prefetch list from {path:?}"))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned)
+                .collect(),
+            Self::Inline(entries) => entries.clone(),
+        };
+
+        Ok(entries)
+    }
+}
+
 fn set_string_or_struct<'de, T, D>(deserializer: D) -> Result<BTreeSet<T>, D::Error>
 where
     T: Deserialize<'de> + FromStr + Ord,
@@ -1,12 +1,30 @@
-use std::{collections::HashSet, io, str::FromStr as _};
+mod transport;
+
+pub use transport::with_timeout;
+
+use std::{collections::HashSet, io, str::FromStr as _, sync::OnceLock};
 
 use anyhow::Context as _;
 use futures::{stream, StreamExt as _, TryStreamExt as _};
 
-use crate::{config, nix};
+use crate::{cache, config, metrics, nix};
 
 const STORE_PATHS_FILE: &str = "store-paths.xz";
 
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BODY_SNIPPET_LEN: usize = 64;
+const DECODE_BUF_SIZE: usize = 64 * 1024;
+
+// Shared by every closure walk, sized from the config on first use, which never changes for the
+// lifetime of the process
+static CLOSURE_NARINFO_FETCHES: OnceLock<tokio::sync::Semaphore> = OnceLock::new();
+
+// A nar starts with the length-prefixed and padded string "nix-archive-1"
+const NAR_MAGIC: &[u8] = b"\x0d\0\0\0\0\0\0\0nix-archive-1\0\0\0";
+// Enough of a compressed nar to decompress its header from
+const NAR_PREFIX_LEN: usize = 64 * 1024;
+
 pub async fn request_all_channel_stores(
     config: &config::Config,
 ) -> anyhow::Result<HashSet<nix::StorePath>> {
@@ -15,13 +33,12 @@ pub async fn request_all_channel_stores(
     stream::iter(config.channels.iter())
         .then(|channel| request_channel_store::<Vec<_>>(config, channel))
         .try_fold(HashSet::new(), |mut set, paths| async {
-            set.extend(paths.into_iter());
+            set.extend(paths);
             Ok(set)
         })
         .await
 }
 
-#[tracing::instrument(skip(config))]
 pub async fn request_channel_store<T>(
     config: &config::Config,
     channel: &nix::Channel,
@@ -29,6 +46,23 @@ pub async fn request_channel_store<T>(
 where
     T: std::iter::FromIterator<nix::StorePath>,
 {
+    let mut store_paths = Vec::new();
+    for_each_channel_store_path(config, channel, |store_path| store_paths.push(store_path)).await?;
+    Ok(store_paths.into_iter().collect())
+}
+
+// Hands each store path of `channel` to `on_store_path` as it is decoded, never holding the whole
+// listing in memory
+#[tracing::instrument(skip(config, on_store_path))]
+pub async fn for_each_channel_store_path(
+    config: &config::Config,
+    channel: &nix::Channel,
+    mut on_store_path: impl FnMut(nix::StorePath),
+) -> anyhow::Result<()> {
+    if config.offline_mode {
+        anyhow::bail!("Not requesting store paths of {channel} in offline mode");
+    }
+
     tracing::info!("Requesting store paths of {channel}");
 
     let store_paths_url = config
@@ -43,88 +77,77 @@ where
 
     tracing::debug!("Fetching newest store paths list from {store_paths_url}");
 
-    let res = reqwest::get(store_paths_url.clone())
-        .await?
-        .error_for_status()
+    let res = transport::get_stream(&store_paths_url)
+        .await
         .with_context(|| format!("Failed to get store paths from {channel} ({store_paths_url})"))?;
 
     tracing::debug!("Decoding received {store_paths_url}");
 
-    decode_xz_to_string(&res.bytes().await?)?
-        .trim()
-        .lines()
-        .map(nix::StorePath::from_str)
-        .collect::<Result<T, _>>()
-        .map_err(anyhow::Error::from)
+    let mut num_mismatched = 0;
+
+    decode_xz_lines(res, config.store_paths_max_size, &mut |line| {
+        let store_path = config.ingest_store_path(nix::StorePath::from_str(line)?);
+
+        if store_path.store_path_root == config.store_dir {
+            on_store_path(store_path);
+        } else {
+            num_mismatched += 1;
+        }
+
+        Ok(())
+    })
+    .await
+    .with_context(|| format!("Failed to decode store paths from {store_paths_url}"))?;
+
+    if num_mismatched > 0 {
+        tracing::warn!(
+            "Ignoring {num_mismatched} store paths of {channel} outside of {}",
+            config.store_dir.display()
+        );
+    }
+
+    Ok(())
 }
 
-#[tracing::instrument(skip(config))]
-pub async fn request_derivation(
-    config: &config::Config,
-    hash: &nix::Hash,
-) -> Option<nix::Derivation> {
-    let stream = stream::iter(config.upstreams.iter()).filter_map(|upstream| async {
-        (|| async {
-            let url = upstream
-                .url()
-                .join(&format!("{}.narinfo", hash.string))
-                .with_context(|| {
-                    format!(
-                        "Failed to build narinfo url with {} and {}",
-                        upstream.url(),
-                        hash.string
-                    )
-                })?;
-
-            let nar_info = {
-                let text = (|| async {
-                    reqwest::get(url.clone())
-                        .await?
-                        .error_for_status()?
-                        .text()
-                        .await
-                })()
-                .await
-                .with_context(|| format!("Failed to request {}.narinfo from {url}", hash.string))?;
-
-                nix::NarInfo::from_str(&text).with_context(|| {
-                    format!(
-                        "Failed to parse narinfo when fetching {}.narinfo from {url}",
-                        hash.string
-                    )
-                })?
-            };
+pub fn init(config: &config::Config) -> anyhow::Result<()> {
+    transport::init(&config.user_agent())
+}
 
-            let info = nar_info.store_path.derivation_info.clone();
+pub fn check_url(url: &url::Url) -> anyhow::Result<()> {
+    transport::for_url(url).map(|_| ())
+}
 
-            let nar_file = {
-                let url = upstream.url().join(&nar_info.url)?;
+// Each upstream is only tried once the previous one is exhausted, so the caller can move on to the
+// next if the nar file it got turns out to be bad
+pub fn request_derivations<'a>(
+    config: &'a config::Config,
+    hash: &'a nix::Hash,
+) -> impl futures::Stream<Item = nix::Derivation> + 'a {
+    if config.offline_mode {
+        tracing::debug!("Not fetching {} in offline mode", hash.string);
+    }
 
-                let info = nix::NarFileInfo {
-                    hash: nar_info.file_hash.clone(),
-                    compression: nar_info.compression.clone(),
-                };
+    let upstreams = config.upstreams.iter().filter(|_| !config.offline_mode);
 
-                let data = (|| async {
-                    reqwest::get(url.clone())
-                        .await?
-                        .error_for_status()?
-                        .bytes()
-                        .await
-                })()
-                .await
-                .with_context(|| format!("Failed to request nar file from {url}"))?;
+    stream::iter(upstreams).filter_map(move |upstream| async move {
+        async {
+            let preferred_compression = upstream.preferred_compression();
+            let upstream: nix::Upstream = upstream.clone().into();
 
-                nix::NarFile { info, data }
-            };
+            let res = request_nar_info_from(config, &upstream, hash).await;
+            metrics::record_upstream_fetch(metrics::Fetch::NarInfo, res.is_ok());
+            let (mut nar_info, raw_nar_info) = res?;
+            let nar_file =
+                request_nar_file(config, &upstream, &mut nar_info, preferred_compression).await?;
 
             Ok::<nix::Derivation, anyhow::Error>(nix::Derivation {
-                info,
+                info: nar_info.store_path.derivation_info.clone(),
                 nar_info,
+                raw_nar_info,
                 nar_file,
-                upstream: upstream.clone().into(),
+                upstream,
             })
-        })()
+        }
         .await
         .map_err(|e| {
             tracing::warn!(
@@ -134,6 +157,34 @@ pub async fn request_derivation(
             );
         })
         .ok()
+    })
+}
+
+#[tracing::instrument(skip(config))]
+pub async fn request_nar_info(
+    config: &config::Config,
+    hash: &nix::Hash,
+) -> Option<(nix::NarInfo, String, nix::Upstream)> {
+    if config.offline_mode {
+        tracing::debug!("Not fetching {}.narinfo in offline mode", hash.string);
+        return None;
+    }
+
+    let stream = stream::iter(config.upstreams.iter()).filter_map(|upstream| async {
+        let upstream: nix::Upstream = upstream.clone().into();
+
+        let res = request_nar_info_from(config, &upstream, hash).await;
+        metrics::record_upstream_fetch(metrics::Fetch::NarInfo, res.is_ok());
+
+        res.map(|(nar_info, raw_nar_info)| (nar_info, raw_nar_info, upstream.clone()))
+            .map_err(|e| {
+                tracing::warn!(
+                    "Failed to fetch {}.narinfo from {}: {e:#}",
+                    hash.string,
+                    upstream.url()
+                );
+            })
+            .ok()
     });
 
     futures::pin_mut!(stream);
@@ -141,13 +192,792 @@ pub async fn request_derivation(
     stream.next().await
 }
 
-fn decode_xz_to_string(bytes: &[u8]) -> anyhow::Result<String> {
+#[derive(Debug, Default)]
+pub struct Closure {
+    pub nar_infos: Vec<nix::NarInfo>,
+    pub num_failed: usize,
+    // Whether references were left unvisited after reaching `max_members` or `max_depth`
+    pub is_truncated: bool,
+}
+
+// Walks the references of `hash` breadth-first, only fetching narinfos
+#[tracing::instrument(skip(config))]
+pub async fn request_closure(
+    config: &config::Config,
+    hash: &nix::Hash,
+    max_members: usize,
+    max_depth: usize,
+) -> Closure {
+    let mut closure = Closure::default();
+    let mut seen = HashSet::from([hash.string.clone()]);
+    let mut frontier = vec![hash.clone()];
+    let mut depth = 0;
+
+    while !frontier.is_empty() {
+        if depth >= max_depth {
+            closure.is_truncated = true;
+            break;
+        }
+        depth += 1;
+
+        let results = stream::iter(std::mem::take(&mut frontier))
+            .map(|hash| async move {
+                let _permit = CLOSURE_NARINFO_FETCHES
+                    .get_or_init(|| {
+                        tokio::sync::Semaphore::new(config.max_concurrent_narinfo_fetches)
+                    })
+                    .acquire()
+                    .await
+                    .expect("Semaphore is never closed");
+
+                request_nar_info(config, &hash).await
+            })
+            .buffer_unordered(config.max_concurrent_narinfo_fetches)
+            .collect::<Vec<_>>()
+            .await;
+
+        for result in results {
+            let Some((nar_info, ..)) = result else {
+                closure.num_failed += 1;
+                continue;
+            };
+
+            for reference in &nar_info.references {
+                if seen.contains(&reference.hash.string) {
+                    continue;
+                }
+
+                if seen.len() >= max_members {
+                    closure.is_truncated = true;
+                    break;
+                }
+
+                seen.insert(reference.hash.string.clone());
+                frontier.push(reference.hash.clone());
+            }
+
+            closure.nar_infos.push(nar_info);
+        }
+    }
+
+    closure
+}
+
+async fn request_nar_info_from(
+    config: &config::Config,
+    upstream: &nix::Upstream,
+    hash: &nix::Hash,
+) -> anyhow::Result<(nix::NarInfo, String)> {
+    let url = upstream
+        .url()
+        .join(&format!("{}.narinfo", hash.string))
+        .with_context(|| {
+            format!(
+                "Failed to build narinfo url with {} and {}",
+                upstream.url(),
+                hash.string
+            )
+        })?;
+
+    let text = async {
+        let res = transport::get(&url).await?;
+        Ok::<_, anyhow::Error>(String::from_utf8(res.data.into())?)
+    }
+    .await
+    .with_context(|| format!("Failed to request {}.narinfo from {url}", hash.string))?;
+
+    let mut nar_info = nix::NarInfo::from_str(&text).with_context(|| {
+        format!(
+            "Failed to parse narinfo when fetching {}.narinfo from {url}",
+            hash.string
+        )
+    })?;
+
+    // guards closure caching against malformed narinfos with absurd reference counts
+    if nar_info.references.len() > config.max_references {
+        anyhow::bail!(
+            "{}.narinfo declares {} references, more than max_references {}",
+            hash.string,
+            nar_info.references.len(),
+            config.max_references
+        );
+    }
+
+    if config.normalize_unknown_deriver {
+        nar_info.normalize_unknown_deriver();
+    }
+
+    // after normalizing, so an `unknown-deriver` does not count as one
+    let missing = config
+        .required_narinfo_fields
+        .iter()
+        .filter(|field| !nar_info.has_field(**field))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "{}.narinfo is missing required fields: {}",
+            hash.string,
+            missing.join(", ")
+        );
+    }
+
+    nar_info.store_path = config.ingest_store_path(nar_info.store_path);
+
+    if nar_info.store_path.store_path_root != config.store_dir {
+        anyhow::bail!(
+            "Store path {} of {}.narinfo is not in {}",
+            nar_info.store_path,
+            hash.string,
+            config.store_dir.display()
+        );
+    }
+
+    if !config.is_system_cached(nar_info.system.as_deref()) {
+        anyhow::bail!(
+            "System {:?} of {}.narinfo is not configured to be cached",
+            nar_info.system,
+            hash.string
+        );
+    }
+
+    Ok((nar_info, text))
+}
+
+// Tries the preferred compressions ranked above the narinfo's own first, updating `nar_info` to
+// describe the fetched file when one of them is used
+#[tracing::instrument(skip(config, nar_info), fields(url = %nar_info.url))]
+pub async fn request_nar_file(
+    config: &config::Config,
+    upstream: &nix::Upstream,
+    nar_info: &mut nix::NarInfo,
+    preferred_compression: &[nix::CompressionType],
+) -> anyhow::Result<nix::NarFile> {
+    let alternatives = preferred_compression
+        .iter()
+        .take_while(|compression| **compression != nar_info.compression);
+
+    for compression in alternatives {
+        let Some(alternative_url) = nar_info
+            .url
+            .strip_suffix(&format!(".{}", nar_info.compression.extension()))
+            .map(|base| format!("{base}.{}", compression.extension()))
+        else {
+            break;
+        };
+
+        match request_nar_file_from(config, upstream, &alternative_url, compression, 0).await {
+            Ok((url, _, body)) => {
+                tracing::debug!("Using {compression} compressed nar file from {url}");
+                metrics::record_upstream_fetch(metrics::Fetch::NarFile, true);
+
+                // its FileHash and FileSize are only known once it is written out
+                nar_info.url = alternative_url;
+                nar_info.compression = compression.clone();
+
+                return Ok(nix::NarFile {
+                    compression: compression.clone(),
+                    hash: None,
+                    url,
+                    offset: 0,
+                    body,
+                });
+            }
+            Err(e) => tracing::debug!("No {compression} compressed nar file available: {e:#}"),
+        }
+    }
+
+    let resume_from =
+        cache::partial_nar_file_len(config, &nar_info.file_hash, &nar_info.compression).await;
+
+    // unavailable alternatives are not failures, only the narinfo's own nar file is expected
+    let res = request_nar_file_from(
+        config,
+        upstream,
+        &nar_info.url,
+        &nar_info.compression,
+        resume_from,
+    )
+    .await;
+    metrics::record_upstream_fetch(metrics::Fetch::NarFile, res.is_ok());
+    let (url, offset, body) = res?;
+
+    Ok(nix::NarFile {
+        compression: nar_info.compression.clone(),
+        hash: Some(nar_info.file_hash.clone()),
+        url,
+        offset,
+        body,
+    })
+}
+
+// Only a prefix is checked up front, the rest is left for the caller to stream to disk. Continues
+// from `resume_from` with a Range request, returning where the body starts, which is 0 if the
+// upstream does not support ranges
+async fn request_nar_file_from(
+    config: &config::Config,
+    upstream: &nix::Upstream,
+    nar_url: &str,
+    compression: &nix::CompressionType,
+    resume_from: u64,
+) -> anyhow::Result<(
+    url::Url,
+    u64,
+    futures::stream::BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+)> {
+    // an absolute `URL` replaces the upstream as a whole
+    let url = upstream.url().join(nar_url)?;
+    check_nar_url(upstream, &url)?;
+
+    let transport::StreamResponse {
+        status,
+        body,
+        content_type,
+    } = match transport::get_stream_from(&url, resume_from).await {
+        // e.g. 416 for a partial file longer than the nar file now is
+        Err(e) if resume_from > 0 => {
+            tracing::warn!("Failed to resume nar file from {url}, starting over: {e:#}");
+            transport::get_stream(&url).await
+        }
+        res => res,
+    }
+    .with_context(|| format!("Failed to request nar file from {url}"))?;
+
+    // its prefix was already checked by the download that left the partial file
+    if status == reqwest::StatusCode::PARTIAL_CONTENT {
+        tracing::info!("Resuming nar file from {url} at {resume_from} bytes");
+
+        let body = resuming(url.clone(), body, resume_from, config.nar_resume_attempts);
+        return Ok((url, resume_from, body.boxed()));
+    }
+
+    if resume_from > 0 {
+        tracing::warn!("{url} does not support ranges, downloading it again from the start");
+    }
+
+    // a nar smaller than the prefix has already ended by the time the rest is chained on
+    let mut body = body.fuse();
+    let prefix = read_prefix(&mut body, NAR_PREFIX_LEN)
+        .await
+        .with_context(|| format!("Failed to request nar file from {url}"))?;
+
+    check_compression_magic(&prefix, compression, content_type.as_deref())
+        .with_context(|| format!("Invalid nar file from {url}"))?;
+    check_nar_header(&prefix, compression)
+        .with_context(|| format!("Invalid nar file from {url}"))?;
+
+    let offset = prefix.len() as u64;
+    let body = resuming(
+        url.clone(),
+        body.boxed(),
+        offset,
+        config.nar_resume_attempts,
+    );
+
+    Ok((
+        url,
+        0,
+        stream::once(async { Ok(bytes::Bytes::from(prefix)) })
+            .chain(body)
+            .boxed(),
+    ))
+}
+
+// Picks an interrupted download back up with a Range request from the last byte yielded, so the
+// caller only ever sees one continuous body. An upstream without range support is downloaded again
+// from the start, skipping over what was already yielded
+fn resuming(
+    url: url::Url,
+    body: futures::stream::BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+    offset: u64,
+    max_attempts: usize,
+) -> impl futures::Stream<Item = anyhow::Result<bytes::Bytes>> {
+    struct State {
+        url: url::Url,
+        body: futures::stream::BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+        offset: u64,
+        skip: u64,
+        num_attempts: usize,
+    }
+
+    // resumes may be polled outside of the scope the download was started in
+    let timeout = transport::current_timeout();
+
+    let state = State {
+        url,
+        body,
+        offset,
+        skip: 0,
+        num_attempts: 0,
+    };
+
+    stream::unfold(Some(state), move |state| async move {
+        let mut state = state?;
+
+        loop {
+            match state.body.next().await {
+                Some(Ok(mut chunk)) => {
+                    if state.skip > 0 {
+                        let num_skipped = state.skip.min(chunk.len() as u64);
+                        state.skip -= num_skipped;
+                        chunk = chunk.slice(num_skipped as usize..);
+
+                        if chunk.is_empty() {
+                            continue;
+                        }
+                    }
+
+                    state.offset += chunk.len() as u64;
+                    state.num_attempts = 0;
+                    return Some((Ok(chunk), Some(state)));
+                }
+                None if state.skip > 0 => {
+                    let e = anyhow::anyhow!("Nar file from {} ended early", state.url);
+                    return Some((Err(e), None));
+                }
+                None => return None,
+                Some(Err(e)) if state.num_attempts >= max_attempts => {
+                    let e = e.context(format!("Failed to download nar file from {}", state.url));
+                    return Some((Err(e), None));
+                }
+                Some(Err(e)) => {
+                    state.num_attempts += 1;
+
+                    tracing::warn!(
+                        "Download of {} interrupted after {} bytes, resuming ({}/{max_attempts}): {e:#}",
+                        state.url,
+                        state.offset,
+                        state.num_attempts
+                    );
+
+                    let resume = transport::get_stream_from(&state.url, state.offset);
+                    let res = match timeout {
+                        Some(timeout) => transport::with_timeout(timeout, resume).await,
+                        None => resume.await,
+                    };
+                    let res = match res {
+                        Ok(res) => res,
+                        Err(e) => {
+                            let e =
+                                e.context(format!("Failed to resume nar file from {}", state.url));
+                            return Some((Err(e), None));
+                        }
+                    };
+
+                    if res.status == reqwest::StatusCode::PARTIAL_CONTENT {
+                        state.skip = 0;
+                    } else {
+                        tracing::warn!(
+                            "{} does not support ranges, downloading it again from the start",
+                            state.url
+                        );
+                        state.skip = state.offset;
+                    }
+
+                    state.body = res.body;
+                }
+            }
+        }
+    })
+}
+
+// Passed through unchecked from the first upstream that has it, for proxying without caching
+#[tracing::instrument(skip(config))]
+pub async fn request_raw_nar_file(
+    config: &config::Config,
+    nar_file: &str,
+) -> Option<(
+    futures::stream::BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+    nix::Upstream,
+)> {
+    for upstream in &config.upstreams {
+        let upstream: nix::Upstream = upstream.clone().into();
+
+        let res = async {
+            let url = upstream.url().join(&format!("nar/{nar_file}"))?;
+            transport::get_stream(&url).await
+        }
+        .await;
+
+        match res {
+            Ok(res) => return Some((res.body, upstream)),
+            Err(e) => tracing::debug!("No {nar_file} from {}: {e:#}", upstream.url()),
+        }
+    }
+
+    None
+}
+
+// Bypasses parsing and caching, for comparing what an upstream serves with what is cached
+#[tracing::instrument]
+pub async fn request_raw_nar_info(
+    upstream: &nix::Upstream,
+    hash: &nix::Hash,
+) -> anyhow::Result<(reqwest::StatusCode, String)> {
+    let url = upstream
+        .url()
+        .join(&format!("{}.narinfo", hash.string))
+        .with_context(|| {
+            format!(
+                "Failed to build narinfo url with {} and {}",
+                upstream.url(),
+                hash.string
+            )
+        })?;
+
+    let res = transport::get_unchecked(&url)
+        .await
+        .with_context(|| format!("Failed to request {}.narinfo from {url}", hash.string))?;
+
+    Ok((res.status, String::from_utf8_lossy(&res.data).into_owned()))
+}
+
+// Decompresses the body as it arrives, so neither the whole compressed body nor the whole
+// decompressed text is held in memory at once
+async fn decode_xz_lines(
+    res: transport::StreamResponse,
+    max_size: u64,
+    on_line: &mut impl FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    use xz2::stream::{Action, Status, Stream};
+
+    let transport::StreamResponse {
+        mut body,
+        content_type,
+        ..
+    } = res;
+
+    let prefix = read_prefix(&mut body, XZ_MAGIC.len()).await?;
+    if prefix.is_empty() {
+        anyhow::bail!("Body is empty");
+    }
+    check_compression_magic(&prefix, &nix::CompressionType::Xz, content_type.as_deref())?;
+
+    let mut body = stream::iter([Ok(bytes::Bytes::from(prefix))]).chain(body);
+
+    let mut decoder = Stream::new_stream_decoder(u64::MAX, 0)?;
+    let mut decoded = Vec::with_capacity(DECODE_BUF_SIZE);
+    let mut size = 0;
+    let mut is_stream_end = false;
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+
+        size += chunk.len() as u64;
+        if size > max_size {
+            anyhow::bail!("Body exceeds the maximum size of {max_size} bytes");
+        }
+
+        let mut input = &chunk[..];
+        while !input.is_empty() && !is_stream_end {
+            decoded.reserve(DECODE_BUF_SIZE);
+
+            let total_in = decoder.total_in();
+            let status = decoder
+                .process_vec(input, &mut decoded, Action::Run)
+                .with_context(|| format!("Failed to decode xz body after {size} bytes"))?;
+            input = &input[(decoder.total_in() - total_in) as usize..];
+
+            is_stream_end = status == Status::StreamEnd;
+
+            drain_lines(&mut decoded, on_line)?;
+        }
+    }
+
+    while !is_stream_end {
+        decoded.reserve(DECODE_BUF_SIZE);
+
+        match decoder
+            .process_vec(&[], &mut decoded, Action::Finish)
+            .context("Failed to decode xz body")?
+        {
+            Status::StreamEnd => is_stream_end = true,
+            Status::MemNeeded => anyhow::bail!("Xz body is truncated ({size} bytes)"),
+            _ => {}
+        }
+
+        drain_lines(&mut decoded, on_line)?;
+    }
+
+    // the last line may not end with a newline
+    decoded.push(b'\n');
+    drain_lines(&mut decoded, on_line)
+}
+
+// Until at least `len` bytes have arrived, or the whole body if it is shorter, as the first chunk
+// alone may not hold all of a magic or header to check
+async fn read_prefix(
+    body: &mut (impl futures::Stream<Item = anyhow::Result<bytes::Bytes>> + Unpin),
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut prefix = Vec::new();
+
+    while prefix.len() < len {
+        match body.try_next().await? {
+            Some(chunk) => prefix.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+
+    Ok(prefix)
+}
+
+fn drain_lines(
+    decoded: &mut Vec<u8>,
+    on_line: &mut impl FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if let Some(end) = decoded.iter().rposition(|&b| b == b'\n') {
+        std::str::from_utf8(&decoded[..end])
+            .context("Decoded body is not valid utf-8")?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .try_for_each(&mut *on_line)?;
+
+        decoded.drain(..=end);
+    }
+
+    Ok(())
+}
+
+// Absolute `URL`s are followed, but a remote upstream can not point at local files or sockets
+fn check_nar_url(upstream: &nix::Upstream, url: &url::Url) -> anyhow::Result<()> {
+    let scheme = url.scheme();
+
+    if scheme != upstream.url().scheme() && !matches!(scheme, "http" | "https") {
+        anyhow::bail!(
+            "Refusing nar file url {url} with a different scheme than upstream {}",
+            upstream.url()
+        );
+    }
+
+    Ok(())
+}
+
+fn check_compression_magic(
+    bytes: &[u8],
+    compression: &nix::CompressionType,
+    content_type: Option<&str>,
+) -> anyhow::Result<()> {
+    let magic = match compression {
+        nix::CompressionType::Xz => XZ_MAGIC,
+        nix::CompressionType::Zstd => ZSTD_MAGIC,
+    };
+
+    if !bytes.starts_with(magic) {
+        anyhow::bail!(
+            "Body is not {compression} compressed (content-type: {}, {} bytes): {}",
+            content_type.unwrap_or("unknown"),
+            bytes.len(),
+            body_snippet(bytes)
+        );
+    }
+
+    Ok(())
+}
+
+fn check_nar_header(bytes: &[u8], compression: &nix::CompressionType) -> anyhow::Result<()> {
     use io::Read as _;
 
-    let mut content = String::new();
-    xz2::read::XzDecoder::new(bytes)
-        .read_to_string(&mut content)
-        .context("Failed to decode bytes as ascii string")?;
+    let mut header = [0; NAR_MAGIC.len()];
+
+    match compression {
+        nix::CompressionType::Xz => xz2::read::XzDecoder::new(bytes).read_exact(&mut header),
+        nix::CompressionType::Zstd => zstd::stream::read::Decoder::new(bytes)
+            .and_then(|mut decoder| decoder.read_exact(&mut header)),
+    }
+    .context("Failed to decompress nar header")?;
+
+    if header != NAR_MAGIC {
+        anyhow::bail!(
+            "Decompressed body is not a nar: {:?}",
+            String::from_utf8_lossy(&header)
+        );
+    }
+
+    Ok(())
+}
+
+fn body_snippet(bytes: &[u8]) -> String {
+    let snippet = String::from_utf8_lossy(&bytes[..bytes.len().min(BODY_SNIPPET_LEN)]);
+
+    if bytes.len() > BODY_SNIPPET_LEN {
+        format!("{:?}...", snippet)
+    } else {
+        format!("{:?}", snippet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    const ROOT_HASH: &str = "00000000000000000000000000000000";
+    const NUM_REFERENCES: usize = 16;
+
+    fn reference_hash(i: usize) -> String {
+        format!("{i:0>32}")
+    }
+
+    fn nar_info(hash: &str, references: &[String]) -> String {
+        let file_hash = "0".repeat(52);
+
+        format!(
+            "\
+StorePath: /nix/store/{hash}-test
+URL: nar/{file_hash}.nar.xz
+Compression: xz
+FileHash: sha256:{file_hash}
+FileSize: 1
+NarHash: sha256:{file_hash}
+NarSize: 1
+References: {}
+",
+            references
+                .iter()
+                .map(|reference| format!("{reference}-test"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+
+    fn stream_response(
+        chunks: Vec<Vec<u8>>,
+        content_type: Option<&str>,
+    ) -> transport::StreamResponse {
+        transport::StreamResponse {
+            status: reqwest::StatusCode::OK,
+            body: stream::iter(
+                chunks
+                    .into_iter()
+                    .map(|chunk| Ok(bytes::Bytes::from(chunk))),
+            )
+            .boxed(),
+            content_type: content_type.map(str::to_owned),
+        }
+    }
+
+    async fn decode_lines(res: transport::StreamResponse) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        decode_xz_lines(res, u64::MAX, &mut |line| {
+            lines.push(line.to_owned());
+            Ok(())
+        })
+        .await?;
+
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn decode_xz_lines_rejects_non_xz_body() {
+        let html = b"<html><body>502 Bad Gateway</body></html>".to_vec();
+
+        let e = decode_lines(stream_response(vec![html], Some("text/html")))
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(e.contains("not xz compressed"), "{e}");
+        assert!(e.contains("content-type: text/html"), "{e}");
+        assert!(e.contains("502 Bad Gateway"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn decode_xz_lines_checks_magic_across_chunks() {
+        use std::io::Read as _;
+
+        let text = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-a\n/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-b\n";
+        let mut xz = Vec::new();
+        xz2::read::XzEncoder::new(text.as_bytes(), 6)
+            .read_to_end(&mut xz)
+            .unwrap();
+
+        // shorter than the magic, which only the first few chunks hold between them
+        let chunks = xz.chunks(1).map(<[u8]>::to_vec).collect();
+
+        let lines = decode_lines(stream_response(chunks, None)).await.unwrap();
+
+        assert_eq!(lines, text.lines().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn read_prefix_spans_chunks() {
+        let mut body = stream::iter(
+            [&b"nix"[..], b"-ar", b"chive", b"-1"]
+                .map(|chunk| Ok(bytes::Bytes::from_static(chunk))),
+        );
+
+        assert_eq!(read_prefix(&mut body, 5).await.unwrap(), b"nix-ar");
+        assert_eq!(read_prefix(&mut body, 64).await.unwrap(), b"chive-1");
+    }
+
+    #[tokio::test]
+    async fn closure_narinfo_fetches_are_bounded() {
+        #[derive(Clone, Default)]
+        struct InFlight {
+            current: Arc<AtomicUsize>,
+            max: Arc<AtomicUsize>,
+        }
+
+        async fn serve_nar_info(
+            axum::extract::Path(file): axum::extract::Path<String>,
+            axum::extract::State(in_flight): axum::extract::State<InFlight>,
+        ) -> String {
+            let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+            in_flight.max.fetch_max(current, Ordering::SeqCst);
+
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            in_flight.current.fetch_sub(1, Ordering::SeqCst);
+
+            let hash = file.trim_end_matches(".narinfo");
+            if hash == ROOT_HASH {
+                nar_info(
+                    hash,
+                    &(1..=NUM_REFERENCES).map(reference_hash).collect::<Vec<_>>(),
+                )
+            } else {
+                nar_info(hash, &[])
+            }
+        }
+
+        let in_flight = InFlight::default();
+        let router = axum::Router::new()
+            .route("/:file", axum::routing::get(serve_nar_info))
+            .with_state(in_flight.clone());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+
+        let config = config::Config {
+            upstreams: [nix::PriorityUpstream::from_url(
+                format!("http://{addr}/").parse().unwrap(),
+            )]
+            .into(),
+            max_concurrent_narinfo_fetches: 4,
+            ..Default::default()
+        };
+        let _ = init(&config);
+
+        // each walk on its own would fetch up to 4 at once as well
+        let hash = nix::Hash::from_hash(ROOT_HASH.to_owned());
+        let closures =
+            futures::future::join_all((0..3).map(|_| request_closure(&config, &hash, 100, 2)))
+                .await;
 
-    Ok(content)
+        for closure in closures {
+            assert_eq!(closure.num_failed, 0);
+            assert_eq!(closure.nar_infos.len(), NUM_REFERENCES + 1);
+        }
+        assert_eq!(in_flight.max.load(Ordering::SeqCst), 4);
+    }
 }
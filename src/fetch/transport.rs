@@ -0,0 +1,402 @@
+use anyhow::Context as _;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt as _, StreamExt as _};
+
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Built from the config at startup, shared by all http(s) requests so connections are pooled
+static HTTP_CLIENT: std::sync::OnceLock<(reqwest::Client, reqwest::header::HeaderValue)> =
+    std::sync::OnceLock::new();
+
+tokio::task_local! {
+    // One-off override set by the admin endpoints, requests are otherwise never timed out. Only
+    // applies within the scope, so it is captured by whatever outlives it, e.g. response bodies
+    static TIMEOUT: std::time::Duration;
+}
+
+pub struct Response {
+    pub status: reqwest::StatusCode,
+    pub data: bytes::Bytes,
+}
+
+pub struct StreamResponse {
+    pub status: reqwest::StatusCode,
+    pub body: BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+    pub content_type: Option<String>,
+}
+
+// Non-success statuses are returned as responses rather than errors. A stream from a non-zero
+// `offset` is a 206 starting there, or a 200 of the whole body if ranges are not supported
+pub trait Transport: Send + Sync {
+    fn get<'a>(&'a self, url: &'a url::Url) -> BoxFuture<'a, anyhow::Result<Response>>;
+
+    fn get_stream<'a>(
+        &'a self,
+        url: &'a url::Url,
+        offset: u64,
+    ) -> BoxFuture<'a, anyhow::Result<StreamResponse>>;
+}
+
+pub fn for_url(url: &url::Url) -> anyhow::Result<&'static dyn Transport> {
+    match url.scheme() {
+        "http" | "https" => Ok(&Http),
+        "file" => Ok(&File),
+        "http+unix" => socket_path(url).map(|_| &Unix as _),
+        scheme => anyhow::bail!("Unsupported url scheme {scheme:?} in {url}"),
+    }
+}
+
+pub fn init(user_agent: &str) -> anyhow::Result<()> {
+    let user_agent = reqwest::header::HeaderValue::from_str(user_agent)
+        .with_context(|| format!("Invalid user agent {user_agent:?}"))?;
+
+    let client = reqwest::Client::builder()
+        .user_agent(user_agent.clone())
+        .build()
+        .context("Failed to build http client")?;
+
+    HTTP_CLIENT
+        .set((client, user_agent))
+        .map_err(|_| anyhow::anyhow!("Http client is already initialised"))
+}
+
+fn http_client() -> anyhow::Result<&'static (reqwest::Client, reqwest::header::HeaderValue)> {
+    HTTP_CLIENT
+        .get()
+        .context("Http client is not initialised yet")
+}
+
+pub async fn get(url: &url::Url) -> anyhow::Result<Response> {
+    let res = get_unchecked(url).await?;
+
+    if !res.status.is_success() {
+        anyhow::bail!("Got status {} from {url}", res.status);
+    }
+
+    Ok(res)
+}
+
+pub async fn with_timeout<F>(timeout: std::time::Duration, f: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    TIMEOUT.scope(timeout, f).await
+}
+
+pub fn current_timeout() -> Option<std::time::Duration> {
+    TIMEOUT.try_with(|timeout| *timeout).ok()
+}
+
+async fn timed<T>(
+    url: &url::Url,
+    f: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match current_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, f)
+            .await
+            .with_context(|| format!("Timed out after {timeout:?} requesting {url}"))?,
+        None => f.await,
+    }
+}
+
+// Times out waiting for each chunk rather than the whole body, so a large but steady download is
+// never cut short while a stalled one still is
+fn timed_body(
+    url: &url::Url,
+    body: BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+) -> BoxStream<'static, anyhow::Result<bytes::Bytes>> {
+    let Some(timeout) = current_timeout() else {
+        return body;
+    };
+    let url = url.clone();
+
+    futures::stream::unfold(Some(body), move |body| {
+        let url = url.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout(timeout, body.next()).await {
+                Ok(Some(chunk)) => Some((chunk, Some(body))),
+                Ok(None) => None,
+                Err(_) => {
+                    let e = anyhow::anyhow!("Timed out after {timeout:?} reading body of {url}");
+                    Some((Err(e), None))
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+pub async fn get_unchecked(url: &url::Url) -> anyhow::Result<Response> {
+    timed(url, for_url(url)?.get(url)).await
+}
+
+pub async fn get_stream(url: &url::Url) -> anyhow::Result<StreamResponse> {
+    get_stream_from(url, 0).await
+}
+
+pub async fn get_stream_from(url: &url::Url, offset: u64) -> anyhow::Result<StreamResponse> {
+    let mut res = timed(url, for_url(url)?.get_stream(url, offset)).await?;
+
+    if !res.status.is_success() {
+        anyhow::bail!("Got status {} from {url}", res.status);
+    }
+
+    res.body = timed_body(url, res.body);
+
+    Ok(res)
+}
+
+fn content_type(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn range_header(offset: u64) -> Option<(reqwest::header::HeaderName, String)> {
+    (offset > 0).then(|| (reqwest::header::RANGE, format!("bytes={offset}-")))
+}
+
+// A partial response must pick up exactly where asked, e.g. `Content-Range: bytes 100-199/200`
+fn check_content_range(
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+    offset: u64,
+) -> anyhow::Result<()> {
+    if status != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Ok(());
+    }
+
+    let content_range = headers
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .context("Partial response without a Content-Range")?;
+
+    let start = content_range
+        .strip_prefix("bytes ")
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, _)| start.parse::<u64>().ok());
+
+    if start != Some(offset) {
+        anyhow::bail!("Requested bytes from {offset}, got Content-Range {content_range:?}");
+    }
+
+    Ok(())
+}
+
+struct Http;
+
+impl Transport for Http {
+    fn get<'a>(&'a self, url: &'a url::Url) -> BoxFuture<'a, anyhow::Result<Response>> {
+        async move {
+            let (client, _) = http_client()?;
+            let res = client.get(url.clone()).send().await?;
+
+            Ok(Response {
+                status: res.status(),
+                data: res.bytes().await?,
+            })
+        }
+        .boxed()
+    }
+
+    fn get_stream<'a>(
+        &'a self,
+        url: &'a url::Url,
+        offset: u64,
+    ) -> BoxFuture<'a, anyhow::Result<StreamResponse>> {
+        async move {
+            let (client, _) = http_client()?;
+            let mut req = client.get(url.clone());
+            if let Some((name, value)) = range_header(offset) {
+                req = req.header(name, value);
+            }
+            let res = req.send().await?;
+
+            let status = res.status();
+            check_content_range(status, res.headers(), offset)?;
+            let content_type = content_type(res.headers());
+
+            // stops after the first error
+            let body = futures::stream::unfold(Some(res), |res| async {
+                let mut res = res?;
+                match res.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk), Some(res))),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e.into()), None)),
+                }
+            })
+            .boxed();
+
+            Ok(StreamResponse {
+                status,
+                body,
+                content_type,
+            })
+        }
+        .boxed()
+    }
+}
+
+struct File;
+
+impl Transport for File {
+    fn get<'a>(&'a self, url: &'a url::Url) -> BoxFuture<'a, anyhow::Result<Response>> {
+        async move {
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow::anyhow!("Invalid file url {url}"))?;
+
+            let (status, data) = match tokio::fs::read(&path).await {
+                Ok(data) => (reqwest::StatusCode::OK, data),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    (reqwest::StatusCode::NOT_FOUND, Vec::new())
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            };
+
+            Ok(Response {
+                status,
+                data: data.into(),
+            })
+        }
+        .boxed()
+    }
+
+    fn get_stream<'a>(
+        &'a self,
+        url: &'a url::Url,
+        offset: u64,
+    ) -> BoxFuture<'a, anyhow::Result<StreamResponse>> {
+        use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+
+        async move {
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow::anyhow!("Invalid file url {url}"))?;
+
+            let mut file = match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Ok(StreamResponse {
+                        status: reqwest::StatusCode::NOT_FOUND,
+                        body: futures::stream::empty().boxed(),
+                        content_type: None,
+                    })
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to open {}", path.display()))
+                }
+            };
+
+            if offset > 0 {
+                file.seek(std::io::SeekFrom::Start(offset))
+                    .await
+                    .with_context(|| format!("Failed to seek in {}", path.display()))?;
+            }
+
+            let body = futures::stream::unfold(Some(file), |file| async {
+                let mut file = file?;
+                let mut buf = vec![0; FILE_CHUNK_SIZE];
+                match file.read(&mut buf).await {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(buf.into()), Some(file)))
+                    }
+                    Err(e) => Some((Err(e.into()), None)),
+                }
+            })
+            .boxed();
+
+            Ok(StreamResponse {
+                status: if offset > 0 {
+                    reqwest::StatusCode::PARTIAL_CONTENT
+                } else {
+                    reqwest::StatusCode::OK
+                },
+                body,
+                content_type: None,
+            })
+        }
+        .boxed()
+    }
+}
+
+// `http+unix://<percent-encoded socket path>/...`, e.g. `http+unix://%2Frun%2Fnix-cache.sock/`
+fn socket_path(url: &url::Url) -> anyhow::Result<std::path::PathBuf> {
+    let host = url
+        .host_str()
+        .filter(|host| !host.is_empty())
+        .with_context(|| format!("No socket path in {url}"))?;
+
+    let path = percent_encoding::percent_decode_str(host)
+        .decode_utf8()
+        .with_context(|| format!("Invalid socket path in {url}"))?;
+
+    Ok(path.into_owned().into())
+}
+
+struct Unix;
+
+impl Unix {
+    async fn request(url: &url::Url, offset: u64) -> anyhow::Result<hyper::Response<hyper::Body>> {
+        let socket = socket_path(url)?;
+        let (_, user_agent) = http_client()?;
+
+        let stream = tokio::net::UnixStream::connect(&socket)
+            .await
+            .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                tracing::warn!("Connection to {} failed: {e}", socket.display());
+            }
+        });
+
+        let mut req =
+            hyper::Request::get(&url[url::Position::BeforePath..url::Position::AfterQuery])
+                .header(hyper::header::HOST, "localhost")
+                .header(hyper::header::USER_AGENT, user_agent);
+        if let Some((name, value)) = range_header(offset) {
+            req = req.header(name, value);
+        }
+
+        Ok(sender.send_request(req.body(hyper::Body::empty())?).await?)
+    }
+}
+
+impl Transport for Unix {
+    fn get<'a>(&'a self, url: &'a url::Url) -> BoxFuture<'a, anyhow::Result<Response>> {
+        async move {
+            let res = Self::request(url, 0).await?;
+
+            Ok(Response {
+                status: res.status(),
+                data: hyper::body::to_bytes(res.into_body()).await?,
+            })
+        }
+        .boxed()
+    }
+
+    fn get_stream<'a>(
+        &'a self,
+        url: &'a url::Url,
+        offset: u64,
+    ) -> BoxFuture<'a, anyhow::Result<StreamResponse>> {
+        async move {
+            let res = Self::request(url, offset).await?;
+            check_content_range(res.status(), res.headers(), offset)?;
+
+            Ok(StreamResponse {
+                status: res.status(),
+                content_type: content_type(res.headers()),
+                body: res.into_body().map(|chunk| Ok(chunk?)).boxed(),
+            })
+        }
+        .boxed()
+    }
+}
@@ -1,11 +1,27 @@
+mod access_log;
 mod admin;
 mod api;
-
-use std::fmt;
+mod proxy;
+pub mod stats;
+pub mod statsd;
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
 
 use anyhow::Context as _;
 
-use crate::app;
+use crate::{app, config, nix};
+
+const SERVER_NAME: &str = concat!("nicacher/", env!("CARGO_PKG_VERSION"));
+const CAPABILITIES_HEADER: &str = "x-nicacher-capabilities";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+pub const REQUEST_ID_PLACEHOLDER: &str = "{request_id}";
 
 #[derive(Debug)]
 pub struct Server {
@@ -13,18 +29,66 @@ pub struct Server {
 }
 
 impl Server {
-    #[tracing::instrument(name = "server_init")]
-    pub fn new() -> Self {
-        use tower_http::trace::TraceLayer;
-
-        let router = api::router().layer(TraceLayer::new_for_http());
-
-        Self { router }
+    #[tracing::instrument(name = "server_init", skip_all)]
+    pub fn new(config: &config::Config) -> anyhow::Result<Self> {
+        use axum::http::{header, header::HeaderName, HeaderValue};
+        use tower_http::{set_header::SetResponseHeaderLayer, trace::TraceLayer};
+
+        let capabilities = HeaderValue::from_str(&capabilities(config))
+            .expect("Capabilities should be a valid header");
+
+        let router = api::router(config)
+            .layer(SetResponseHeaderLayer::overriding(
+                header::SERVER,
+                HeaderValue::from_static(SERVER_NAME),
+            ))
+            .layer(SetResponseHeaderLayer::overriding(
+                HeaderName::from_static(CAPABILITIES_HEADER),
+                capabilities,
+            ))
+            .layer(
+                TraceLayer::new_for_http().make_span_with(|req: &axum::http::Request<_>| {
+                    let request_id = req
+                        .headers()
+                        .get(REQUEST_ID_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+
+                    tracing::info_span!(
+                        "request",
+                        method = %req.method(),
+                        uri = %req.uri(),
+                        request_id,
+                    )
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                ErrorBody {
+                    is_detail_exposed: config.expose_error_detail,
+                    template: config.error_body_template.as_str().into(),
+                },
+                with_request_id,
+            ));
+
+        // outermost, so the logged status is the one sent
+        let router = match access_log::AccessLog::open(config)? {
+            Some(access_log) => router.layer(axum::middleware::from_fn_with_state(
+                access_log,
+                access_log::log_access,
+            )),
+            None => router,
+        };
+
+        Ok(Self { router })
     }
 
     pub async fn run(self, state: app::State) -> anyhow::Result<()> {
         let server = axum::Server::bind(&"0.0.0.0:8080".parse().unwrap())
-            .serve(self.router.with_state(state).into_make_service())
+            .serve(
+                self.router
+                    .with_state(state)
+                    .into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
             .with_graceful_shutdown(shutdown_signal());
 
         tracing::info!("Starting http server");
@@ -37,6 +101,21 @@ impl Server {
     }
 }
 
+fn capabilities(config: &config::Config) -> String {
+    let compressions = nix::CompressionType::SUPPORTED
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    // nar files are only served off the disk, which answers Range requests, outside proxy_only
+    format!(
+        "compression={compressions}; signing={}; recompression=false; range={}",
+        config.secret_key_file.is_some(),
+        !config.proxy_only
+    )
+}
+
 async fn shutdown_signal() {
     use tokio::signal;
 
@@ -65,6 +144,71 @@ async fn shutdown_signal() {
     println!("signal received, starting graceful shutdown");
 }
 
+#[derive(Clone, Debug)]
+struct ErrorBody {
+    is_detail_exposed: bool,
+    template: Arc<str>,
+}
+
+impl ErrorBody {
+    fn render(&self, request_id: &str, detail: &str) -> String {
+        if self.is_detail_exposed {
+            format!(
+                "Failed to handle request due to internal server error (request id {request_id}):\n{detail}"
+            )
+        } else {
+            self.template.replace(REQUEST_ID_PLACEHOLDER, request_id)
+        }
+    }
+}
+
+// Only needs to tell requests apart in the logs, so a counter tagged with the start time will do
+fn next_request_id() -> String {
+    static STARTED: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let started = STARTED.get_or_init(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    });
+
+    format!("{started:x}-{:x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+// Ids set by a reverse proxy in front are kept, so its logs correlate too
+async fn with_request_id<B>(
+    axum::extract::State(error_body): axum::extract::State<ErrorBody>,
+    mut req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    use axum::{http::HeaderValue, response::IntoResponse as _};
+
+    let request_id = match req.headers().get(REQUEST_ID_HEADER) {
+        Some(value) => value.clone(),
+        None => {
+            let value = HeaderValue::from_str(&next_request_id())
+                .expect("Request id should be a valid header");
+            req.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+            value
+        }
+    };
+
+    let mut res = next.run(req).await;
+
+    if let Some(ErrorDetail(detail)) = res.extensions_mut().remove::<ErrorDetail>() {
+        let body = error_body.render(request_id.to_str().unwrap_or_default(), &detail);
+        res = (res.status(), body).into_response();
+    }
+
+    res.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    res
+}
+
+// Left on 500 responses for `with_request_id` to render the body from
+#[derive(Clone, Debug)]
+struct ErrorDetail(String);
+
 type Result<T> = std::result::Result<T, Error>;
 
 struct Error(anyhow::Error);
@@ -85,14 +229,16 @@ impl axum::response::IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         tracing::error!("{:?}", self);
 
-        (
+        let detail = format!("{:?}", self);
+
+        let mut res = (
             axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!(
-                "Failed to handle request due to internal server error:\n{:?}",
-                self
-            ),
+            format!("Failed to handle request due to internal server error:\n{detail}"),
         )
-            .into_response()
+            .into_response();
+        res.extensions_mut().insert(ErrorDetail(detail));
+
+        res
     }
 }
 
@@ -0,0 +1,161 @@
+use std::{
+    io::{self, Write as _},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request},
+    response::Response,
+};
+
+use crate::config;
+
+// Lines are written as each response body finishes, so byte counts are what was actually sent
+#[derive(Clone)]
+pub struct AccessLog {
+    format: config::AccessLogFormat,
+    out: Arc<Mutex<io::LineWriter<Box<dyn io::Write + Send>>>>,
+}
+
+impl AccessLog {
+    pub fn open(config: &config::Config) -> anyhow::Result<Option<Self>> {
+        let Some(path) = &config.access_log else {
+            return Ok(None);
+        };
+
+        let out: Box<dyn io::Write + Send> = if path.as_os_str() == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open access log {path:?}"))?,
+            )
+        };
+
+        Ok(Some(Self {
+            format: config.access_log_format,
+            out: Arc::new(Mutex::new(io::LineWriter::new(out))),
+        }))
+    }
+
+    fn write(&self, line: &str) {
+        if let Err(e) = writeln!(self.out.lock().unwrap(), "{line}") {
+            tracing::warn!("Failed to write access log: {e}");
+        }
+    }
+}
+
+pub async fn log_access<B>(
+    axum::extract::State(access_log): axum::extract::State<AccessLog>,
+    req: Request<B>,
+    next: axum::middleware::Next<B>,
+) -> Response {
+    let client = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or_else(|| "-".to_owned(), |ConnectInfo(addr)| addr.ip().to_string());
+
+    let request = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+    let (referer, user_agent) = match access_log.format {
+        config::AccessLogFormat::Combined => (
+            Some(quoted_header(req.headers(), header::REFERER)),
+            Some(quoted_header(req.headers(), header::USER_AGENT)),
+        ),
+        config::AccessLogFormat::Common => (None, None),
+    };
+
+    let received = chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z");
+    let res = next.run(req).await;
+
+    let mut line = format!(
+        "{client} - - [{received}] {} {}",
+        escape(&request),
+        res.status().as_u16()
+    );
+    let suffix = match (referer, user_agent) {
+        (Some(referer), Some(user_agent)) => format!(" {referer} {user_agent}"),
+        _ => String::new(),
+    };
+
+    res.map(|inner| {
+        axum::body::boxed(LoggedBody {
+            inner,
+            num_bytes: 0,
+            on_drop: Some(Box::new(move |num_bytes| {
+                // "-" rather than 0 when nothing was sent, as in Apache's %b
+                match num_bytes {
+                    0 => line.push_str(" -"),
+                    n => line.push_str(&format!(" {n}")),
+                }
+                line.push_str(&suffix);
+                access_log.write(&line);
+            })),
+        })
+    })
+}
+
+fn quoted_header(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(|| "\"-\"".to_owned(), escape)
+}
+
+fn escape(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+struct LoggedBody {
+    inner: axum::body::BoxBody,
+    num_bytes: u64,
+    on_drop: Option<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.num_bytes += chunk.len() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Also covers downloads the client aborted partway, logging how much of it was sent
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop(self.num_bytes);
+        }
+    }
+}
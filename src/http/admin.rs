@@ -1,30 +1,137 @@
+use std::sync::Arc;
+
 use anyhow::Context as _;
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{header, StatusCode},
     response::IntoResponse,
 };
-use futures::{FutureExt as _, StreamExt as _, TryStreamExt as _};
-use serde::Deserialize;
+use futures::{stream, StreamExt as _, TryStreamExt as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{app, cache, config, fetch, http, jobs, nix, transaction};
 
-use crate::{app, cache, http, jobs, nix, transaction};
+pub(super) fn router(config: &config::Config) -> axum::Router<app::State> {
+    use axum::routing::{get, on, MethodFilter};
+    use tower_http::compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate as _},
+        CompressionLayer,
+    };
 
-pub(super) fn router() -> axum::Router<app::State> {
-    use axum::routing::get;
+    // Actions with side effects must not be triggered by the GETs browsers and crawlers issue,
+    // unless explicitly allowed for older scripts
+    let write = |filter: MethodFilter| {
+        if config.allow_admin_writes_via_get {
+            filter | MethodFilter::GET
+        } else {
+            filter
+        }
+    };
+    let post = write(MethodFilter::POST);
+    let delete = write(MethodFilter::DELETE);
 
     let push_job = axum::Router::new()
-        .route("/cache_nar/:hash", get(push_cache_nar))
-        .route("/purge_nar/:hash", get(push_purge_nar));
+        .route("/cache_nar/:hash", on(post, push_cache_nar))
+        .route("/purge_nar/:hash", on(post, push_purge_nar))
+        .route("/mirror_channel/:channel", on(post, push_mirror_channel))
+        .route("/backfill_sizes", on(post, push_backfill_sizes))
+        .route("/purge_stale", on(post, push_purge_stale))
+        .route("/evict_lru", on(post, push_evict_lru))
+        .route("/refresh_golden", on(post, push_refresh_golden));
 
-    axum::Router::new()
-        .route("/cache_size", get(cache_size))
+    // Gzipped when the client accepts it, streamed through the encoder. Exports that are already
+    // compressed are left alone
+    let listings = axum::Router::new()
         .route("/list_cached", get(list_cached))
+        .route("/store_paths", get(export_store_paths))
+        .route("/nar_index.json", get(nar_index))
+        .route("/entries", get(entries))
         .route("/list_cache_diff", get(list_cache_diff))
+        .route("/diff.json", get(diff_json))
+        .layer(
+            CompressionLayer::new().compress_when(
+                DefaultPredicate::new()
+                    .and(NotForContentType::const_new("application/x-xz"))
+                    .and(NotForContentType::const_new("application/zstd")),
+            ),
+        );
+
+    let router = axum::Router::new()
+        .merge(listings)
+        .route("/cache_size", get(cache_size))
+        .route("/largest", get(largest))
+        .route("/largest.json", get(largest_json))
+        .route("/channel_stats", get(channel_stats))
+        .route("/path_channels/:hash", get(path_channels))
+        .route("/mirror_progress", get(mirror_progress))
+        .route("/jobs", get(list_jobs))
+        .route("/stats", get(stats))
+        .route("/tombstones", get(tombstones))
+        .route("/tombstones/clear", on(post, clear_tombstones))
+        .route("/flush_caches", on(post, flush_caches))
+        .route("/db_info", get(db_info))
+        .route("/checkpoint_wal", on(post, checkpoint_wal))
         .route("/nar_status/:hash", get(nar_status))
+        .route("/reset_status/:hash", on(post, reset_status))
         .route("/nar_entry/:hash", get(nar_entry))
-        .route("/cache_nar/:hash", get(cache_nar))
-        .route("/purge_nar/:hash", get(purge_nar))
-        .nest("/push", push_job)
+        .route("/upstream_narinfo/:hash", get(upstream_narinfo))
+        .route("/closure_size/:hash", get(closure_size))
+        .route("/raw_narinfo/:hash", get(raw_narinfo))
+        .route("/cache_nar/:hash", on(post, cache_nar))
+        .route("/purge_nar/:hash", on(delete, purge_nar))
+        .route("/backfill_sizes", on(post, backfill_sizes))
+        .route("/purge_stale", on(post, purge_stale))
+        .route("/purge_bulk", on(MethodFilter::POST, push_purge_bulk))
+        .route("/evict_lru", on(post, evict_lru))
+        .route(
+            "/purge_only_in_channel/:channel",
+            on(post, purge_only_in_channel),
+        )
+        .route("/retry_failed", on(post, retry_failed))
+        .route("/refresh_golden", on(post, refresh_golden))
+        .nest("/push", push_job);
+
+    match config
+        .admin_token()
+        .expect("admin_token_file should be validated on startup")
+    {
+        Some(token) => router.layer(axum::middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_admin_token,
+        )),
+        None => {
+            tracing::warn!("No admin_token_file set, admin endpoints are unauthenticated");
+            router
+        }
+    }
+}
+
+// Compared in constant time, so the token cannot be guessed a byte at a time
+async fn require_admin_token<B>(
+    State(token): State<Arc<str>>,
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let is_authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok()?.strip_prefix("Bearer "))
+        .is_some_and(|given| {
+            ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_ok()
+        });
+
+    if !is_authorized {
+        tracing::warn!("Rejecting admin request without a valid admin token");
+
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Missing or invalid admin token",
+        )
+            .into_response();
+    }
+
+    next.run(req).await
 }
 
 async fn nar_entry(
@@ -62,10 +169,135 @@ async fn cache_size(
         .await
         .context("Failed to get reported cache size")?;
 
+    // drift between the nar files on disk and the sizes recorded in the db points to orphaned
+    // or missing nar files
+    let drift = nar_disk_size as i64 - reported_size as i64;
+    let drift_percent = if reported_size == 0 {
+        if nar_disk_size == 0 {
+            0.0
+        } else {
+            100.0
+        }
+    } else {
+        drift.unsigned_abs() as f64 / reported_size as f64 * 100.0
+    };
+
+    let drift_note = if drift_percent > config.size_drift_warn_percent {
+        format!(
+            "\nWarning: drift exceeds {}%, consider running an orphan GC / reconciliation",
+            config.size_drift_warn_percent
+        )
+    } else {
+        String::new()
+    };
+
     Ok(format!(
         "\
 Cache disk size: {disk_size} (nar: {nar_disk_size})
-Cache reported size: {reported_size}"
+Cache reported size: {reported_size}
+Drift (nar disk - reported): {drift} ({drift_percent:.2}%){drift_note}"
+    ))
+}
+
+async fn db_info(
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let info = cache::db::get_db_info(cache.db.pool())
+        .await
+        .context("Failed to get cache database info")?;
+
+    // none in proxy_only mode, where the database is kept in memory
+    let db_file_size = match tokio::fs::metadata(cache::db::db_file_path(&config)).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context("Failed to get cache database file size")
+                .into())
+        }
+    };
+
+    let wal_file_size = cache::wal_file_size(&config)
+        .await
+        .context("Failed to get WAL file size")?;
+
+    let last_wal_checkpoint = match cache.last_wal_checkpoint() {
+        Some(checkpoint) => format!(
+            "{}s ago, {} -> {} bytes{}",
+            checkpoint.finished.elapsed().as_secs(),
+            checkpoint.wal_size_before,
+            checkpoint.wal_size_after,
+            if checkpoint.is_busy { " (busy)" } else { "" }
+        ),
+        None => "None".to_owned(),
+    };
+
+    let migration = match info.migration {
+        Some((version, description)) => format!("{version} ({description})"),
+        None => "None".to_owned(),
+    };
+
+    Ok(format!(
+        "\
+Migration version: {migration}
+Database file size: {db_file_size}
+WAL file size: {wal_file_size}
+Last WAL checkpoint: {last_wal_checkpoint}
+Page count: {} (page size: {})
+Cache size: {}",
+        info.page_count, info.page_size, info.cache_size
+    ))
+}
+
+async fn checkpoint_wal(
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let checkpoint = cache::checkpoint_wal(&config, &cache).await?;
+    Ok(format!(
+        "Checkpointed WAL from {} to {} bytes{}",
+        checkpoint.wal_size_before,
+        checkpoint.wal_size_after,
+        if checkpoint.is_busy {
+            ", some frames are still in use"
+        } else {
+            ""
+        }
+    ))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ResetTo {
+    to: cache::db::Status,
+}
+
+// Escape hatch for entries left in a stuck status, e.g. a lingering `Fetching`
+async fn reset_status(
+    Path(hash): Path<nix::Hash>,
+    Query(ResetTo { to }): Query<ResetTo>,
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let mut tx = transaction!(begin: cache)?;
+
+    let Some(status) = cache::db::get_status(&mut tx, &hash).await? else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("{} is not in the cache", hash.string),
+        ));
+    };
+
+    tracing::warn!(
+        "Manually resetting status of {} from {status:?} to {to:?}",
+        hash.string
+    );
+
+    cache::db::set_status(&mut tx, &hash, to).await?;
+
+    transaction!(commit: tx)?;
+
+    Ok((
+        StatusCode::OK,
+        format!("Reset status of {} from {status:?} to {to:?}", hash.string),
     ))
 }
 
@@ -76,13 +308,43 @@ struct IsForce {
     is_force: bool,
 }
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Timeout {
+    timeout_ms: Option<u64>,
+}
+
+const TIMEOUT_MS_RANGE: std::ops::RangeInclusive<u64> = 100..=600_000;
+
+// `timeout_ms` bounds each upstream request, to tell slow upstreams apart from missing paths
 async fn cache_nar(
     Path(hash): Path<nix::Hash>,
     Query(IsForce { is_force }): Query<IsForce>,
+    Query(Timeout { timeout_ms }): Query<Timeout>,
     State(app::State { config, cache, .. }): State<app::State>,
 ) -> http::Result<impl IntoResponse> {
-    let res = jobs::cache_nar(&config, &cache, hash, is_force).await?;
-    Ok(format!("{res:#?}"))
+    let res = match timeout_ms {
+        Some(timeout_ms) if !TIMEOUT_MS_RANGE.contains(&timeout_ms) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "timeout_ms must be between {} and {}",
+                    TIMEOUT_MS_RANGE.start(),
+                    TIMEOUT_MS_RANGE.end()
+                ),
+            ))
+        }
+        Some(timeout_ms) => {
+            fetch::with_timeout(
+                std::time::Duration::from_millis(timeout_ms),
+                jobs::cache_nar(&config, &cache, hash, is_force),
+            )
+            .await?
+        }
+        None => jobs::cache_nar(&config, &cache, hash, is_force).await?,
+    };
+
+    Ok((StatusCode::OK, format!("{res:#?}")))
 }
 
 async fn push_cache_nar(
@@ -94,6 +356,7 @@ async fn push_cache_nar(
         .push_job(jobs::Job::CacheNar {
             hash: hash.clone(),
             is_force,
+            priority: jobs::Priority::High,
         })
         .await
         .with_context(|| format!("Failed to push job for caching {} to queue", hash.string))?;
@@ -129,6 +392,337 @@ async fn push_purge_nar(
     ))
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Concurrency {
+    concurrency: usize,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self { concurrency: 4 }
+    }
+}
+
+async fn backfill_sizes(
+    Query(Concurrency { concurrency }): Query<Concurrency>,
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let num_corrected = jobs::backfill_sizes(&config, &cache, concurrency).await?;
+    Ok(format!("Corrected sizes of {num_corrected} entries"))
+}
+
+async fn push_backfill_sizes(
+    Query(Concurrency { concurrency }): Query<Concurrency>,
+    State(app::State { mut workers, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    workers
+        .push_job(jobs::Job::BackfillSizes { concurrency })
+        .await
+        .context("Failed to push job for backfilling sizes to queue")?;
+
+    Ok("Pushed job for backfilling sizes to queue")
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetBytes {
+    target_bytes: u64,
+}
+
+async fn evict_lru(
+    Query(TargetBytes { target_bytes }): Query<TargetBytes>,
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let (num_evicted, num_bytes_reclaimed) = jobs::evict_lru(&config, &cache, target_bytes).await?;
+    Ok(format!(
+        "Evicted {num_evicted} least recently accessed entries, reclaiming {num_bytes_reclaimed} \
+        bytes"
+    ))
+}
+
+async fn push_evict_lru(
+    Query(TargetBytes { target_bytes }): Query<TargetBytes>,
+    State(app::State { mut workers, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    workers
+        .push_job(jobs::Job::EvictLru { target_bytes })
+        .await
+        .context("Failed to push job for evicting entries to queue")?;
+
+    Ok("Pushed job for evicting entries to queue")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct IsDryRun {
+    #[serde(rename = "dry_run")]
+    is_dry_run: bool,
+}
+
+async fn purge_stale(
+    Query(IsDryRun { is_dry_run }): Query<IsDryRun>,
+    State(app::State {
+        config,
+        cache,
+        mut workers,
+        ..
+    }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let stale = jobs::purge_stale(&config, &cache, &mut workers, is_dry_run).await?;
+
+    if stale.is_empty() {
+        return Ok("No cached paths missing from channels".to_owned());
+    }
+
+    let action = if is_dry_run {
+        "Would purge"
+    } else {
+        "Pushed jobs for purging"
+    };
+
+    Ok(format!(
+        "\
+{action} {} cached paths missing from channels:
+
+{}",
+        stale.len(),
+        stale
+            .iter()
+            .map(nix::StorePath::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+async fn purge_only_in_channel(
+    Path(channel): Path<nix::Channel>,
+    Query(IsDryRun { is_dry_run }): Query<IsDryRun>,
+    State(app::State {
+        config,
+        cache,
+        mut workers,
+        ..
+    }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let only_in_channel =
+        jobs::purge_only_in_channel(&config, &cache, &mut workers, &channel, is_dry_run).await?;
+
+    if only_in_channel.is_empty() {
+        return Ok(format!("No cached paths only in {channel}"));
+    }
+
+    let action = if is_dry_run {
+        "Would purge"
+    } else {
+        "Pushed jobs for purging"
+    };
+
+    Ok(format!(
+        "\
+{action} {} cached paths only in {channel}:
+
+{}",
+        only_in_channel.len(),
+        only_in_channel
+            .iter()
+            .map(nix::StorePath::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+async fn push_purge_stale(
+    Query(IsDryRun { is_dry_run }): Query<IsDryRun>,
+    State(app::State { mut workers, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    workers
+        .push_job(jobs::Job::PurgeStale { is_dry_run })
+        .await
+        .context("Failed to push job for purging stale paths to queue")?;
+
+    Ok("Pushed job for purging stale paths to queue")
+}
+
+// Accepts either a JSON array of strings or one hash / store path per line
+async fn push_purge_bulk(
+    Query(IsForce { is_force }): Query<IsForce>,
+    State(app::State { mut workers, .. }): State<app::State>,
+    body: String,
+) -> http::Result<impl IntoResponse> {
+    let entries: Vec<String> = if body.trim_start().starts_with('[') {
+        match serde_json::from_str(&body) {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid JSON list of hashes: {e}"),
+                ))
+            }
+        }
+    } else {
+        body.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+
+    let mut num_accepted = 0;
+    let mut errors = Vec::new();
+
+    for entry in entries {
+        let hash = match nix::parse_hash_or_store_path(&entry) {
+            Ok(hash) => hash,
+            Err(e) => {
+                errors.push(format!("{entry:?}: {e}"));
+                continue;
+            }
+        };
+
+        let res = workers
+            .push_job(jobs::Job::PurgeNar {
+                hash: hash.clone(),
+                is_force,
+            })
+            .await;
+
+        match res {
+            Ok(()) => num_accepted += 1,
+            // reported like malformed entries, so the caller knows which ones to retry
+            Err(e) if e.is::<jobs::QueueFull>() => errors.push(format!("{entry:?}: {e}")),
+            Err(e) => {
+                return Err(e
+                    .context(format!(
+                        "Failed to push job for purging {} to queue",
+                        hash.string
+                    ))
+                    .into())
+            }
+        }
+    }
+
+    let mut res = format!(
+        "Pushed {num_accepted} purge jobs to queue, rejected {}",
+        errors.len()
+    );
+    for error in errors {
+        res.push_str(&format!("\n{error}"));
+    }
+
+    Ok((StatusCode::OK, res))
+}
+
+async fn refresh_golden(
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    if config.golden_paths.is_none() {
+        return Ok("No golden paths configured".to_owned());
+    }
+
+    let outcomes = jobs::refresh_golden(&config, &cache).await?;
+    let num_failed = outcomes.iter().filter(|(_, res)| res.is_err()).count();
+
+    Ok(format!(
+        "Refreshed {} golden paths, {num_failed} failed\n\n{}",
+        outcomes.len(),
+        outcomes
+            .iter()
+            .map(|(entry, res)| match res {
+                Ok(res) => format!("{entry}: {res:?}"),
+                Err(e) => format!("{entry}: failed: {e:#}"),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+async fn push_refresh_golden(
+    State(app::State { mut workers, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    workers
+        .push_job(jobs::Job::RefreshGolden)
+        .await
+        .context("Failed to push job for refreshing golden paths to queue")?;
+
+    Ok("Pushed job for refreshing golden paths to queue")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RetryLimit {
+    limit: Option<usize>,
+}
+
+// Forces a refetch of every `NotAvailable` entry, e.g. after an upstream outage. Entries still
+// within `miss_grace_period_secs` are skipped, and the queue is never pushed past
+// `queue_high_water_mark`
+async fn retry_failed(
+    Query(RetryLimit { limit }): Query<RetryLimit>,
+    State(app::State {
+        config,
+        cache,
+        mut workers,
+        ..
+    }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let hashes = cache::db::get_entries(
+        cache.db.pool(),
+        cache::db::EntryFilter {
+            status: Some(cache::db::Status::NotAvailable),
+            limit: u32::MAX,
+            ..Default::default()
+        },
+    )
+    .map_ok(|entry| nix::Hash::from_hash(entry.hash))
+    .try_collect::<Vec<_>>()
+    .await
+    .context("Failed to get entries not available")?;
+
+    let room = match config.queue_high_water_mark {
+        Some(high_water_mark) => {
+            let queue_depth = workers
+                .queue_depth()
+                .await
+                .context("Failed to get job queue depth")?;
+            (high_water_mark - queue_depth).max(0) as usize
+        }
+        None => usize::MAX,
+    };
+    let cap = limit.unwrap_or(usize::MAX).min(room);
+
+    let mut num_pushed = 0;
+    let mut num_recent = 0;
+
+    for hash in &hashes {
+        if num_pushed == cap {
+            break;
+        }
+
+        if !cache.try_record_miss_attempt(&config, hash) {
+            num_recent += 1;
+            continue;
+        }
+
+        workers
+            .push_job(jobs::Job::CacheNar {
+                hash: hash.clone(),
+                is_force: true,
+                priority: jobs::Priority::Low,
+            })
+            .await
+            .with_context(|| format!("Failed to push job for caching {} to queue", hash.string))?;
+
+        num_pushed += 1;
+    }
+
+    Ok(format!(
+        "Pushed {num_pushed} of {} not available entries to queue ({num_recent} recently \
+         attempted, {} left over the cap)",
+        hashes.len(),
+        hashes.len() - num_pushed - num_recent
+    ))
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 struct ListLimit {
@@ -141,6 +735,60 @@ impl Default for ListLimit {
     }
 }
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Export {
+    // by extension, as in nar file names, e.g. `zst`
+    #[serde(deserialize_with = "deserialize_export_compression")]
+    compress: Option<nix::CompressionType>,
+}
+
+fn deserialize_export_compression<'de, D>(
+    deserializer: D,
+) -> Result<Option<nix::CompressionType>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match String::deserialize(deserializer)?.as_str() {
+        "none" => Ok(None),
+        extension => nix::CompressionType::from_extension(extension)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+// Cached store paths, one per line like a channel's `store-paths` file
+async fn export_store_paths(
+    Query(Export { compress }): Query<Export>,
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    use async_compression::{tokio::bufread, Level};
+    use tokio_util::io::{ReaderStream, StreamReader};
+
+    let store_paths = cache::db::stream_store_paths(cache.db.pool())
+        .map_ok(|store_path| bytes::Bytes::from(format!("{store_path}\n")))
+        .map_err(|e| std::io::Error::other(format!("Failed to get cached store paths: {e:#}")));
+
+    let plain = StreamReader::new(store_paths);
+
+    let body = match &compress {
+        None => axum::body::boxed(axum::body::StreamBody::new(ReaderStream::new(plain))),
+        Some(nix::CompressionType::Xz) => axum::body::boxed(axum::body::StreamBody::new(
+            ReaderStream::new(bufread::XzEncoder::new(plain)),
+        )),
+        Some(nix::CompressionType::Zstd) => {
+            axum::body::boxed(axum::body::StreamBody::new(ReaderStream::new(
+                bufread::ZstdEncoder::with_quality(plain, Level::Precise(config.export_zstd_level)),
+            )))
+        }
+    };
+    let content_type = compress
+        .as_ref()
+        .map_or("text/plain", nix::CompressionType::content_type);
+
+    Ok(([(header::CONTENT_TYPE, content_type)], body))
+}
+
 async fn list_cached(
     Query(ListLimit { limit }): Query<ListLimit>,
     State(app::State { cache, .. }): State<app::State>,
@@ -180,11 +828,96 @@ Store paths of cached derivations: (limit: {limit})
     }
 }
 
+async fn entries(
+    Query(filter): Query<cache::db::EntryFilter>,
+    State(app::State { cache, .. }): State<app::State>,
+) -> impl IntoResponse {
+    let entries = cache::db::get_entries(cache.db.pool(), filter)
+        .enumerate()
+        .map(|(i, entry)| {
+            let json = serde_json::to_string::<cache::db::EntrySummary>(&entry?)?;
+            Ok::<_, anyhow::Error>(if i == 0 { json } else { format!(",{json}") })
+        });
+
+    let body = stream::once(async { Ok("[".to_owned()) })
+        .chain(entries)
+        .chain(stream::once(async { Ok("]".to_owned()) }));
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        axum::body::StreamBody::new(body),
+    )
+}
+
+// The nar file counterpart of `/store_paths`, for replicating the cache with external tools
+async fn nar_index(State(app::State { cache, .. }): State<app::State>) -> impl IntoResponse {
+    let entries = cache::db::get_nar_index(cache.db.pool())
+        .enumerate()
+        .map(|(i, entry)| {
+            let json = serde_json::to_string::<cache::db::NarIndexEntry>(&entry?)?;
+            Ok::<_, anyhow::Error>(if i == 0 { json } else { format!(",{json}") })
+        });
+
+    let body = stream::once(async { Ok("[".to_owned()) })
+        .chain(entries)
+        .chain(stream::once(async { Ok("]".to_owned()) }));
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        axum::body::StreamBody::new(body),
+    )
+}
+
+async fn largest(
+    Query(ListLimit { limit }): Query<ListLimit>,
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let largest = cache::db::get_largest_entries(cache.db.pool(), limit as i64)
+        .await
+        .context("Failed to get largest cached entries")?;
+
+    if largest.is_empty() {
+        return Ok("No (0) derivations cached".to_owned());
+    }
+
+    Ok(format!(
+        "\
+Largest cached derivations: (limit: {limit})
+File size / nar size / compression ratio / store path
+
+{}",
+        largest
+            .iter()
+            .map(|e| {
+                format!(
+                    "{}\t{}\t{}\t{}",
+                    e.file_size,
+                    e.nar_size,
+                    format_ratio(e.compression_ratio),
+                    e.store_path
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+async fn largest_json(
+    Query(ListLimit { limit }): Query<ListLimit>,
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let largest = cache::db::get_largest_entries(cache.db.pool(), limit as i64)
+        .await
+        .context("Failed to get largest cached entries")?;
+
+    Ok(axum::Json(largest))
+}
+
 async fn list_cache_diff(
     Query(ListLimit { limit }): Query<ListLimit>,
     State(app::State { config, cache, .. }): State<app::State>,
 ) -> http::Result<impl IntoResponse> {
-    let diff = cache::missing_from_channel_upstreams(&config, &cache).await?;
+    let diff = cache::missing_from_channel_upstreams(&config, &cache, None).await?;
     let diff_len = diff.len();
 
     if diff_len == 0 {
@@ -205,3 +938,438 @@ Store paths of missing derivations: (limit: {limit})
         ))
     }
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct DiffQuery {
+    channel: Option<nix::Channel>,
+    limit: usize,
+    offset: usize,
+}
+
+impl Default for DiffQuery {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            limit: 1000,
+            offset: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Diff {
+    computed_at: chrono::DateTime<chrono::Utc>,
+    total: usize,
+    offset: usize,
+    store_paths: Vec<String>,
+}
+
+async fn diff_json(
+    Query(DiffQuery {
+        channel,
+        limit,
+        offset,
+    }): Query<DiffQuery>,
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<axum::response::Response> {
+    if let Some(channel) = &channel {
+        if !config
+            .channels
+            .iter()
+            .any(|c| c.to_string() == channel.to_string())
+        {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                format!("Channel {channel} is not configured"),
+            )
+                .into_response());
+        }
+    }
+
+    let diff = cache::missing_from_channel_upstreams(&config, &cache, channel.as_ref()).await?;
+    let computed_at = chrono::Utc::now();
+
+    // sorted so that offsets are stable between requests
+    let mut store_paths = diff
+        .iter()
+        .map(nix::StorePath::to_string)
+        .collect::<Vec<_>>();
+    store_paths.sort_unstable();
+
+    Ok(axum::Json(Diff {
+        computed_at,
+        total: store_paths.len(),
+        offset,
+        store_paths: store_paths.into_iter().skip(offset).take(limit).collect(),
+    })
+    .into_response())
+}
+
+async fn channel_stats(
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let syncs = cache::db::get_channel_syncs(cache.db.pool())
+        .await
+        .context("Failed to get channel sync records")?;
+    let coverage = cache::channel_coverage(&config, &cache)
+        .await
+        .context("Failed to compute channel coverage")?;
+
+    Ok(config
+        .channels
+        .iter()
+        .map(|channel| {
+            let channel = channel.to_string();
+
+            match syncs.iter().find(|sync| sync.channel == channel) {
+                Some(sync) => {
+                    let cached = match coverage.iter().find(|c| c.channel == channel) {
+                        Some(c) if c.num_paths > 0 => format!(
+                            "{}/{} ({:.1}%)",
+                            c.num_cached,
+                            c.num_paths,
+                            c.num_cached as f64 / c.num_paths as f64 * 100.0
+                        ),
+                        _ => "0/0".to_owned(),
+                    };
+
+                    format!(
+                        "\
+Channel: {channel}
+Last synced: {}
+Store paths: {} (new in last sync: {})
+Cached: {cached}
+",
+                        sync.last_synced, sync.num_paths, sync.num_new
+                    )
+                }
+                None => format!("Channel: {channel}\nNever synced\n"),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+async fn push_mirror_channel(
+    Path(channel): Path<nix::Channel>,
+    State(app::State { mut workers, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    workers
+        .push_job(jobs::Job::MirrorChannel {
+            channel: channel.clone(),
+        })
+        .await
+        .with_context(|| format!("Failed to push job for mirroring {channel} to queue"))?;
+
+    Ok(format!("Pushed job for mirroring {channel} to queue"))
+}
+
+async fn mirror_progress(
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let mirrors = cache::db::get_channel_mirrors(cache.db.pool())
+        .await
+        .context("Failed to get channel mirror records")?;
+
+    if mirrors.is_empty() {
+        return Ok("No channel mirrors started".to_owned());
+    }
+
+    Ok(mirrors
+        .iter()
+        .map(|mirror| {
+            let state = match mirror.finished {
+                Some(finished) => format!("finished at {finished}"),
+                None => format!("in progress, last updated {}", mirror.updated),
+            };
+
+            format!(
+                "\
+Channel: {}
+Started: {} ({state})
+Processed: {}/{} (failed: {})
+",
+                mirror.channel,
+                mirror.started,
+                mirror.num_done + mirror.num_failed,
+                mirror.num_paths,
+                mirror.num_failed
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct JobsPage {
+    page: i32,
+}
+
+impl Default for JobsPage {
+    fn default() -> Self {
+        Self { page: 1 }
+    }
+}
+
+async fn list_jobs(
+    Query(JobsPage { page }): Query<JobsPage>,
+    State(app::State { mut workers, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    use apalis_core::request::JobState;
+
+    if page < 1 {
+        return Ok((StatusCode::BAD_REQUEST, "Page starts at 1".to_owned()));
+    }
+
+    let mut sections = Vec::new();
+
+    for (name, state) in [
+        ("Running", JobState::Running),
+        ("Pending", JobState::Pending),
+    ] {
+        let jobs = workers
+            .list_jobs(&state, page)
+            .await
+            .with_context(|| format!("Failed to list {} jobs", name.to_lowercase()))?;
+
+        let lines = if jobs.is_empty() {
+            "None".to_owned()
+        } else {
+            jobs.iter()
+                .map(|req| {
+                    let ctx = req.context();
+                    format!(
+                        "{} {} (run at {}, attempts: {})",
+                        ctx.id(),
+                        req.inner(),
+                        ctx.run_at(),
+                        ctx.attempts()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        sections.push(format!("{name} (page {page}):\n{lines}"));
+    }
+
+    Ok((StatusCode::OK, sections.join("\n\n")))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct UpstreamQuery {
+    upstream: Option<url::Url>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct MaxMembers {
+    max_members: usize,
+}
+
+impl Default for MaxMembers {
+    fn default() -> Self {
+        Self {
+            max_members: 10_000,
+        }
+    }
+}
+
+async fn closure_size(
+    Path(hash): Path<nix::Hash>,
+    Query(MaxMembers { max_members }): Query<MaxMembers>,
+    State(app::State { config, .. }): State<app::State>,
+) -> impl IntoResponse {
+    let closure = fetch::request_closure(&config, &hash, max_members, usize::MAX).await;
+
+    let file_size: usize = closure.nar_infos.iter().map(|info| info.file_size).sum();
+    let nar_size: usize = closure.nar_infos.iter().map(|info| info.nar_size).sum();
+
+    let truncated_note = if closure.is_truncated {
+        format!("\nWarning: stopped at {max_members} members, totals are partial")
+    } else {
+        String::new()
+    };
+
+    format!(
+        "\
+Closure of {}: {} members ({} failed to fetch)
+Total file size: {file_size}
+Total nar size: {nar_size}{truncated_note}",
+        hash.string,
+        closure.nar_infos.len(),
+        closure.num_failed
+    )
+}
+
+async fn path_channels(
+    Path(hash): Path<nix::Hash>,
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let Some(nar_info) = cache::db::get_nar_info(cache.db.pool(), &hash).await? else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("{} is not cached", hash.string),
+        ));
+    };
+
+    let store_path = config.ingest_store_path(nar_info.store_path);
+    let channels = cache::db::get_store_path_channels(cache.db.pool(), &store_path).await?;
+
+    if channels.is_empty() {
+        return Ok((
+            StatusCode::OK,
+            format!("{store_path} is in no channel as of their last sync"),
+        ));
+    }
+
+    Ok((
+        StatusCode::OK,
+        format!(
+            "{store_path} is in {} channels as of their last sync:\n\n{}",
+            channels.len(),
+            channels.join("\n")
+        ),
+    ))
+}
+
+async fn raw_narinfo(
+    Path(hash): Path<nix::Hash>,
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    match cache::db::get_raw_nar_info(cache.db.pool(), &hash).await? {
+        Some(raw) => Ok((StatusCode::OK, raw)),
+        None => Ok((
+            StatusCode::NOT_FOUND,
+            format!("No raw narinfo stored for {}", hash.string),
+        )),
+    }
+}
+
+async fn upstream_narinfo(
+    Path(hash): Path<nix::Hash>,
+    Query(UpstreamQuery { upstream }): Query<UpstreamQuery>,
+    State(app::State { config, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    // trailing slashes are ignored so `?upstream=https://cache.nixos.org` matches too
+    let upstream = match upstream {
+        Some(url) => config.upstreams.iter().find(|upstream| {
+            upstream.url().as_str().trim_end_matches('/') == url.as_str().trim_end_matches('/')
+        }),
+        None => config.upstreams.iter().next(),
+    };
+
+    let upstream: nix::Upstream = match upstream {
+        Some(upstream) => upstream.clone().into(),
+        None => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                "Upstream is not configured".to_owned(),
+            ))
+        }
+    };
+
+    Ok(fetch::request_raw_nar_info(&upstream, &hash).await?)
+}
+
+async fn stats(
+    State(app::State {
+        cache,
+        workers,
+        serve_stats,
+        ..
+    }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let queue_depth = workers
+        .queue_depth()
+        .await
+        .context("Failed to get job queue depth")?;
+
+    let compression_ratio = cache::db::get_compression_ratio(cache.db.pool())
+        .await
+        .context("Failed to get compression ratio")?;
+
+    let queue_limit = match workers.max_queue_size() {
+        Some(max_queue_size) => format!(
+            "{max_queue_size} max ({} rejected, {} dropped)",
+            workers.num_overflow_rejected(),
+            workers.num_overflow_dropped()
+        ),
+        None => "no max".to_owned(),
+    };
+
+    Ok(format!(
+        "\
+narinfo serve latency: {}
+nar file serve latency: {}
+job queue: {queue_depth} pending, {queue_limit}, {}
+narinfo response cache: {}
+compression ratio: {} overall, {} mean per entry",
+        serve_stats.nar_info.summary(),
+        serve_stats.nar_file.summary(),
+        serve_stats.queue,
+        cache.nar_info_responses,
+        format_ratio(compression_ratio.overall),
+        format_ratio(compression_ratio.mean),
+    ))
+}
+
+fn format_ratio(ratio: Option<f64>) -> String {
+    ratio.map_or_else(|| "-".to_owned(), |ratio| format!("{ratio:.2}x"))
+}
+
+async fn tombstones(
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let tombstones = cache::db::get_tombstones(cache.db.pool())
+        .await
+        .context("Failed to get tombstones")?;
+
+    if tombstones.is_empty() {
+        return Ok("No active tombstones".to_owned());
+    }
+
+    Ok(tombstones
+        .iter()
+        .map(|t| format!("{} (purged {}, expires {})", t.hash, t.purged, t.expires))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TombstoneQuery {
+    hash: Option<nix::Hash>,
+}
+
+async fn flush_caches(
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> impl IntoResponse {
+    let flushed = cache
+        .flush_caches(&config)
+        .into_iter()
+        .map(|(name, num_cleared)| match num_cleared {
+            Some(num_cleared) => format!("{name}: cleared {num_cleared} entries"),
+            None => format!("{name}: disabled"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    tracing::info!("Flushed in-memory caches");
+
+    format!("Flushed in-memory caches:\n\n{flushed}")
+}
+
+async fn clear_tombstones(
+    Query(TombstoneQuery { hash }): Query<TombstoneQuery>,
+    State(app::State { cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let num_cleared = cache::db::clear_tombstones(cache.db.pool(), hash.as_ref())
+        .await
+        .context("Failed to clear tombstones")?;
+
+    Ok(format!("Cleared {num_cleared} tombstones"))
+}
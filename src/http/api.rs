@@ -1,8 +1,12 @@
-use crate::{app, cache, http, jobs, nix};
+use crate::{
+    app,
+    cache::{self, responses::NarInfoResponse},
+    config, http, jobs, metrics, nix,
+};
 
 use axum::{
     extract::{Path, State},
-    http::{header, Request, StatusCode},
+    http::{header, HeaderMap, Request, StatusCode},
     response::IntoResponse,
 };
 use serde_with::DeserializeFromStr;
@@ -12,30 +16,94 @@ use tower::ServiceExt as _;
 
 use std::str::FromStr;
 
-pub(super) fn router() -> axum::Router<app::State> {
+pub(super) fn router(config: &config::Config) -> axum::Router<app::State> {
     use axum::routing::get;
 
+    use tower_http::set_header::SetResponseHeaderLayer;
+
+    let extra_headers = config
+        .extra_headers()
+        .expect("extra_headers should be validated on startup");
+
+    let served = if config.proxy_only {
+        axum::Router::new()
+            .route("/:nar_info", get(http::proxy::get_nar_info))
+            .route("/nar/:nar_file", get(http::proxy::get_nar_file))
+    } else {
+        axum::Router::new()
+            .route("/:nar_info", get(get_nar_info))
+            .route("/nar/:nar_file", get(get_nar_file))
+    };
+
+    // never overriding what the handlers set themselves, e.g. `Content-Type`
+    let served = extra_headers
+        .into_iter()
+        .fold(served, |router, (name, value)| {
+            router.layer(SetResponseHeaderLayer::if_not_present(name, value))
+        });
+
     axum::Router::new()
         .route("/", get(index))
         .route("/nix-cache-info", get(nix_cache_info))
-        .route("/:nar_info", get(get_nar_info))
-        .route("/nar/:nar_file", get(get_nar_file))
-        .nest("/admin", http::admin::router())
+        .route("/ready", get(ready))
+        .route("/metrics", get(get_metrics))
+        .merge(served)
+        .nest("/admin", http::admin::router(config))
 }
 
 async fn index() -> impl IntoResponse {
     "Nicacher is up!"
 }
 
-async fn nix_cache_info() -> impl IntoResponse {
-    "\
-StoreDir: /nix/store
-WantMassQuery: 0
-Priority: 30"
+async fn ready(State(app::State { config, cache, .. }): State<app::State>) -> impl IntoResponse {
+    if config.offline_mode {
+        (
+            StatusCode::OK,
+            "Ready (offline mode, serving cached paths only)",
+        )
+    } else if config.proxy_only {
+        (StatusCode::OK, "Ready (proxy only, caching nothing)")
+    } else if !cache.is_db_writable() && cache.probe_db().await.is_err() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Degraded: cache database is full",
+        )
+    } else if cache.is_storage_writable() {
+        (StatusCode::OK, "Ready")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Degraded: local data path is not writable",
+        )
+    }
+}
+
+async fn get_metrics(
+    State(app::State { config, cache, .. }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let body = metrics::render(&config, &cache).await?;
+    Ok(([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body))
+}
+
+// Nix reads `key: value` lines, `WantMassQuery` as 0/1 and `Priority` as an integer
+async fn nix_cache_info(State(app::State { config, .. }): State<app::State>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, nix::NIX_CACHE_INFO_MIME)],
+        format!(
+            "\
+StoreDir: {}
+WantMassQuery: {}
+Priority: {}
+",
+            config.store_dir.display(),
+            u8::from(config.want_mass_query),
+            config.priority
+        ),
+    )
 }
 
 #[derive(Debug, DeserializeFromStr)]
-struct NarInfoPath(nix::Hash);
+pub(super) struct NarInfoPath(pub(super) nix::Hash);
 
 impl FromStr for NarInfoPath {
     type Err = anyhow::Error;
@@ -51,34 +119,109 @@ impl FromStr for NarInfoPath {
 
 async fn get_nar_info(
     Path(NarInfoPath(hash)): Path<NarInfoPath>,
+    headers: HeaderMap,
     State(app::State {
-        cache, mut workers, ..
+        config,
+        cache,
+        mut workers,
+        serve_stats,
+        secret_key,
+        ..
     }): State<app::State>,
 ) -> http::Result<impl IntoResponse> {
+    let _timer = serve_stats.nar_info.start_timer();
+
     tracing::info!("Request for {}.narinfo", hash.string);
 
-    let nar_info = cache::db::get_nar_info(cache.db.pool(), &hash)
-        .await
-        .with_context(|| {
-            format!(
-                "Failed to get {}.narinfo due to internal error",
-                hash.string
-            )
-        })?;
+    let responses = &cache.nar_info_responses;
+    let cached = if config.nar_info_cache_size > 0 {
+        responses.get(&hash.string)
+    } else {
+        None
+    };
+
+    let response = if let Some(response) = cached {
+        tracing::debug!("Found rendered narinfo in response cache");
+        Some(response)
+    } else {
+        let generation = responses.generation();
 
-    if let Some(nar_info) = nar_info {
-        cache::db::set_last_accessed(cache.db.pool(), &hash)
+        let nar_info = cache::db::get_nar_info(cache.db.pool(), &hash)
             .await
             .with_context(|| {
                 format!(
-                    "Failed to set last_accessed time for {}.narinfo due to internal error",
+                    "Failed to get {}.narinfo due to internal error",
                     hash.string
                 )
             })?;
 
+        match nar_info {
+            Some(nar_info) => {
+                let response = NarInfoResponse::new(render_nar_info(
+                    &config,
+                    secret_key.as_deref(),
+                    nar_info,
+                )?);
+                responses.insert(
+                    config.nar_info_cache_size,
+                    hash.string.clone(),
+                    response.clone(),
+                    generation,
+                );
+                Some(response)
+            }
+            None => None,
+        }
+    };
+
+    metrics::record_nar_info(response.is_some());
+
+    if let Some(NarInfoResponse { body, etag }) = response {
+        cache.record_access(&config, &hash).await.with_context(|| {
+            format!(
+                "Failed to set last_accessed time for {}.narinfo due to internal error",
+                hash.string
+            )
+        })?;
+
+        let is_not_modified = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+            });
+
+        if is_not_modified {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+        }
+
         Ok((
             [(header::CONTENT_TYPE, nix::NARINFO_MIME)],
-            nar_info.to_string(),
+            [(header::ETAG, etag)],
+            body.to_string(),
+        )
+            .into_response())
+    } else if config.offline_mode {
+        tracing::info!("Cache miss in offline mode");
+
+        Ok((
+            StatusCode::NOT_FOUND,
+            format!("{}.narinfo unavaliable", hash.string),
+        )
+            .into_response())
+    } else if is_queue_saturated(&config, &workers, &serve_stats).await? {
+        tracing::warn!("Cache miss while job queue is saturated, not pushing job");
+        serve_stats.queue.reject();
+
+        Ok(queue_full_response(&config, &hash))
+    } else if !cache.try_record_miss_attempt(&config, &hash) {
+        tracing::info!("Cache miss within grace period of last attempt, not pushing job");
+
+        Ok((
+            StatusCode::NOT_FOUND,
+            format!("{}.narinfo unavaliable", hash.string),
         )
             .into_response())
     } else {
@@ -87,14 +230,41 @@ async fn get_nar_info(
         let job = jobs::Job::CacheNar {
             hash: hash.clone(),
             is_force: false,
+            priority: jobs::Priority::High,
         };
 
-        workers.push_job(job.clone()).await.with_context(|| {
-            format!(
-                "Failed to request caching of {}.narinfo due to internal error",
-                hash.string
-            )
-        })?;
+        match workers.push_job(job.clone()).await {
+            Err(e) if e.is::<jobs::QueueFull>() => {
+                tracing::warn!("Cache miss not pushed: {e}");
+                serve_stats.queue.reject();
+
+                return Ok(queue_full_response(&config, &hash));
+            }
+            ret => ret.with_context(|| {
+                format!(
+                    "Failed to request caching of {}.narinfo due to internal error",
+                    hash.string
+                )
+            })?,
+        }
+
+        if config.cache_closure_on_miss {
+            match workers
+                .push_job(jobs::Job::CacheClosure { hash: hash.clone() })
+                .await
+            {
+                // the path itself is still cached, so the miss is answered as usual
+                Err(e) if e.is::<jobs::QueueFull>() => {
+                    tracing::warn!("Closure of cache miss not pushed: {e}");
+                }
+                ret => ret.with_context(|| {
+                    format!(
+                        "Failed to request caching of the closure of {}.narinfo due to internal error",
+                        hash.string
+                    )
+                })?,
+            }
+        }
 
         Ok((
             StatusCode::NOT_FOUND,
@@ -104,30 +274,425 @@ async fn get_nar_info(
     }
 }
 
+fn queue_full_response(config: &config::Config, hash: &nix::Hash) -> axum::response::Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(
+            header::RETRY_AFTER,
+            config.queue_retry_after_secs.to_string(),
+        )],
+        format!("{}.narinfo unavaliable, job queue is full", hash.string),
+    )
+        .into_response()
+}
+
+pub(super) fn render_nar_info(
+    config: &config::Config,
+    secret_key: Option<&nix::SecretKey>,
+    mut nar_info: nix::NarInfo,
+) -> anyhow::Result<String> {
+    match (config.resign_policy, secret_key) {
+        (config::ResignPolicy::StripAndResign, Some(secret_key)) => {
+            nar_info.signatures = vec![nix::sign_nar_info(&nar_info, secret_key)];
+        }
+        (config::ResignPolicy::StripAndResign, None) => {
+            anyhow::bail!("resign_policy strip_and_resign is set without a secret_key_file")
+        }
+        (config::ResignPolicy::Keep, Some(secret_key)) => {
+            // one signature per key, should an upstream already have signed with ours
+            nar_info
+                .signatures
+                .retain(|signature| !secret_key.is_signer_of(signature));
+            let signature = nix::sign_nar_info(&nar_info, secret_key);
+            nar_info.signatures.push(signature);
+        }
+        (config::ResignPolicy::Keep, None) => {}
+    }
+
+    if config.require_signature_on_serve && nar_info.signatures.is_empty() {
+        anyhow::bail!(
+            "Refusing to serve unsigned narinfo of {} as require_signature_on_serve is set",
+            nar_info.store_path
+        );
+    }
+
+    if config.normalize_unknown_deriver {
+        nar_info.normalize_unknown_deriver();
+    }
+
+    // only reachable as is from narinfos proxied straight from the upstreams
+    nar_info.relativize_url();
+
+    if let Some(base_url) = config.external_base_url() {
+        nar_info.url = base_url
+            .join(&nar_info.url)
+            .with_context(|| format!("Failed to join {} onto {base_url}", nar_info.url))?
+            .to_string();
+    }
+
+    let mut text = nar_info.to_string();
+    if !config.narinfo_trailing_newline {
+        text.truncate(text.trim_end_matches('\n').len());
+    }
+
+    Ok(text)
+}
+
+async fn is_queue_saturated(
+    config: &config::Config,
+    workers: &jobs::Workers,
+    serve_stats: &http::stats::ServeStats,
+) -> anyhow::Result<bool> {
+    let Some(high_water_mark) = config.queue_high_water_mark else {
+        return Ok(false);
+    };
+
+    let queue_depth = workers
+        .queue_depth()
+        .await
+        .context("Failed to get job queue depth")?;
+
+    let is_saturated = queue_depth >= high_water_mark;
+    serve_stats.queue.set(is_saturated);
+
+    Ok(is_saturated)
+}
+
+// Accepted nar file paths, all resolved by looking up the file hash in the cache db:
+// - `<hash>.nar.<extension>` (`xz`, `zst`), as referenced by the `URL` of served narinfos
+// - `<hash>.nar`, an uncompressed nar file, which is never cached as nars are only fetched
+//   compressed, so it is only ever found in proxy_only mode
+// where `<hash>` is the nix32 file hash, optionally prefixed by its method (`sha256:`), or its
+// base16 (hex) form.
+#[derive(Debug, DeserializeFromStr)]
+pub(super) struct NarFilePath {
+    hash: nix::Hash,
+    compression: Option<nix::CompressionType>,
+}
+
+impl std::fmt::Display for NarFilePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.nar", self.hash.string)?;
+
+        if let Some(compression) = &self.compression {
+            write!(f, ".{}", compression.extension())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for NarFilePath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hash, compression) = match *s.splitn(3, '.').collect::<Vec<&str>>().as_slice() {
+            [hash, "nar"] => (hash, None),
+            [hash, "nar", extension] => {
+                (hash, Some(nix::CompressionType::from_extension(extension)?))
+            }
+
+            _ => anyhow::bail!("Invalid nar file format: {s}"),
+        };
+
+        Ok(Self {
+            hash: hash.parse::<nix::Hash>()?.normalized(),
+            compression,
+        })
+    }
+}
+
 async fn get_nar_file(
-    Path(nar_file): Path<nix::NarFileInfo>,
-    State(app::State { config, cache, .. }): State<app::State>,
+    Path(nar_file_path): Path<NarFilePath>,
+    State(app::State {
+        config,
+        cache,
+        mut workers,
+        serve_stats,
+        ..
+    }): State<app::State>,
 ) -> http::Result<impl IntoResponse> {
-    tracing::info!("Request for {nar_file}");
+    let _timer = serve_stats.nar_file.start_timer();
+
+    tracing::info!("Request for {nar_file_path}");
 
-    let res = (|| async {
-        if cache::db::is_nar_file_cached(cache.db.pool(), &nar_file).await? {
-            let nar_file_path = cache::nar_file_path_from_nar_file(&config, &nar_file);
+    let res = async {
+        let mut nar_file = get_cached_nar_file(&cache, &nar_file_path).await?;
+
+        if nar_file.is_none() && config.redirect_uncached_nar {
+            if let Some((hash, url)) = upstream_nar_url(&cache, &nar_file_path).await? {
+                let is_in_flight = cache::db::get_in_flight_hash_by_file_hash(
+                    cache.db.pool(),
+                    &nar_file_path.hash,
+                )
+                .await?
+                .is_some();
+
+                if !is_in_flight {
+                    let job = jobs::Job::CacheNar {
+                        hash: hash.clone(),
+                        is_force: false,
+                        priority: jobs::Priority::High,
+                    };
+
+                    match workers.push_job(job).await {
+                        // still redirected, the nar file is just not cached this time
+                        Err(e) if e.is::<jobs::QueueFull>() => {
+                            tracing::warn!("Caching of {nar_file_path} not pushed: {e}");
+                        }
+                        ret => ret.with_context(|| {
+                            format!("Failed to push job for caching {}", hash.string)
+                        })?,
+                    }
+                }
+
+                tracing::info!("{nar_file_path} not cached, redirecting to {url}");
+                return Ok(redirect_response(&url));
+            }
+        }
+
+        if nar_file.is_none() && config.nar_coalesce_timeout_secs > 0 {
+            nar_file = wait_for_in_flight(&config, &cache, &nar_file_path).await?;
+        }
+
+        if let Some(nar_file) = nar_file {
+            let file_path = cache::nar_file_path_from_nar_file(&config, &nar_file);
+            // byte-identical nar files are shared by every entry they were cached for
+            let hashes = || cache::db::get_hashes_by_file_hash(cache.db.pool(), &nar_file.hash);
+
+            // looked up before purging, which removes the narinfo it is found by
+            let uncached_response = || async {
+                let upstream_url = if config.redirect_uncached_nar {
+                    upstream_nar_url(&cache, &nar_file_path).await?
+                } else {
+                    None
+                };
+
+                Ok::<_, anyhow::Error>(match upstream_url {
+                    Some((_, url)) => redirect_response(&url),
+                    None => StatusCode::NOT_FOUND.into_response(),
+                })
+            };
+
+            // deleted out-of-band, so the db is reconciled with the disk
+            if let Err(e) = tokio::fs::metadata(&file_path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+
+                tracing::error!("{nar_file_path} is missing from disk, refetching");
+
+                // purged rather than just marked, so its narinfo is no longer served either
+                let response = uncached_response().await?;
+                for hash in hashes().await? {
+                    jobs::purge_nar(&config, &cache, hash.clone(), true).await?;
+                    refetch(&mut workers, hash).await?;
+                }
+
+                return Ok(response);
+            }
+
+            if !cache.revalidate_nar_file(&config, &nar_file).await? {
+                tracing::error!("{nar_file_path} is corrupted, purging and refetching");
+
+                let response = uncached_response().await?;
+                for hash in hashes().await? {
+                    jobs::purge_nar(&config, &cache, hash.clone(), true).await?;
+                    refetch(&mut workers, hash).await?;
+                }
+
+                return Ok(response);
+            }
 
             Ok(tower_http::services::ServeFile::new_with_mime(
-                nar_file_path,
+                file_path,
                 &nix::NAR_FILE_MIME.parse().unwrap(),
             )
             .oneshot(Request::new(()))
             .await?
             .into_response())
         } else {
-            tracing::debug!("{nar_file} not found");
+            tracing::debug!("{nar_file_path} not found");
             Ok::<_, anyhow::Error>(StatusCode::NOT_FOUND.into_response())
         }
-    })()
+    }
     .await
-    .with_context(|| format!("Failed to get {nar_file}"))?;
+    .with_context(|| format!("Failed to get {nar_file_path}"))?;
+
+    // anything not served from the cache, redirects included, is a miss
+    let is_hit = res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED;
+    let num_bytes = res
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    metrics::record_nar_file(is_hit, num_bytes);
 
     Ok(res)
 }
+
+// Where an entry with this nar file fetched it from, as long as clients can follow it
+async fn upstream_nar_url(
+    cache: &cache::Cache,
+    nar_file_path: &NarFilePath,
+) -> anyhow::Result<Option<(nix::Hash, url::Url)>> {
+    let hashes = cache::db::get_hashes_by_file_hash(cache.db.pool(), &nar_file_path.hash).await?;
+
+    for hash in hashes {
+        let Some(nar_info) = cache::db::get_nar_info(cache.db.pool(), &hash).await? else {
+            continue;
+        };
+
+        if nar_file_path.compression.as_ref() != Some(&nar_info.compression) {
+            continue;
+        }
+
+        let Some(url) = cache::db::get_nar_url(cache.db.pool(), &hash).await? else {
+            continue;
+        };
+
+        if matches!(url.scheme(), "http" | "https") {
+            return Ok(Some((hash, url)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn redirect_response(url: &url::Url) -> axum::response::Response {
+    (StatusCode::FOUND, [(header::LOCATION, url.to_string())]).into_response()
+}
+
+async fn get_cached_nar_file(
+    cache: &cache::Cache,
+    nar_file_path: &NarFilePath,
+) -> anyhow::Result<Option<nix::NarFileInfo>> {
+    cache::db::get_cached_nar_file(
+        cache.db.pool(),
+        &nar_file_path.hash,
+        nar_file_path.compression.as_ref(),
+    )
+    .await
+}
+
+// Concurrent requests for a nar file still being fetched all wait on the one fetch, rather than
+// missing until it is done
+async fn wait_for_in_flight(
+    config: &config::Config,
+    cache: &cache::Cache,
+    nar_file_path: &NarFilePath,
+) -> anyhow::Result<Option<nix::NarFileInfo>> {
+    // nar files are only ever fetched compressed
+    if nar_file_path.compression.is_none() {
+        return Ok(None);
+    }
+
+    let Some(hash) =
+        cache::db::get_in_flight_hash_by_file_hash(cache.db.pool(), &nar_file_path.hash).await?
+    else {
+        return Ok(None);
+    };
+
+    // not being fetched by this process, or already done with
+    let Some(notify) = cache.in_flight_waiter(&hash) else {
+        return get_cached_nar_file(cache, nar_file_path).await;
+    };
+    let notified = notify.notified();
+    futures::pin_mut!(notified);
+    notified.as_mut().enable();
+
+    // the fetch may have finished before the waiter was registered
+    if cache::db::get_in_flight_hash_by_file_hash(cache.db.pool(), &nar_file_path.hash)
+        .await?
+        .is_none()
+    {
+        return get_cached_nar_file(cache, nar_file_path).await;
+    }
+
+    tracing::info!("{nar_file_path} is being fetched, waiting for it");
+
+    let timeout = std::time::Duration::from_secs(config.nar_coalesce_timeout_secs);
+    if tokio::time::timeout(timeout, notified).await.is_err() {
+        tracing::info!("Timed out waiting for {nar_file_path}");
+        return Ok(None);
+    }
+
+    get_cached_nar_file(cache, nar_file_path).await
+}
+
+async fn refetch(workers: &mut jobs::Workers, hash: nix::Hash) -> anyhow::Result<()> {
+    workers
+        .push_job(jobs::Job::CacheNar {
+            hash: hash.clone(),
+            is_force: true,
+            priority: jobs::Priority::High,
+        })
+        .await
+        .with_context(|| format!("Failed to push job for refetching {}", hash.string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_HASH: &str = "1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3";
+
+    #[test]
+    fn nar_file_path_without_extension_is_uncompressed() {
+        let nar_file_path = format!("{FILE_HASH}.nar").parse::<NarFilePath>().unwrap();
+
+        assert_eq!(nar_file_path.hash.string, FILE_HASH);
+        assert_eq!(nar_file_path.compression, None);
+        assert_eq!(nar_file_path.to_string(), format!("{FILE_HASH}.nar"));
+    }
+
+    #[test]
+    fn nar_file_path_with_extension() {
+        for (extension, compression) in [
+            ("xz", nix::CompressionType::Xz),
+            ("zst", nix::CompressionType::Zstd),
+        ] {
+            let nar_file_path = format!("{FILE_HASH}.nar.{extension}")
+                .parse::<NarFilePath>()
+                .unwrap();
+
+            assert_eq!(nar_file_path.hash.string, FILE_HASH);
+            assert_eq!(nar_file_path.compression, Some(compression));
+            assert_eq!(
+                nar_file_path.to_string(),
+                format!("{FILE_HASH}.nar.{extension}")
+            );
+        }
+    }
+
+    #[test]
+    fn nar_file_path_with_method_prefix() {
+        let nar_file_path = format!("sha256:{FILE_HASH}.nar.xz")
+            .parse::<NarFilePath>()
+            .unwrap();
+
+        assert_eq!(nar_file_path.hash.string, FILE_HASH);
+        assert_eq!(nar_file_path.to_string(), format!("{FILE_HASH}.nar.xz"));
+    }
+
+    #[test]
+    fn nar_file_path_with_base16_hash() {
+        let nar_file_path = format!("{}.nar.xz", "00".repeat(32))
+            .parse::<NarFilePath>()
+            .unwrap();
+
+        assert_eq!(nar_file_path.hash.string, "0".repeat(52));
+    }
+
+    #[test]
+    fn nar_file_path_rejects_other_names() {
+        for nar_file_path in [
+            format!("{FILE_HASH}.narinfo"),
+            format!("{FILE_HASH}.nar.gz"),
+            format!("{FILE_HASH}.nar.zstd"),
+            FILE_HASH.to_owned(),
+        ] {
+            assert!(nar_file_path.parse::<NarFilePath>().is_err());
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+
+use anyhow::Context as _;
+
+use crate::{app, fetch, http, nix};
+
+use super::api::{render_nar_info, NarFilePath, NarInfoPath};
+
+// Served straight from the upstreams on every request, without touching the cache db or disk
+pub(super) async fn get_nar_info(
+    Path(NarInfoPath(hash)): Path<NarInfoPath>,
+    State(app::State {
+        config,
+        serve_stats,
+        secret_key,
+        ..
+    }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let _timer = serve_stats.nar_info.start_timer();
+
+    tracing::info!("Proxying request for {}.narinfo", hash.string);
+
+    let Some((nar_info, _, upstream)) = fetch::request_nar_info(&config, &hash).await else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("{}.narinfo unavaliable", hash.string),
+        )
+            .into_response());
+    };
+
+    tracing::debug!("Proxying {}.narinfo from {}", hash.string, upstream.url());
+
+    let body = render_nar_info(&config, secret_key.as_deref(), nar_info)
+        .with_context(|| format!("Failed to render {}.narinfo", hash.string))?;
+
+    Ok(([(header::CONTENT_TYPE, nix::NARINFO_MIME)], body).into_response())
+}
+
+// The file name is passed on as requested, as it is the one in the `URL` of the proxied narinfo.
+// It is still parsed, so that only nar files are ever requested from the upstreams
+pub(super) async fn get_nar_file(
+    Path(nar_file): Path<String>,
+    State(app::State {
+        config,
+        serve_stats,
+        ..
+    }): State<app::State>,
+) -> http::Result<impl IntoResponse> {
+    let _timer = serve_stats.nar_file.start_timer();
+
+    tracing::info!("Proxying request for {nar_file}");
+
+    if let Err(e) = nar_file.parse::<NarFilePath>() {
+        tracing::debug!("Not proxying {nar_file}: {e:#}");
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let Some((body, upstream)) = fetch::request_raw_nar_file(&config, &nar_file).await else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    tracing::debug!("Proxying {nar_file} from {}", upstream.url());
+
+    Ok((
+        [(header::CONTENT_TYPE, nix::NAR_FILE_MIME)],
+        axum::body::StreamBody::new(body),
+    )
+        .into_response())
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Number of most recent samples percentiles are computed over
+const WINDOW_SIZE: usize = 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct ServeStats {
+    pub nar_info: Latencies,
+    pub nar_file: Latencies,
+    pub queue: QueueSaturation,
+}
+
+// Whether the job queue was over its high-water mark at the last cache miss
+#[derive(Clone, Debug, Default)]
+pub struct QueueSaturation(Arc<Saturation>);
+
+#[derive(Debug, Default)]
+struct Saturation {
+    is_saturated: AtomicBool,
+    num_rejected: AtomicU64,
+}
+
+impl QueueSaturation {
+    pub fn set(&self, is_saturated: bool) {
+        if self.0.is_saturated.swap(is_saturated, Ordering::Relaxed) != is_saturated {
+            if is_saturated {
+                tracing::warn!("Job queue is over its high-water mark, rejecting cache misses");
+            } else {
+                tracing::info!("Job queue is back under its high-water mark");
+            }
+        }
+    }
+
+    pub fn reject(&self) {
+        self.set(true);
+        self.0.num_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.0.is_saturated.load(Ordering::Relaxed)
+    }
+
+    pub fn num_rejected(&self) -> u64 {
+        self.0.num_rejected.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for QueueSaturation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} cache misses rejected)",
+            if self.is_saturated() {
+                "saturated"
+            } else {
+                "not saturated"
+            },
+            self.num_rejected()
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Latencies(Arc<Mutex<Window>>);
+
+#[derive(Debug, Default)]
+struct Window {
+    samples: VecDeque<Duration>,
+    total: u64,
+}
+
+impl Latencies {
+    pub fn start_timer(&self) -> Timer {
+        Timer {
+            latencies: self.clone(),
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let mut window = self.0.lock().unwrap();
+
+        if window.samples.len() == WINDOW_SIZE {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(elapsed);
+        window.total += 1;
+    }
+
+    // Samples recorded after the first `total` requests, as many of them as are still in the
+    // window, along with the new total
+    pub fn samples_since(&self, total: u64) -> (Vec<Duration>, u64) {
+        let window = self.0.lock().unwrap();
+        let num_new = (window.total.saturating_sub(total) as usize).min(window.samples.len());
+
+        (
+            window
+                .samples
+                .iter()
+                .rev()
+                .take(num_new)
+                .rev()
+                .copied()
+                .collect(),
+            window.total,
+        )
+    }
+
+    pub fn summary(&self) -> Summary {
+        let (mut samples, total) = {
+            let window = self.0.lock().unwrap();
+            (
+                window.samples.iter().copied().collect::<Vec<_>>(),
+                window.total,
+            )
+        };
+
+        samples.sort_unstable();
+
+        let percentile = |p: f64| {
+            let index = ((p * samples.len() as f64).ceil() as usize).max(1) - 1;
+            samples.get(index).copied()
+        };
+
+        Summary {
+            total,
+            num_samples: samples.len(),
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+        }
+    }
+}
+
+// Records the time since creation when dropped, so every return path of a handler is covered
+pub struct Timer {
+    latencies: Latencies,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        tracing::debug!("Served in {elapsed:?}");
+        self.latencies.record(elapsed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Summary {
+    pub total: u64,
+    pub num_samples: usize,
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub p99: Option<Duration>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.p50, self.p95, self.p99) {
+            (Some(p50), Some(p95), Some(p99)) => write!(
+                f,
+                "p50 {p50:?}, p95 {p95:?}, p99 {p99:?} (last {} of {} requests)",
+                self.num_samples, self.total
+            ),
+            _ => write!(f, "no requests yet"),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context as _;
+use tokio::net::UdpSocket;
+
+use crate::{app, config};
+
+// Keeps each packet within a typical ethernet MTU, so none of them are fragmented
+const MAX_PACKET_SIZE: usize = 1432;
+
+// Totals as of the last push, as statsd counters and timers are sent as what is new since. Only
+// advanced once a push succeeds, so nothing is lost while the agent is unreachable
+#[derive(Clone, Debug, Default)]
+struct Pushed {
+    nar_info_requests: u64,
+    nar_file_requests: u64,
+    num_rejected: u64,
+    num_overflow_rejected: u64,
+    num_overflow_dropped: u64,
+    num_hits: u64,
+    num_misses: u64,
+}
+
+pub async fn push_periodically(state: app::State) {
+    let Some(statsd) = state.config.statsd.clone() else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(statsd.interval_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    tracing::info!("Pushing metrics to statsd at {}", statsd.address);
+
+    let mut connected = None;
+    let mut pushed = Pushed::default();
+
+    loop {
+        interval.tick().await;
+
+        // the address is resolved again after failures, in case the agent moved
+        let socket = match connected.take() {
+            Some(socket) => socket,
+            None => match connect(&statsd.address).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    tracing::warn!("Failed to connect to statsd: {e:#}");
+                    continue;
+                }
+            },
+        };
+
+        let mut totals = pushed.clone();
+        let metrics = collect(&state, &statsd, &mut totals).await;
+
+        match send(&socket, &metrics).await {
+            Ok(()) => {
+                pushed = totals;
+                connected = Some(socket);
+            }
+            Err(e) => tracing::warn!("Failed to push metrics to statsd: {e:#}"),
+        }
+    }
+}
+
+async fn connect(address: &str) -> anyhow::Result<UdpSocket> {
+    let addr = tokio::net::lookup_host(address)
+        .await
+        .with_context(|| format!("Failed to resolve {address}"))?
+        .next()
+        .with_context(|| format!("{address} did not resolve to any address"))?;
+
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0; 16], 0).into()
+    };
+
+    let socket = UdpSocket::bind(local)
+        .await
+        .context("Failed to bind UDP socket")?;
+    socket
+        .connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to {addr}"))?;
+
+    tracing::debug!("Connected to statsd at {addr}");
+
+    Ok(socket)
+}
+
+async fn collect(
+    app::State {
+        workers,
+        cache,
+        serve_stats,
+        ..
+    }: &app::State,
+    statsd: &config::StatsdConfig,
+    pushed: &mut Pushed,
+) -> Vec<String> {
+    let name = |metric: &str| {
+        if statsd.prefix.is_empty() {
+            metric.to_owned()
+        } else {
+            format!("{}.{metric}", statsd.prefix)
+        }
+    };
+
+    let mut metrics = Vec::new();
+
+    for (metric, latencies, total) in [
+        (
+            "narinfo",
+            &serve_stats.nar_info,
+            &mut pushed.nar_info_requests,
+        ),
+        (
+            "nar_file",
+            &serve_stats.nar_file,
+            &mut pushed.nar_file_requests,
+        ),
+    ] {
+        let (samples, new_total) = latencies.samples_since(*total);
+
+        metrics.push(format!(
+            "{}:{}|c",
+            name(&format!("{metric}.requests")),
+            new_total - *total
+        ));
+        metrics.extend(samples.into_iter().map(|sample| {
+            format!(
+                "{}:{:.3}|ms",
+                name(&format!("{metric}.serve_time")),
+                sample.as_secs_f64() * 1000.0
+            )
+        }));
+
+        *total = new_total;
+    }
+
+    for (metric, num, total) in [
+        (
+            "queue.rejected",
+            serve_stats.queue.num_rejected(),
+            &mut pushed.num_rejected,
+        ),
+        (
+            "queue.overflow_rejected",
+            workers.num_overflow_rejected(),
+            &mut pushed.num_overflow_rejected,
+        ),
+        (
+            "queue.overflow_dropped",
+            workers.num_overflow_dropped(),
+            &mut pushed.num_overflow_dropped,
+        ),
+        (
+            "narinfo_cache.hits",
+            cache.nar_info_responses.num_hits(),
+            &mut pushed.num_hits,
+        ),
+        (
+            "narinfo_cache.misses",
+            cache.nar_info_responses.num_misses(),
+            &mut pushed.num_misses,
+        ),
+    ] {
+        metrics.push(format!("{}:{}|c", name(metric), num - *total));
+        *total = num;
+    }
+
+    match workers.queue_depth().await {
+        Ok(queue_depth) => {
+            metrics.push(format!("{}:{queue_depth}|g", name("queue.pending")));
+
+            if let Some(max_queue_size) = workers.max_queue_size() {
+                metrics.push(format!(
+                    "{}:{}|g",
+                    name("queue.full"),
+                    u8::from(queue_depth >= max_queue_size)
+                ));
+            }
+        }
+        Err(e) => tracing::warn!("Failed to get job queue depth: {e}"),
+    }
+
+    metrics.push(format!(
+        "{}:{}|g",
+        name("queue.saturated"),
+        u8::from(serve_stats.queue.is_saturated())
+    ));
+    metrics.push(format!(
+        "{}:{}|g",
+        name("narinfo_cache.entries"),
+        cache.nar_info_responses.num_entries()
+    ));
+
+    metrics
+}
+
+// Newline separated, as many metrics per packet as fit
+async fn send(socket: &UdpSocket, metrics: &[String]) -> anyhow::Result<()> {
+    let mut packet = String::with_capacity(MAX_PACKET_SIZE);
+
+    for metric in metrics {
+        if !packet.is_empty() && packet.len() + 1 + metric.len() > MAX_PACKET_SIZE {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(metric);
+    }
+
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+
+    Ok(())
+}
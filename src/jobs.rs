@@ -1,7 +1,16 @@
-use std::{fmt, time::Duration};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use anyhow::Context as _;
 use apalis::prelude::{Job as ApalisJob, *};
+use futures::{stream, StreamExt as _};
 use serde::{Deserialize, Serialize};
 use tracing::Instrument as _;
 
@@ -16,20 +25,43 @@ macro_rules! extract_state {
 #[derive(Clone, Debug)]
 pub struct Workers {
     storage: apalis::sqlite::SqliteStorage<Job>,
+    priority_storage: apalis::sqlite::SqliteStorage<Job>,
+    max_queue_size: Option<i64>,
+    overflow_policy: config::QueueOverflowPolicy,
+    num_overflow_rejected: Arc<AtomicU64>,
+    num_overflow_dropped: Arc<AtomicU64>,
+    // Hashes with a `CacheClosure` job queued or running, so repeated misses walk it only once
+    pending_closures: Arc<Mutex<HashSet<String>>>,
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("Job queue is full, with {0} pending jobs")]
+pub struct QueueFull(pub i64);
+
 impl Workers {
     #[tracing::instrument(name = "workers_init", skip_all)]
-    pub async fn new() -> anyhow::Result<Self> {
-        let storage = apalis::sqlite::SqliteStorage::connect("sqlite::memory:")
-            .await
-            .context("Unable to connect to in-memory sqlite database")?;
-        storage
-            .setup()
-            .await
-            .context("Unable to migrate sqlite database")?;
+    pub async fn new(config: &config::Config) -> anyhow::Result<Self> {
+        async fn new_storage() -> anyhow::Result<apalis::sqlite::SqliteStorage<Job>> {
+            let storage = apalis::sqlite::SqliteStorage::connect("sqlite::memory:")
+                .await
+                .context("Unable to connect to in-memory sqlite database")?;
+            storage
+                .setup()
+                .await
+                .context("Unable to migrate sqlite database")?;
+
+            Ok(storage)
+        }
 
-        Ok(Self { storage })
+        Ok(Self {
+            storage: new_storage().await?,
+            priority_storage: new_storage().await?,
+            max_queue_size: config.max_queue_size,
+            overflow_policy: config.queue_overflow_policy,
+            num_overflow_rejected: Arc::default(),
+            num_overflow_dropped: Arc::default(),
+            pending_closures: Arc::default(),
+        })
     }
 
     pub async fn run(self, state: app::State) -> anyhow::Result<()> {
@@ -50,14 +82,15 @@ impl Workers {
         }
 
         macro_rules! new_cron_worker {
-            ($cron:literal => $job:expr) => {{
+            ($cron:expr => $job:expr) => {{
                 use anyhow::Context as _;
                 use apalis::cron::{CronWorker, Schedule};
                 use std::str::FromStr as _;
                 use tower::ServiceBuilder;
 
                 CronWorker::new(
-                    Schedule::from_str($cron).unwrap(),
+                    Schedule::from_str($cron)
+                        .with_context(|| format!("Invalid cron schedule: {:?}", $cron))?,
                     ServiceBuilder::new()
                         .layer(TraceLayer::new().make_span_with(custom_make_span))
                         .layer(Extension(state.clone()))
@@ -83,14 +116,83 @@ impl Workers {
             }};
         }
 
-        let monitor = Monitor::new().register_with_count(4, |_| {
-            WorkerBuilder::new(self.storage())
-                .layer(TraceLayer::new().make_span_with(custom_make_span))
-                .layer(Extension(state.clone()))
-                .build_fn(dispatch_jobs)
-        });
+        // apalis has no job priorities, so high priority (client-triggered) jobs get their own
+        // queue and workers, which bulk syncs and prefetches on the other queue can never occupy
+        let monitor = Monitor::new()
+            .register_with_count(state.config.sync_concurrency, |_| {
+                WorkerBuilder::new(self.storage())
+                    .layer(TraceLayer::new().make_span_with(custom_make_span))
+                    .layer(Extension(state.clone()))
+                    .build_fn(dispatch_jobs)
+            })
+            .register_with_count(state.config.worker_count, |_| {
+                WorkerBuilder::new(self.priority_storage.clone())
+                    .layer(TraceLayer::new().make_span_with(custom_make_span))
+                    .layer(Extension(state.clone()))
+                    .build_fn(dispatch_jobs)
+            });
         // .register(new_cron_worker!("*/10 * * * * *" => Job::Test));
 
+        let monitor = match &state.config.channel_sync_schedule {
+            Some(_) if state.config.offline_mode => {
+                tracing::info!("Not scheduling channel sync in offline mode");
+                monitor
+            }
+            Some(_) if state.config.proxy_only => {
+                tracing::info!("Not scheduling channel sync in proxy_only mode");
+                monitor
+            }
+            Some(schedule) => {
+                tracing::info!("Scheduling channel sync with {schedule:?}");
+                monitor.register(new_cron_worker!(schedule => Job::SyncChannels))
+            }
+            None => monitor,
+        };
+
+        let monitor = match &state.config.golden_refresh_schedule {
+            Some(_) if state.config.offline_mode => {
+                tracing::info!("Not scheduling golden path refresh in offline mode");
+                monitor
+            }
+            Some(_) if state.config.proxy_only => {
+                tracing::info!("Not scheduling golden path refresh in proxy_only mode");
+                monitor
+            }
+            Some(schedule) => {
+                tracing::info!("Scheduling golden path refresh with {schedule:?}");
+                monitor.register(new_cron_worker!(schedule => Job::RefreshGolden))
+            }
+            None => monitor,
+        };
+
+        let monitor = match state.config.max_cache_size_bytes {
+            Some(_) if state.config.proxy_only => {
+                tracing::info!("Not scheduling garbage collection in proxy_only mode");
+                monitor
+            }
+            Some(max_cache_size_bytes) => {
+                let schedule = &state.config.gc_check_schedule;
+                tracing::info!(
+                    "Scheduling garbage collection with {schedule:?}, for at most \
+                    {max_cache_size_bytes} bytes"
+                );
+                monitor.register(new_cron_worker!(schedule => Job::CollectGarbage))
+            }
+            None => monitor,
+        };
+
+        let monitor = match &state.config.wal_checkpoint_schedule {
+            Some(_) if state.config.proxy_only => {
+                tracing::info!("Not scheduling WAL checkpoints in proxy_only mode");
+                monitor
+            }
+            Some(schedule) => {
+                tracing::info!("Scheduling WAL checkpoints with {schedule:?}");
+                monitor.register(new_cron_worker!(schedule => Job::CheckpointWal))
+            }
+            None => monitor,
+        };
+
         tracing::info!("Starting workers");
 
         monitor.run().await?;
@@ -104,34 +206,314 @@ impl Workers {
         self.storage.clone()
     }
 
-    pub async fn push_job(&mut self, job: Job) -> apalis_core::storage::StorageResult<()> {
-        self.storage.push(job).await
+    // Concurrent pushes check the limit before any of them lands, so may overshoot it by as many
+    pub async fn push_job(&mut self, job: Job) -> anyhow::Result<()> {
+        if let Some(max_queue_size) = self.max_queue_size {
+            let queue_depth = self.queue_depth().await?;
+
+            if queue_depth >= max_queue_size {
+                let is_dropped = match self.overflow_policy {
+                    config::QueueOverflowPolicy::Reject => false,
+                    config::QueueOverflowPolicy::DropOldestLow => self.drop_oldest_low().await?,
+                };
+
+                if !is_dropped {
+                    self.num_overflow_rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(QueueFull(queue_depth).into());
+                }
+            }
+        }
+
+        let closure = match &job {
+            Job::CacheClosure { hash } => Some(hash.clone()),
+            _ => None,
+        };
+
+        if let Some(hash) = &closure {
+            if !self
+                .pending_closures
+                .lock()
+                .unwrap()
+                .insert(hash.string.clone())
+            {
+                tracing::debug!("Closure of {} is already pending, not pushing", hash.string);
+                return Ok(());
+            }
+        }
+
+        let res = match job.priority() {
+            Priority::High => self.priority_storage.push(job).await,
+            Priority::Low => self.storage.push(job).await,
+        };
+
+        if let (Err(_), Some(hash)) = (&res, &closure) {
+            self.finish_closure(hash);
+        }
+
+        res?;
+
+        Ok(())
+    }
+
+    fn finish_closure(&self, hash: &nix::Hash) {
+        self.pending_closures.lock().unwrap().remove(&hash.string);
+    }
+
+    // Killed rather than deleted, as apalis offers no way to, so it shows up like any other
+    async fn drop_oldest_low(&mut self) -> anyhow::Result<bool> {
+        use apalis_core::{job::JobStreamExt as _, request::JobState};
+
+        let num_pending = self.storage.len().await?;
+        if num_pending == 0 {
+            return Ok(false);
+        }
+
+        // listed newest first, 10 to a page
+        let last_page = ((num_pending - 1) / 10 + 1) as i32;
+        let Some(mut oldest) = self
+            .storage
+            .list_jobs(&JobState::Pending, last_page)
+            .await?
+            .pop()
+        else {
+            return Ok(false);
+        };
+
+        oldest.set_status(JobState::Killed);
+        oldest.set_done_at(Some(chrono::Utc::now()));
+        self.storage
+            .update_by_id(oldest.id().to_owned(), &oldest)
+            .await?;
+
+        if let Job::CacheClosure { hash } = oldest.inner() {
+            self.finish_closure(hash);
+        }
+
+        tracing::warn!(
+            "Job queue is full, dropped oldest low priority job {:?}",
+            oldest.inner()
+        );
+        self.num_overflow_dropped.fetch_add(1, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    pub fn max_queue_size(&self) -> Option<i64> {
+        self.max_queue_size
+    }
+
+    pub fn num_overflow_rejected(&self) -> u64 {
+        self.num_overflow_rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn num_overflow_dropped(&self) -> u64 {
+        self.num_overflow_dropped.load(Ordering::Relaxed)
+    }
+
+    // Number of done and pending jobs across both queues
+    pub async fn job_counts(&mut self) -> Result<(i64, i64), JobError> {
+        use apalis_core::{job::JobStreamExt as _, request::JobState};
+
+        let mut num_done = 0;
+        let mut num_pending = 0;
+
+        for storage in [&mut self.priority_storage, &mut self.storage] {
+            let counts = storage.counts().await?;
+            num_done += counts
+                .inner
+                .get(&JobState::Done)
+                .copied()
+                .unwrap_or_default();
+            num_pending += counts
+                .inner
+                .get(&JobState::Pending)
+                .copied()
+                .unwrap_or_default();
+        }
+
+        Ok((num_done, num_pending))
+    }
+
+    // Number of pending jobs across both queues
+    pub async fn queue_depth(&self) -> apalis_core::storage::StorageResult<i64> {
+        Ok(self.priority_storage.len().await? + self.storage.len().await?)
+    }
+
+    // One page of each queue, newest first, 10 to a page
+    pub async fn list_jobs(
+        &mut self,
+        state: &apalis_core::request::JobState,
+        page: i32,
+    ) -> Result<Vec<JobRequest<Job>>, JobError> {
+        use apalis_core::job::JobStreamExt as _;
+
+        let mut jobs = Vec::new();
+
+        for storage in [&mut self.priority_storage, &mut self.storage] {
+            jobs.extend(storage.list_jobs(state, page).await?);
+        }
+
+        Ok(jobs)
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum Priority {
+    High,
+    #[default]
+    Low,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Job {
-    CacheNar { hash: nix::Hash, is_force: bool },
-    PurgeNar { hash: nix::Hash, is_force: bool },
+    CacheNar {
+        hash: nix::Hash,
+        is_force: bool,
+        priority: Priority,
+    },
+    PurgeNar {
+        hash: nix::Hash,
+        is_force: bool,
+    },
+    SyncChannels,
+    MirrorChannel {
+        channel: nix::Channel,
+    },
+    BackfillSizes {
+        concurrency: usize,
+    },
+    CacheClosure {
+        hash: nix::Hash,
+    },
+    PurgeStale {
+        is_dry_run: bool,
+    },
+    EvictLru {
+        target_bytes: u64,
+    },
+    CheckpointWal,
+    CollectGarbage,
+    RefreshGolden,
     Test,
 }
 
+impl Job {
+    pub fn priority(&self) -> Priority {
+        match self {
+            Self::CacheNar { priority, .. } => *priority,
+            // the WAL grows the most during bulk syncs, which it would otherwise wait behind
+            Self::CheckpointWal => Priority::High,
+            // likewise bulk syncs are what fill the disk, and the workers caching them are paused
+            // once it is full
+            Self::CollectGarbage | Self::EvictLru { .. } => Priority::High,
+            _ => Priority::Low,
+        }
+    }
+}
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CacheNar {
+                hash,
+                is_force,
+                priority,
+            } => {
+                write!(f, "cache nar {hash} ({priority:?} priority")?;
+                if *is_force {
+                    write!(f, ", forced")?;
+                }
+                write!(f, ")")
+            }
+            Self::PurgeNar { hash, is_force } => {
+                write!(f, "purge nar {hash}")?;
+                if *is_force {
+                    write!(f, " (forced)")?;
+                }
+                Ok(())
+            }
+            Self::SyncChannels => write!(f, "sync channels"),
+            Self::MirrorChannel { channel } => write!(f, "mirror channel {channel}"),
+            Self::BackfillSizes { concurrency } => {
+                write!(f, "backfill nar sizes ({concurrency} at a time)")
+            }
+            Self::CacheClosure { hash } => write!(f, "cache closure of {hash}"),
+            Self::PurgeStale { is_dry_run } => {
+                write!(f, "purge stale paths")?;
+                if *is_dry_run {
+                    write!(f, " (dry run)")?;
+                }
+                Ok(())
+            }
+            Self::EvictLru { target_bytes } => {
+                write!(f, "evict least recently used down to {target_bytes} bytes")
+            }
+            Self::CheckpointWal => write!(f, "checkpoint WAL"),
+            Self::CollectGarbage => write!(f, "collect garbage"),
+            Self::RefreshGolden => write!(f, "refresh golden paths"),
+            Self::Test => write!(f, "test"),
+        }
+    }
+}
+
 impl ApalisJob for Job {
     const NAME: &'static str = "nicacher::jobs::Job";
 }
 
 async fn dispatch_jobs(job: Job, ctx: JobContext) -> Result<JobResult, JobError> {
-    extract_state!({ config, cache } <- ctx);
+    extract_state!({ config, cache, workers } <- ctx);
 
     match job {
-        Job::CacheNar { hash, is_force } => cache_nar(config, cache, hash, is_force).await,
+        Job::CacheNar { hash, is_force, .. } => cache_nar(config, cache, hash, is_force).await,
         Job::PurgeNar { hash, is_force } => purge_nar(config, cache, hash, is_force).await,
+        Job::SyncChannels => sync_channels(config, cache, &mut workers.clone()).await,
+        Job::MirrorChannel { channel } => mirror_channel(config, cache, channel).await,
+        Job::CacheClosure { hash } => {
+            let ret = cache_closure(config, cache, &mut workers.clone(), hash.clone()).await;
+            workers.finish_closure(&hash);
+            ret
+        }
+        Job::PurgeStale { is_dry_run } => {
+            purge_stale(config, cache, &mut workers.clone(), is_dry_run)
+                .await
+                .map(|_| JobResult::Success)
+        }
+        Job::EvictLru { target_bytes } => evict_lru(config, cache, target_bytes).await.map(
+            |(num_evicted, num_bytes_reclaimed)| {
+                tracing::info!(
+                    "Evicted {num_evicted} least recently accessed entries, reclaiming \
+                        {num_bytes_reclaimed} bytes"
+                );
+                JobResult::Success
+            },
+        ),
+        Job::CollectGarbage => collect_garbage(config, &mut workers.clone()).await,
+        Job::CheckpointWal => cache::checkpoint_wal(config, cache)
+            .await
+            .map(|_| JobResult::Success),
+        Job::RefreshGolden => refresh_golden(config, cache).await.map(|outcomes| {
+            let num_failed = outcomes.iter().filter(|(_, res)| res.is_err()).count();
+            tracing::info!(
+                "Refreshed {} golden paths, {num_failed} failed",
+                outcomes.len()
+            );
+            JobResult::Success
+        }),
+        Job::BackfillSizes { concurrency } => {
+            backfill_sizes(config, cache, concurrency)
+                .await
+                .map(|num_corrected| {
+                    tracing::info!("Corrected sizes of {num_corrected} entries");
+                    JobResult::Success
+                })
+        }
         Job::Test => {
             tracing::info!("Ran test job");
             Ok(JobResult::Success)
         }
     }
     .map_err(|e| {
+        cache.check_db_error(&e);
         tracing::error!("Job failed: {e:#}");
         JobError::Failed(e.into())
     })
@@ -143,16 +525,73 @@ pub async fn cache_nar(
     cache: &cache::Cache,
     hash: nix::Hash,
     is_force: bool,
+) -> anyhow::Result<JobResult> {
+    // paused rather than failed, so the queue drains once space is freed
+    if cache.is_db_writable() || cache.probe_db().await.is_ok() {
+        match cache_nar_with_lock(config, cache, hash, is_force).await {
+            Err(e) if cache::db::is_full_error(&e) => cache.check_db_error(&e),
+            ret => return ret,
+        }
+    }
+
+    tracing::warn!("Cache database is full, rescheduling");
+    Ok(JobResult::Reschedule(Duration::from_secs(
+        config.database_full_retry_secs,
+    )))
+}
+
+async fn cache_nar_with_lock(
+    config: &config::Config,
+    cache: &cache::Cache,
+    hash: nix::Hash,
+    is_force: bool,
+) -> anyhow::Result<JobResult> {
+    // the status check below can race between workers, the lock cannot
+    if !cache::db::try_lock(cache.db.pool(), &hash).await? {
+        tracing::warn!("Locked by other worker, killing");
+        return Ok(JobResult::Kill);
+    }
+
+    cache.start_in_flight(&hash);
+    let ret = cache_nar_locked(config, cache, hash.clone(), is_force).await;
+
+    if let Err(e) = cache::db::unlock(cache.db.pool(), &hash).await {
+        tracing::error!("Failed to release lock on {}: {e:#}", hash.string);
+    }
+    cache.finish_in_flight(&hash);
+
+    ret
+}
+
+async fn cache_nar_locked(
+    config: &config::Config,
+    cache: &cache::Cache,
+    hash: nix::Hash,
+    is_force: bool,
 ) -> anyhow::Result<JobResult> {
     tracing::info!("Caching {} narinfo and corresponding nar file", hash.string);
 
+    if is_force {
+        cache::db::clear_tombstones(cache.db.pool(), Some(&hash)).await?;
+    } else if cache::db::is_tombstoned(cache.db.pool(), &hash).await? {
+        tracing::warn!("Recently purged, killing");
+        return Ok(JobResult::Kill);
+    }
+
+    if !cache.is_storage_writable() {
+        cache
+            .probe_storage(config)
+            .await
+            .context("Unable to cache while local data path is not writable")?;
+    }
+
     let ret = async {
         use cache::db::Status;
 
         let mut tx = transaction!(begin: cache).map_err(Err)?;
 
         match cache::db::get_status(&mut tx, &hash).await.map_err(Err)? {
-            Some(Status::Fetching) => {
+            Some(Status::Fetching | Status::MetadataOnly) => {
                 tracing::warn!("Already fetching by other worker, killing");
                 return Err(Ok(JobResult::Kill));
             }
@@ -190,7 +629,43 @@ pub async fn cache_nar(
         return ret;
     }
 
-    if let Some(derivation) = fetch::request_derivation(config, &hash).await {
+    if config.serve_narinfo_while_fetching {
+        return cache_nar_metadata_first(config, cache, hash, is_force).await;
+    }
+
+    let derivations = fetch::request_derivations(config, &hash);
+    futures::pin_mut!(derivations);
+
+    while let Some(mut derivation) = derivations.next().await {
+        tracing::info!(
+            "Fetched {} from {}",
+            derivation.info,
+            derivation.upstream.url()
+        );
+
+        let nar_url = derivation.nar_file.url.clone();
+
+        // downloaded before the transaction, so the db is not held up for as long as it takes
+        let (file_hash, file_size) = match cache::write_nar_file(config, cache, derivation.nar_file)
+            .await
+        {
+            Ok(written) => written,
+            Err(e)
+                if e.downcast_ref::<cache::FileHashMismatch>().is_some()
+                    && config.on_file_hash_mismatch == config::OnFileHashMismatch::NextUpstream =>
+            {
+                tracing::warn!(
+                    "Skipping {} from {}: {e:#}",
+                    derivation.info,
+                    derivation.upstream.url()
+                );
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        derivation.nar_info.file_hash = file_hash;
+        derivation.nar_info.file_size = file_size;
+
         async {
             let mut tx = transaction!(begin: cache)?;
 
@@ -202,12 +677,16 @@ pub async fn cache_nar(
                 is_force,
             )
             .await?;
+            cache::db::set_nar_url(&mut tx, &hash, &nar_url).await?;
 
-            cache::db::set_status(&mut tx, &hash, cache::db::Status::Available).await?;
+            if config.store_raw_narinfo {
+                cache::db::set_raw_nar_info(&mut tx, &hash, &derivation.raw_nar_info).await?;
+            }
 
-            cache::write_nar_file(config, &derivation.nar_file).await?;
+            cache::db::set_status(&mut tx, &hash, cache::db::Status::Available).await?;
 
             transaction!(commit: tx)?;
+            cache.nar_info_responses.invalidate(&hash);
 
             tracing::info!("Commit success");
 
@@ -215,8 +694,81 @@ pub async fn cache_nar(
         }
         .instrument(tracing::debug_span!("cache_nar_insert"))
         .await?;
-    } else {
-        cache::db::set_status(cache.db.pool(), &hash, cache::db::Status::NotAvailable).await?;
+
+        return Ok(JobResult::Success);
+    }
+
+    cache::db::set_status(cache.db.pool(), &hash, cache::db::Status::NotAvailable).await?;
+
+    Ok(JobResult::Success)
+}
+
+async fn cache_nar_metadata_first(
+    config: &config::Config,
+    cache: &cache::Cache,
+    hash: nix::Hash,
+    is_force: bool,
+) -> anyhow::Result<JobResult> {
+    use cache::db::Status;
+
+    let Some((mut nar_info, raw_nar_info, upstream)) = fetch::request_nar_info(config, &hash).await
+    else {
+        cache::db::set_status(cache.db.pool(), &hash, Status::NotAvailable).await?;
+        return Ok(JobResult::Success);
+    };
+
+    tracing::info!(
+        "Fetched narinfo of {} from {}",
+        nar_info.store_path.derivation_info,
+        upstream.url()
+    );
+
+    async {
+        let mut tx = transaction!(begin: cache)?;
+
+        cache::db::insert_nar_info(&mut tx, &hash, &nar_info, &upstream, is_force).await?;
+        if config.store_raw_narinfo {
+            cache::db::set_raw_nar_info(&mut tx, &hash, &raw_nar_info).await?;
+        }
+        cache::db::set_status(&mut tx, &hash, Status::MetadataOnly).await?;
+
+        transaction!(commit: tx)?;
+        cache.nar_info_responses.invalidate(&hash);
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .instrument(tracing::debug_span!("cache_nar_insert_metadata"))
+    .await?;
+
+    let ret = async {
+        // the narinfo is already being served, so its stated compression is kept
+        let nar_file = fetch::request_nar_file(config, &upstream, &mut nar_info, &[]).await?;
+        // stored as soon as it is known, so uncached requests can be redirected to it meanwhile
+        cache::db::set_nar_url(cache.db.pool(), &hash, &nar_file.url).await?;
+        cache::write_nar_file(config, cache, nar_file).await?;
+
+        let mut tx = transaction!(begin: cache)?;
+        cache::db::set_status(&mut tx, &hash, Status::Available).await?;
+        transaction!(commit: tx)?;
+
+        tracing::info!("Commit success");
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .instrument(tracing::debug_span!("cache_nar_insert_file"))
+    .await;
+
+    if let Err(e) = ret {
+        // the narinfo must not be served without a nar file to back it
+        tracing::warn!("Dropping {}.narinfo as its nar file failed", hash.string);
+
+        let mut tx = transaction!(begin: cache)?;
+        cache::db::purge_nar_info(&mut tx, &hash).await?;
+        cache::db::set_status(&mut tx, &hash, Status::NotAvailable).await?;
+        transaction!(commit: tx)?;
+        cache.nar_info_responses.invalidate(&hash);
+
+        return Err(e);
     }
 
     Ok(JobResult::Success)
@@ -249,11 +801,11 @@ pub async fn purge_nar(
                 tracing::warn!("Already purging by other worker, killing");
                 return Err(Ok(JobResult::Kill));
             }
-            Some(Status::Fetching) if is_force => {
+            Some(Status::Fetching | Status::MetadataOnly) if is_force => {
                 tracing::warn!("Fetching by other worker, rescheduling due to `is_force`");
                 return Err(Ok(JobResult::Reschedule(Duration::from_secs(10))));
             }
-            Some(Status::Fetching) if !is_force => {
+            Some(Status::Fetching | Status::MetadataOnly) if !is_force => {
                 tracing::warn!("Fetching by other worker, killing");
                 return Err(Ok(JobResult::Kill));
             }
@@ -261,10 +813,25 @@ pub async fn purge_nar(
                 tracing::warn!("Cached data not avaliable, killing");
                 return Err(Ok(JobResult::Kill));
             }
-            _ => cache::db::get_nar_file_path(cache.db.pool(), config, &hash)
-                .await
-                .with_context(|| format!("Failed to get {} narinfo from cache db", hash.string))
-                .map_err(Err)?,
+            Some(status) => {
+                let nar_file_path = cache::db::get_nar_file_path(cache.db.pool(), config, &hash)
+                    .await
+                    .with_context(|| format!("Failed to get {} narinfo from cache db", hash.string))
+                    .map_err(Err)?;
+
+                // identical nar files are shared, only the last entry referencing one removes it
+                let num_sharers = cache::db::count_nar_file_sharers(&mut tx, &hash)
+                    .await
+                    .context("Failed to count entries sharing the nar file")
+                    .map_err(Err)?;
+
+                if num_sharers > 0 {
+                    tracing::info!("Keeping nar file shared with {num_sharers} other entries");
+                    (None, status)
+                } else {
+                    (nar_file_path, status)
+                }
+            }
         };
 
         cache::db::set_status(&mut tx, &hash, Status::Purging)
@@ -278,25 +845,762 @@ pub async fn purge_nar(
     .instrument(tracing::debug_span!("purge_nar_init"))
     .await;
 
-    match ret {
-        Ok(Some(path)) => {
-            tracing::debug!("Deleting {}", path.display());
-
-            tokio::fs::remove_file(path)
-                .await
-                .context("Error when deeleting nar file")?;
-        }
+    let (nar_file_path, status) = match ret {
+        Ok(ret) => ret,
         Err(ret) => return ret,
-        _ => {}
     };
 
+    if let Some(path) = nar_file_path {
+        tracing::debug!("Deleting {}", path.display());
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!("{} was already gone", path.display());
+            }
+            // the entry is put back as it was, so that the purge can be retried once the file
+            // can be removed, rather than claiming a path is gone while its file lingers
+            Err(e) => {
+                cache::db::set_status(cache.db.pool(), &hash, status)
+                    .await
+                    .context("Failed to restore cache status after failing to delete nar file")?;
+
+                return Err(anyhow::Error::new(e).context(format!(
+                    "Failed to delete {}, keeping its entry",
+                    path.display()
+                )));
+            }
+        }
+    }
+
     cache::db::purge_nar_info(cache.db.pool(), &hash)
         .await
         .context("Error when deleting narinfo entry from cache db")?;
+    cache.nar_info_responses.invalidate(&hash);
+
+    if let Some(ttl_secs) = config.tombstone_ttl_secs {
+        cache::db::add_tombstone(cache.db.pool(), &hash, ttl_secs)
+            .await
+            .context("Error when adding tombstone")?;
+    }
 
     Ok(JobResult::Success)
 }
 
+#[tracing::instrument(skip_all)]
+pub async fn sync_channels(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+) -> anyhow::Result<JobResult> {
+    for channel in &config.channels {
+        let store_paths = fetch::request_channel_store::<Vec<_>>(config, channel)
+            .await
+            .with_context(|| format!("Failed to request store paths of {channel}"))?;
+
+        let last_synced = cache::db::get_channel_store_paths(cache.db.pool(), channel)
+            .await
+            .with_context(|| format!("Failed to get last synced store paths of {channel}"))?;
+
+        let new_store_paths = store_paths
+            .iter()
+            .filter(|path| !last_synced.contains(&path.to_string()))
+            .collect::<Vec<_>>();
+
+        tracing::info!(
+            "{} new store paths in {channel} since last sync",
+            new_store_paths.len()
+        );
+
+        for store_path in &new_store_paths {
+            let hash = &store_path.derivation_info.hash;
+
+            if cache::db::is_cached_by_hash(cache.db.pool(), hash).await? {
+                continue;
+            }
+
+            match workers
+                .push_job(Job::CacheNar {
+                    hash: hash.clone(),
+                    is_force: false,
+                    priority: Priority::Low,
+                })
+                .await
+            {
+                // not recorded as synced, so the next sync picks up where this one stopped
+                Err(e) if e.is::<QueueFull>() => {
+                    tracing::warn!("{e}, leaving the rest of {channel} to the next sync");
+                    return Ok(JobResult::Success);
+                }
+                ret => {
+                    ret.with_context(|| format!("Failed to push job for caching {}", hash.string))?
+                }
+            }
+        }
+
+        let mut tx = transaction!(begin: cache)?;
+
+        cache::db::set_channel_store_paths(&mut tx, channel, &store_paths).await?;
+        cache::db::set_channel_sync(&mut tx, channel, store_paths.len(), new_store_paths.len())
+            .await?;
+
+        transaction!(commit: tx)?;
+    }
+
+    if config.purge_stale_after_sync {
+        purge_stale(config, cache, workers, false).await?;
+    }
+
+    Ok(JobResult::Success)
+}
+
+// Goes through the usual purge jobs, so tombstones apply just as with manual purges
+#[tracing::instrument(skip(config, cache, workers))]
+pub async fn purge_stale(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+    is_dry_run: bool,
+) -> anyhow::Result<Vec<nix::StorePath>> {
+    let stale = cache::stale_from_channel_upstreams(config, cache)
+        .await
+        .context("Failed to find cached paths no longer in any channel")?;
+
+    if is_dry_run {
+        tracing::info!("{} cached paths no longer in any channel", stale.len());
+        return Ok(stale);
+    }
+
+    tracing::info!(
+        "Purging {} cached paths no longer in any channel",
+        stale.len()
+    );
+
+    push_purge_jobs(workers, &stale).await?;
+
+    Ok(stale)
+}
+
+// Goes by the listings recorded at the last sync of each channel, without refetching them
+#[tracing::instrument(skip(config, cache, workers))]
+pub async fn purge_only_in_channel(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+    channel: &nix::Channel,
+    is_dry_run: bool,
+) -> anyhow::Result<Vec<nix::StorePath>> {
+    let only_in_channel = cache::only_in_channel(config, cache, channel)
+        .await
+        .with_context(|| format!("Failed to find cached paths only in {channel}"))?;
+
+    if is_dry_run {
+        tracing::info!("{} cached paths only in {channel}", only_in_channel.len());
+        return Ok(only_in_channel);
+    }
+
+    tracing::info!(
+        "Purging {} cached paths only in {channel}",
+        only_in_channel.len()
+    );
+
+    push_purge_jobs(workers, &only_in_channel).await?;
+
+    Ok(only_in_channel)
+}
+
+async fn push_purge_jobs(
+    workers: &mut Workers,
+    store_paths: &[nix::StorePath],
+) -> anyhow::Result<()> {
+    for store_path in store_paths {
+        let hash = &store_path.derivation_info.hash;
+
+        workers
+            .push_job(Job::PurgeNar {
+                hash: hash.clone(),
+                is_force: false,
+            })
+            .await
+            .with_context(|| format!("Failed to push job for purging {}", hash.string))?;
+    }
+
+    Ok(())
+}
+
+// Like the revalidation of served nar files, for entries whose nar file turned out corrupted or
+// missing. Returns the number of entries purged
+#[tracing::instrument(skip_all)]
+pub async fn purge_and_refetch(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+    hashes: Vec<nix::Hash>,
+) -> anyhow::Result<usize> {
+    let mut num_purged = 0;
+
+    for hash in hashes {
+        if matches!(
+            purge_nar(config, cache, hash.clone(), true).await?,
+            JobResult::Success
+        ) {
+            num_purged += 1;
+        }
+
+        match workers
+            .push_job(Job::CacheNar {
+                hash: hash.clone(),
+                is_force: true,
+                priority: Priority::Low,
+            })
+            .await
+        {
+            // purged either way, so it is fetched again on the next miss
+            Err(e) if e.is::<QueueFull>() => {
+                tracing::warn!("Refetch of {} not pushed: {e}", hash.string)
+            }
+            ret => {
+                ret.with_context(|| format!("Failed to push job for refetching {}", hash.string))?
+            }
+        }
+    }
+
+    Ok(num_purged)
+}
+
+const LRU_BATCH_SIZE: i64 = 100;
+
+// Purges directly rather than through purge jobs, so the nar disk size can be watched going down.
+// Sizes are only estimated within a batch, as walking the nar directory after every purge is slow
+#[tracing::instrument(skip(config, cache))]
+pub async fn evict_lru(
+    config: &config::Config,
+    cache: &cache::Cache,
+    target_bytes: u64,
+) -> anyhow::Result<(usize, u64)> {
+    // accesses not yet flushed would otherwise look older than they are
+    cache
+        .flush_accessed()
+        .await
+        .context("Failed to flush last_accessed times")?;
+
+    let mut num_evicted = 0;
+    let mut initial_nar_disk_size = None;
+
+    let nar_disk_size = loop {
+        let nar_disk_size = cache::nar_disk_size(config)
+            .await
+            .context("Failed to get nar disk size")?;
+        initial_nar_disk_size.get_or_insert(nar_disk_size);
+
+        if nar_disk_size <= target_bytes {
+            tracing::info!("Nar files take {nar_disk_size} bytes, within {target_bytes}");
+            break nar_disk_size;
+        }
+
+        let candidates = cache::db::get_lru_candidates(cache.db.pool(), LRU_BATCH_SIZE)
+            .await
+            .context("Failed to get least recently accessed entries")?;
+
+        if candidates.is_empty() {
+            tracing::warn!("Nothing left to evict, nar files still take {nar_disk_size} bytes");
+            break nar_disk_size;
+        }
+
+        let mut estimated_size = nar_disk_size;
+
+        for candidate in candidates {
+            if estimated_size <= target_bytes {
+                break;
+            }
+
+            tracing::debug!(
+                "Evicting {}, last accessed {:?}",
+                candidate.hash,
+                candidate.last_accessed
+            );
+
+            let hash = nix::Hash::from_hash(candidate.hash);
+            match purge_nar(config, cache, hash.clone(), false).await? {
+                JobResult::Success => num_evicted += 1,
+                res => tracing::debug!("Not evicted {}: {res:?}", hash.string),
+            }
+
+            estimated_size =
+                estimated_size.saturating_sub(candidate.file_size.unwrap_or(0).max(0) as u64);
+        }
+    };
+
+    Ok((
+        num_evicted,
+        initial_nar_disk_size
+            .unwrap_or(nar_disk_size)
+            .saturating_sub(nar_disk_size),
+    ))
+}
+
+// Only sizes the data path, leaving the purging to an `EvictLru` job. Everything besides the nar
+// files, mostly the cache db, is counted but never evicted
+#[tracing::instrument(skip_all)]
+pub async fn collect_garbage(
+    config: &config::Config,
+    workers: &mut Workers,
+) -> anyhow::Result<JobResult> {
+    let Some(max_cache_size_bytes) = config.max_cache_size_bytes else {
+        return Ok(JobResult::Success);
+    };
+
+    let disk_size = cache::disk_size(config)
+        .await
+        .context("Failed to get cache disk size")?;
+
+    let high_water = (max_cache_size_bytes as f64 * config.gc_high_water) as u64;
+    if disk_size <= high_water {
+        tracing::debug!("Cache takes {disk_size} bytes, within {high_water}");
+        return Ok(JobResult::Success);
+    }
+
+    let nar_disk_size = cache::nar_disk_size(config)
+        .await
+        .context("Failed to get nar disk size")?;
+
+    let low_water = (max_cache_size_bytes as f64 * config.gc_low_water) as u64;
+    let target_bytes = nar_disk_size.saturating_sub(disk_size - low_water);
+
+    tracing::info!(
+        "Cache takes {disk_size} bytes, over {high_water}, evicting nar files down to \
+        {target_bytes} bytes"
+    );
+
+    match workers.push_job(Job::EvictLru { target_bytes }).await {
+        // left to the next check
+        Err(e) if e.is::<QueueFull>() => tracing::warn!("{e}, not evicting"),
+        ret => ret.context("Failed to push job for evicting entries")?,
+    }
+
+    Ok(JobResult::Success)
+}
+
+const MIRROR_PROGRESS_INTERVAL: usize = 100;
+
+#[tracing::instrument(skip(config, cache))]
+pub async fn mirror_channel(
+    config: &config::Config,
+    cache: &cache::Cache,
+    channel: nix::Channel,
+) -> anyhow::Result<JobResult> {
+    let store_paths = fetch::request_channel_store::<Vec<_>>(config, &channel)
+        .await
+        .with_context(|| format!("Failed to request store paths of {channel}"))?;
+
+    let num_paths = store_paths.len();
+
+    // every path is attempted below, so a following sync has nothing new to pick up either way
+    if config.record_channel_membership {
+        let mut tx = transaction!(begin: cache)?;
+        cache::db::set_channel_store_paths(&mut tx, &channel, &store_paths).await?;
+        transaction!(commit: tx)?;
+    }
+
+    tracing::info!("Mirroring {num_paths} store paths of {channel}");
+
+    cache::db::start_channel_mirror(cache.db.pool(), &channel, num_paths).await?;
+
+    let mirror_paths = |hashes: Vec<nix::Hash>| {
+        stream::iter(hashes)
+            .map(|hash| async move {
+                let res = mirror_path(config, cache, &hash).await;
+                (hash, res)
+            })
+            .buffer_unordered(config.mirror_concurrency.max(1))
+    };
+
+    let mut num_done = 0;
+    let mut failed = Vec::new();
+
+    {
+        let hashes = store_paths
+            .into_iter()
+            .map(|path| path.derivation_info.hash)
+            .collect();
+
+        let results = mirror_paths(hashes);
+        futures::pin_mut!(results);
+
+        while let Some((hash, res)) = results.next().await {
+            match res {
+                Ok(true) => num_done += 1,
+                Ok(false) => failed.push(hash),
+                Err(e) => {
+                    tracing::warn!("Failed to mirror {}: {e:#}", hash.string);
+                    failed.push(hash);
+                }
+            }
+
+            if (num_done + failed.len()) % MIRROR_PROGRESS_INTERVAL == 0 {
+                tracing::info!(
+                    "Mirroring {channel}: {}/{num_paths} processed ({} failed)",
+                    num_done + failed.len(),
+                    failed.len()
+                );
+
+                cache::db::update_channel_mirror(
+                    cache.db.pool(),
+                    &channel,
+                    num_done,
+                    failed.len(),
+                    false,
+                )
+                .await?;
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        tracing::info!("Retrying {} failed store paths of {channel}", failed.len());
+
+        let results = mirror_paths(std::mem::take(&mut failed));
+        futures::pin_mut!(results);
+
+        while let Some((hash, res)) = results.next().await {
+            match res {
+                Ok(true) => num_done += 1,
+                Ok(false) => failed.push(hash),
+                Err(e) => {
+                    tracing::warn!("Failed to mirror {} on retry: {e:#}", hash.string);
+                    failed.push(hash);
+                }
+            }
+        }
+    }
+
+    cache::db::update_channel_mirror(cache.db.pool(), &channel, num_done, failed.len(), true)
+        .await?;
+
+    tracing::info!(
+        "Finished mirroring {channel}: {num_done}/{num_paths} cached ({} failed)",
+        failed.len()
+    );
+
+    Ok(JobResult::Success)
+}
+
+async fn mirror_path(
+    config: &config::Config,
+    cache: &cache::Cache,
+    hash: &nix::Hash,
+) -> anyhow::Result<bool> {
+    // paths cached by an earlier, interrupted run are skipped
+    if cache::db::is_cached_by_hash(cache.db.pool(), hash).await? {
+        return Ok(true);
+    }
+
+    cache_nar(config, cache, hash.clone(), false).await?;
+
+    cache::db::is_cached_by_hash(cache.db.pool(), hash).await
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn resume_channel_mirrors(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+) -> anyhow::Result<()> {
+    if config.offline_mode {
+        return Ok(());
+    }
+
+    let mirrors = cache::db::get_channel_mirrors(cache.db.pool())
+        .await
+        .context("Failed to get channel mirror records")?;
+
+    for mirror in mirrors.into_iter().filter(|m| m.finished.is_none()) {
+        tracing::info!(
+            "Resuming mirror of {} ({}/{} processed)",
+            mirror.channel,
+            mirror.num_done + mirror.num_failed,
+            mirror.num_paths
+        );
+
+        let channel = nix::Channel::new(mirror.channel);
+
+        workers
+            .push_job(Job::MirrorChannel { channel })
+            .await
+            .context("Failed to push job for resuming channel mirror")?;
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn enqueue_prefetch(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+) -> anyhow::Result<()> {
+    let prefetch = match &config.prefetch_on_start {
+        Some(_) if config.offline_mode => {
+            tracing::info!("Not prefetching in offline mode");
+            return Ok(());
+        }
+        Some(_) if config.proxy_only => {
+            tracing::info!("Not prefetching in proxy_only mode");
+            return Ok(());
+        }
+        Some(prefetch) => prefetch,
+        None => return Ok(()),
+    };
+
+    tracing::info!("Enqueuing prefetch jobs from {prefetch:?}");
+
+    let entries = prefetch
+        .entries()
+        .await
+        .context("Failed to load prefetch list")?;
+
+    let mut num_enqueued = 0;
+    let mut num_skipped = 0;
+
+    for entry in entries {
+        let hash = match nix::parse_hash_or_store_path(&entry) {
+            Ok(hash) => hash,
+            Err(e) => {
+                tracing::warn!("Skipping invalid prefetch entry {entry:?}: {e}");
+                num_skipped += 1;
+                continue;
+            }
+        };
+
+        if cache::db::is_cached_by_hash(cache.db.pool(), &hash).await? {
+            tracing::debug!("{} already cached, skipping", hash.string);
+            num_skipped += 1;
+            continue;
+        }
+
+        match workers
+            .push_job(Job::CacheNar {
+                hash: hash.clone(),
+                is_force: false,
+                priority: Priority::Low,
+            })
+            .await
+        {
+            Err(e) if e.is::<QueueFull>() => {
+                tracing::warn!("{e}, not enqueuing the rest of the prefetch list");
+                break;
+            }
+            ret => {
+                ret.with_context(|| format!("Failed to push job for caching {}", hash.string))?
+            }
+        }
+
+        num_enqueued += 1;
+    }
+
+    tracing::info!("Enqueued {num_enqueued} prefetch jobs ({num_skipped} skipped)");
+
+    Ok(())
+}
+
+// Force refetches every one of `golden_paths`, keeping them current however rarely they are
+// accessed, unlike channel syncs which only fetch what is not cached yet
+#[tracing::instrument(skip_all)]
+pub async fn refresh_golden(
+    config: &config::Config,
+    cache: &cache::Cache,
+) -> anyhow::Result<Vec<(String, anyhow::Result<JobResult>)>> {
+    let Some(golden_paths) = &config.golden_paths else {
+        return Ok(Vec::new());
+    };
+
+    let entries = golden_paths
+        .entries()
+        .await
+        .context("Failed to load golden paths")?;
+
+    tracing::info!("Refreshing {} golden paths", entries.len());
+
+    Ok(stream::iter(entries)
+        .map(|entry| async move {
+            let res = match nix::parse_hash_or_store_path(&entry) {
+                Ok(hash) => cache_nar(config, cache, hash, true).await,
+                Err(e) => Err(e.into()),
+            };
+
+            match &res {
+                Ok(res) => tracing::info!("Refreshed golden path {entry}: {res:?}"),
+                Err(e) => tracing::warn!("Failed to refresh golden path {entry}: {e:#}"),
+            }
+
+            (entry, res)
+        })
+        .buffer_unordered(config.mirror_concurrency)
+        .collect()
+        .await)
+}
+
+// Enqueues caching of every uncached path in the closure of `hash`, other than `hash` itself
+#[tracing::instrument(skip(config, cache, workers))]
+pub async fn cache_closure(
+    config: &config::Config,
+    cache: &cache::Cache,
+    workers: &mut Workers,
+    hash: nix::Hash,
+) -> anyhow::Result<JobResult> {
+    let closure = fetch::request_closure(
+        config,
+        &hash,
+        config.closure_max_paths,
+        config.closure_max_depth,
+    )
+    .await;
+
+    if closure.is_truncated {
+        tracing::warn!(
+            "Closure of {} exceeds closure_max_paths or closure_max_depth, only caching part of it",
+            hash.string
+        );
+    }
+
+    let mut num_enqueued = 0;
+
+    for nar_info in closure.nar_infos {
+        let member = nar_info.store_path.derivation_info.hash;
+
+        if member.string == hash.string
+            || cache::db::get_status(cache.db.pool(), &member)
+                .await?
+                .is_some()
+        {
+            continue;
+        }
+
+        match workers
+            .push_job(Job::CacheNar {
+                hash: member.clone(),
+                is_force: false,
+                priority: Priority::Low,
+            })
+            .await
+        {
+            Err(e) if e.is::<QueueFull>() => {
+                tracing::warn!("{e}, not enqueuing the rest of the closure");
+                break;
+            }
+            ret => {
+                ret.with_context(|| format!("Failed to push job for caching {}", member.string))?
+            }
+        }
+
+        num_enqueued += 1;
+    }
+
+    tracing::info!(
+        "Enqueued {num_enqueued} jobs for the closure of {} ({} failed to fetch)",
+        hash.string,
+        closure.num_failed
+    );
+
+    Ok(JobResult::Success)
+}
+
+// Recomputes sizes and nar hashes from the nar files on disk, returning the number of corrected
+// entries
+#[tracing::instrument(skip(config, cache))]
+pub async fn backfill_sizes(
+    config: &config::Config,
+    cache: &cache::Cache,
+    concurrency: usize,
+) -> anyhow::Result<usize> {
+    let entries = cache::db::get_available_nar_sizes(cache.db.pool())
+        .await
+        .context("Failed to get narinfo sizes")?;
+
+    tracing::info!("Checking sizes of {} entries", entries.len());
+
+    let results = stream::iter(entries)
+        .map(|entry| async move {
+            let ret = backfill_entry_sizes(config, cache, &entry).await;
+            if let Err(e) = &ret {
+                tracing::warn!("Failed to check sizes of {}: {e:#}", entry.hash);
+            }
+            ret
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok(results
+        .into_iter()
+        .filter(|ret| matches!(ret, Ok(true)))
+        .count())
+}
+
+async fn backfill_entry_sizes(
+    config: &config::Config,
+    cache: &cache::Cache,
+    entry: &cache::db::NarSizes,
+) -> anyhow::Result<bool> {
+    let compression: nix::CompressionType = entry
+        .compression
+        .parse()
+        .context("Failed to parse compression type from cache db")?;
+    let file_hash =
+        nix::Hash::from_method_hash(entry.file_hash_method.clone(), entry.file_hash.clone());
+    let path = cache::nar_file_path_from_parts(config, &file_hash, &compression);
+
+    let (file_size, nar_hash, nar_size) = tokio::task::spawn_blocking(move || {
+        use sha2::Digest as _;
+
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let file_size = file
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {}", path.display()))?
+            .len();
+        let file = std::io::BufReader::new(file);
+
+        let mut hasher = sha2::Sha256::new();
+        let nar_size = match compression {
+            nix::CompressionType::Xz => {
+                std::io::copy(&mut xz2::bufread::XzDecoder::new(file), &mut hasher)
+            }
+            nix::CompressionType::Zstd => zstd::stream::read::Decoder::with_buffer(file)
+                .and_then(|mut decoder| std::io::copy(&mut decoder, &mut hasher)),
+        }
+        .context("Failed to decompress nar file")?;
+
+        Ok::<_, anyhow::Error>((
+            file_size,
+            nix::Hash::from_sha256_digest(&hasher.finalize()),
+            nar_size,
+        ))
+    })
+    .await??;
+
+    if entry.file_size as u64 == file_size
+        && entry.nar_size as u64 == nar_size
+        && entry.nar_hash == nar_hash.string
+    {
+        return Ok(false);
+    }
+
+    tracing::info!(
+        "Correcting {}: file size {} -> {file_size}, nar size {} -> {nar_size}",
+        entry.hash,
+        entry.file_size,
+        entry.nar_size
+    );
+
+    cache::db::set_nar_sizes(cache.db.pool(), &entry.hash, file_size, &nar_hash, nar_size).await?;
+    cache
+        .nar_info_responses
+        .invalidate(&nix::Hash::from_hash(entry.hash.clone()));
+
+    Ok(true)
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 struct Periodic;
 
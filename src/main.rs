@@ -4,12 +4,17 @@ mod config;
 mod fetch;
 mod http;
 mod jobs;
+mod metrics;
 mod nix;
 
 use anyhow::Context as _;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    if std::env::args().skip(1).any(|arg| arg == "--check-config") {
+        std::process::exit(if check_config() { 0 } else { 1 });
+    }
+
     {
         use tracing_subscriber::{filter::EnvFilter, fmt::format::FmtSpan, prelude::*};
 
@@ -49,6 +54,8 @@ async fn main() -> anyhow::Result<()> {
         }));
     }
 
+    metrics::init()?;
+
     let app = app::App::new().await?;
 
     tracing::info!("Nicacher server starting");
@@ -57,3 +64,26 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+fn check_config() -> bool {
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            return false;
+        }
+    };
+
+    // going through `toml::Value` lets tables be emitted after plain values
+    match toml::Value::try_from(&config).and_then(|value| toml::to_string_pretty(&value)) {
+        Ok(config) => println!("{config}"),
+        Err(e) => println!("{config:#?}\n(Unable to render config as toml: {e})"),
+    }
+
+    let errors = config.validate();
+    for e in &errors {
+        eprintln!("Error: {e:#}");
+    }
+
+    errors.is_empty()
+}
@@ -0,0 +1,120 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use crate::{cache, config};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Sizing the data path walks all of it, which is too slow to do on every scrape
+const DISK_SIZE_TTL: Duration = Duration::from_secs(60);
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+static DISK_SIZE: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Fetch {
+    NarInfo,
+    NarFile,
+}
+
+pub fn init() -> anyhow::Result<()> {
+    let handle = PrometheusBuilder::new()
+        .install_recorder()
+        .context("Failed to install Prometheus recorder")?;
+
+    if PROMETHEUS.set(handle).is_err() {
+        anyhow::bail!("Prometheus recorder is already installed");
+    }
+
+    ::metrics::describe_counter!(
+        "nicacher_narinfo_requests_total",
+        "Narinfo requests, by whether they were cached"
+    );
+    ::metrics::describe_counter!(
+        "nicacher_nar_requests_total",
+        "Nar file requests, by whether they were served from the cache"
+    );
+    ::metrics::describe_counter!(
+        "nicacher_nar_served_bytes_total",
+        "Bytes of nar files served from the cache"
+    );
+    ::metrics::describe_counter!(
+        "nicacher_upstream_fetches_total",
+        "Fetches from the upstreams, by what was fetched and whether it succeeded"
+    );
+    ::metrics::describe_gauge!(
+        "nicacher_cached_paths",
+        "Store paths available in the cache"
+    );
+    ::metrics::describe_gauge!(
+        "nicacher_disk_size_bytes",
+        "Size of the local data path, updated at most once a minute"
+    );
+
+    Ok(())
+}
+
+pub fn record_nar_info(is_hit: bool) {
+    ::metrics::increment_counter!("nicacher_narinfo_requests_total", "result" => result(is_hit));
+}
+
+pub fn record_nar_file(is_hit: bool, num_bytes: u64) {
+    ::metrics::increment_counter!("nicacher_nar_requests_total", "result" => result(is_hit));
+    ::metrics::counter!("nicacher_nar_served_bytes_total", num_bytes);
+}
+
+pub fn record_upstream_fetch(fetch: Fetch, is_success: bool) {
+    let kind = match fetch {
+        Fetch::NarInfo => "narinfo",
+        Fetch::NarFile => "nar",
+    };
+    let result = if is_success { "success" } else { "failure" };
+
+    ::metrics::increment_counter!("nicacher_upstream_fetches_total", "kind" => kind, "result" => result);
+}
+
+fn result(is_hit: bool) -> &'static str {
+    if is_hit {
+        "hit"
+    } else {
+        "miss"
+    }
+}
+
+// Gauges are only updated here, as they are read off the cache rather than counted as they change
+pub async fn render(config: &config::Config, cache: &cache::Cache) -> anyhow::Result<String> {
+    let num_cached = cache::db::get_num_store_paths(cache.db.pool())
+        .await
+        .context("Failed to get number of cached store paths")?;
+    ::metrics::gauge!("nicacher_cached_paths", num_cached as f64);
+
+    match disk_size(config).await {
+        Ok(disk_size) => ::metrics::gauge!("nicacher_disk_size_bytes", disk_size as f64),
+        // none in proxy_only mode, where nothing is written to the local data path
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(anyhow::Error::new(e).context("Failed to get cache disk size")),
+    }
+
+    Ok(PROMETHEUS
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default())
+}
+
+async fn disk_size(config: &config::Config) -> tokio::io::Result<u64> {
+    if let Some((measured, disk_size)) = *DISK_SIZE.lock().unwrap() {
+        if measured.elapsed() < DISK_SIZE_TTL {
+            return Ok(disk_size);
+        }
+    }
+
+    let disk_size = cache::disk_size(config).await?;
+    *DISK_SIZE.lock().unwrap() = Some((Instant::now(), disk_size));
+
+    Ok(disk_size)
+}
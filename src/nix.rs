@@ -1,3 +1,7 @@
+mod signing;
+
+pub use signing::SecretKey;
+
 use std::{
     fmt,
     path::{Path, PathBuf},
@@ -10,6 +14,11 @@ use serde_with::{DeserializeFromStr, SerializeDisplay};
 
 pub const NARINFO_MIME: &str = "text/x-nix-narinfo";
 pub const NAR_FILE_MIME: &str = "application/x-nix-nar";
+pub const NIX_CACHE_INFO_MIME: &str = "text/x-nix-cache-info";
+
+pub const UNKNOWN_DERIVER: &str = "unknown-deriver";
+
+const NIX32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
 
 macro_rules! string_newtype_variant {
     ($method_fn:ident, $method_str:expr) => {
@@ -36,10 +45,84 @@ pub struct NarInfo {
     pub system: Option<String>,
     pub references: Vec<DerivationInfo>,
     #[builder(default)]
-    pub signature: Option<String>,
+    pub signatures: Vec<String>,
+}
+
+impl NarInfo {
+    pub fn normalize_unknown_deriver(&mut self) {
+        if self.deriver.as_deref() == Some(UNKNOWN_DERIVER) {
+            self.deriver = None;
+        }
+    }
+
+    // Upstreams may point `URL` anywhere, while ours is always `nar/<file name>` under the cache
+    pub fn relativize_url(&mut self) {
+        let Ok(url) = url::Url::parse(&self.url) else {
+            return;
+        };
+
+        if let Some(file_name) = url
+            .path_segments()
+            .and_then(Iterator::last)
+            .filter(|file_name| !file_name.is_empty())
+        {
+            self.url = format!("nar/{file_name}");
+        }
+    }
+
+    // What a `Sig` signs, as in `ValidPathInfo::fingerprint` of Nix
+    pub fn fingerprint(&self) -> String {
+        let root = &self.store_path.store_path_root;
+        let mut references = self
+            .references
+            .iter()
+            .map(|reference| root.join(reference.name()).to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        references.sort();
+
+        format!(
+            "1;{};{};{};{}",
+            self.store_path.path().display(),
+            self.nar_hash.clone().normalized(),
+            self.nar_size,
+            references.join(",")
+        )
+    }
+
+    pub fn has_field(&self, field: NarInfoField) -> bool {
+        match field {
+            NarInfoField::Deriver => self.deriver.is_some(),
+            NarInfoField::System => self.system.is_some(),
+            NarInfoField::Sig => !self.signatures.is_empty(),
+        }
+    }
+}
+
+pub fn sign_nar_info(nar_info: &NarInfo, key: &SecretKey) -> String {
+    key.sign(nar_info.fingerprint().as_bytes())
+}
+
+// Fields a narinfo may leave out, named by their keys
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum NarInfoField {
+    Deriver,
+    System,
+    Sig,
+}
+
+impl fmt::Display for NarInfoField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Deriver => write!(f, "Deriver"),
+            Self::System => write!(f, "System"),
+            Self::Sig => write!(f, "Sig"),
+        }
+    }
 }
 
 impl fmt::Display for NarInfo {
+    // Field order and spacing follow `NarInfo::to_string` in Nix: every line, including the last,
+    // ends with `\n`, and `References: ` keeps its trailing space when there are none
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -61,6 +144,13 @@ NarSize: {}
             self.nar_size,
         )?;
 
+        let references = self
+            .references
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        writeln!(f, "References: {}", references.join(" "))?;
+
         if let Some(ref deriver) = self.deriver {
             writeln!(f, "Deriver: {deriver}")?;
         }
@@ -69,11 +159,7 @@ NarSize: {}
             writeln!(f, "System: {system}")?;
         }
 
-        write!(f, "References:")?;
-        self.references.iter().try_for_each(|d| write!(f, " {d}"))?;
-        writeln!(f)?;
-
-        if let Some(ref signature) = self.signature {
+        for signature in &self.signatures {
             writeln!(f, "Sig: {signature}")?;
         }
 
@@ -104,6 +190,8 @@ impl FromStr for NarInfo {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut nar_info_builder = NarInfoBuilder::default();
+        // one line per key that signed it
+        let mut signatures = Vec::new();
 
         for line in s.lines() {
             if let Some((key, value)) = line.split_once(':') {
@@ -149,7 +237,10 @@ impl FromStr for NarInfo {
                             .collect::<Result<Vec<_>, _>>()
                             .map_err(Self::Err::InvalidReference)?,
                     ),
-                    "Sig" => nar_info_builder.signature(Some(value.into())),
+                    "Sig" => {
+                        signatures.push(value.to_owned());
+                        &mut nar_info_builder
+                    }
                     _ => return Err(Self::Err::UnknownField(line.to_owned())),
                 };
             } else {
@@ -157,7 +248,10 @@ impl FromStr for NarInfo {
             }
         }
 
-        nar_info_builder.build().map_err(Self::Err::MissingField)
+        nar_info_builder
+            .signatures(signatures)
+            .build()
+            .map_err(Self::Err::MissingField)
     }
 }
 
@@ -169,10 +263,27 @@ impl TryFrom<&str> for NarInfo {
     }
 }
 
-#[derive(Debug)]
 pub struct NarFile {
-    pub info: NarFileInfo,
-    pub data: bytes::Bytes,
+    pub compression: CompressionType,
+    // `None` when the narinfo's FileHash does not describe it, as for an alternative compression
+    pub hash: Option<Hash>,
+    // Where it is fetched from, absolute `URL`s and alternative compressions resolved
+    pub url: url::Url,
+    // Bytes of it left in the partial file by an interrupted download, which `body` continues from
+    pub offset: u64,
+    // Still being downloaded, only read as it is written out
+    pub body: futures::stream::BoxStream<'static, anyhow::Result<bytes::Bytes>>,
+}
+
+impl fmt::Debug for NarFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NarFile")
+            .field("compression", &self.compression)
+            .field("hash", &self.hash)
+            .field("url", &self.url)
+            .field("offset", &self.offset)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, DeserializeFromStr)]
@@ -183,7 +294,12 @@ pub struct NarFileInfo {
 
 impl fmt::Display for NarFileInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}.nar.{}", self.hash.string, self.compression)
+        write!(
+            f,
+            "{}.nar.{}",
+            self.hash.string,
+            self.compression.extension()
+        )
     }
 }
 
@@ -192,9 +308,9 @@ impl FromStr for NarFileInfo {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.splitn(3, '.').collect::<Vec<&str>>().as_slice() {
-            &[hash, "nar", compression] => Ok(Self {
+            &[hash, "nar", extension] => Ok(Self {
                 hash: hash.parse()?,
-                compression: compression.parse()?,
+                compression: CompressionType::from_extension(extension)?,
             }),
 
             _ => anyhow::bail!("Invalid nar file format: {s}"),
@@ -206,6 +322,8 @@ impl FromStr for NarFileInfo {
 pub struct Derivation {
     pub info: DerivationInfo,
     pub nar_info: NarInfo,
+    // The narinfo exactly as served by the upstream
+    pub raw_nar_info: String,
     pub nar_file: NarFile,
     pub upstream: Upstream,
 }
@@ -256,6 +374,10 @@ impl fmt::Display for DerivationInfo {
 pub struct Channel(String);
 
 impl Channel {
+    pub fn new(name: String) -> Self {
+        Self(name)
+    }
+
     string_newtype_variant!(NixosUnstable, "nixos-unstable");
     string_newtype_variant!(NixpkgsUnstable, "nixpkgs-unstable");
 }
@@ -300,6 +422,29 @@ impl Hash {
         }
     }
 
+    pub fn sha256(data: &[u8]) -> Self {
+        use sha2::Digest as _;
+
+        Self::from_sha256_digest(&sha2::Sha256::digest(data))
+    }
+
+    pub fn from_sha256_digest(digest: &[u8]) -> Self {
+        Self {
+            method: Some(HashMethod::Sha256()),
+            string: encode_nix32(digest),
+        }
+    }
+
+    pub fn normalized(self) -> Self {
+        match decode_base16(&self.string) {
+            Some(bytes) if bytes.len() == 32 => Self {
+                method: self.method,
+                string: encode_nix32(&bytes),
+            },
+            _ => self,
+        }
+    }
+
     pub fn from_method_hash(method: String, string: String) -> Self {
         Self {
             method: Some(HashMethod(method)),
@@ -338,7 +483,54 @@ impl TryFrom<&str> for Hash {
     }
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+fn decode_base16(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_nix32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let c = (bytes[i] >> j)
+                | bytes
+                    .get(i + 1)
+                    .map_or(0, |next| next.checked_shl(8 - j as u32).unwrap_or(0));
+            NIX32_CHARS[(c & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HashOrStorePathParseError {
+    #[error("Invalid hash: {0}")]
+    InvalidHash(HashParseError),
+    #[error("Invalid store path: {0}")]
+    InvalidStorePath(StorePathParseError),
+}
+
+pub fn parse_hash_or_store_path(s: &str) -> Result<Hash, HashOrStorePathParseError> {
+    if s.starts_with('/') {
+        s.parse::<StorePath>()
+            .map(|store_path| store_path.derivation_info.hash)
+            .map_err(HashOrStorePathParseError::InvalidStorePath)
+    } else {
+        s.parse::<Hash>()
+            .map_err(HashOrStorePathParseError::InvalidHash)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HashMethod(String);
 
 impl HashMethod {
@@ -367,6 +559,28 @@ impl StorePath {
     pub fn path(&self) -> PathBuf {
         self.store_path_root.join(self.derivation_info.name())
     }
+
+    // Lexically normalizes the root (`.`, `..`, repeated or trailing `/`), resolving a relative
+    // root against `store_dir`
+    pub fn canonicalized(self, store_dir: &Path) -> Self {
+        use std::path::Component;
+
+        let mut store_path_root = PathBuf::new();
+        for component in store_dir.join(&self.store_path_root).components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    store_path_root.pop();
+                }
+                component => store_path_root.push(component),
+            }
+        }
+
+        Self {
+            store_path_root,
+            ..self
+        }
+    }
 }
 
 impl std::hash::Hash for StorePath {
@@ -430,7 +644,7 @@ impl Eq for StorePath {}
 
 impl PartialOrd for StorePath {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.path().partial_cmp(&other.path())
+        Some(self.cmp(other))
     }
 }
 
@@ -440,10 +654,38 @@ impl Ord for StorePath {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum CompressionType {
     Xz,
+    Zstd,
+}
+
+impl CompressionType {
+    pub const SUPPORTED: &'static [Self] = &[Self::Xz, Self::Zstd];
+
+    // The suffix after `.nar.` in nar file names, which is not always the name itself
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Xz => "xz",
+            Self::Zstd => "zst",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Xz => "application/x-xz",
+            Self::Zstd => "application/zstd",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Result<Self, CompressionTypeParseError> {
+        Self::SUPPORTED
+            .iter()
+            .find(|compression| compression.extension() == extension)
+            .cloned()
+            .ok_or_else(|| CompressionTypeParseError(extension.to_owned()))
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -456,6 +698,7 @@ impl FromStr for CompressionType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(match s {
             "xz" => Self::Xz,
+            "zstd" => Self::Zstd,
             _ => return Err(CompressionTypeParseError(s.to_owned())),
         })
     }
@@ -465,6 +708,7 @@ impl fmt::Display for CompressionType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Xz => write!(f, "xz"),
+            Self::Zstd => write!(f, "zstd"),
         }
     }
 }
@@ -473,10 +717,6 @@ impl fmt::Display for CompressionType {
 pub struct Upstream(url::Url);
 
 impl Upstream {
-    pub fn new(url: url::Url) -> Self {
-        Self(url)
-    }
-
     pub fn url(&self) -> &url::Url {
         &self.0
     }
@@ -489,6 +729,9 @@ pub struct PriorityUpstream {
     inner: Upstream,
     #[serde(default)]
     priority: Priority,
+    // Only useful with upstreams serving each nar in several compressions under the same name
+    #[serde(default)]
+    preferred_compression: Vec<CompressionType>,
 }
 
 impl PriorityUpstream {
@@ -496,12 +739,17 @@ impl PriorityUpstream {
         Self {
             inner: Upstream(url),
             priority: Priority::default(),
+            preferred_compression: Vec::new(),
         }
     }
 
     pub fn url(&self) -> &url::Url {
         &self.inner.0
     }
+
+    pub fn preferred_compression(&self) -> &[CompressionType] {
+        &self.preferred_compression
+    }
 }
 
 impl AsRef<Upstream> for PriorityUpstream {
@@ -518,11 +766,7 @@ impl From<PriorityUpstream> for Upstream {
 
 impl PartialOrd for PriorityUpstream {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.priority.partial_cmp(&other.priority) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.inner.partial_cmp(&other.inner)
+        Some(self.cmp(other))
     }
 }
 
@@ -0,0 +1,70 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use anyhow::Context as _;
+use ring::signature::Ed25519KeyPair;
+
+// `<name>:<base64 of the ed25519 seed followed by its public key>`, as made by
+// `nix-store --generate-binary-cache-key`
+pub struct SecretKey {
+    name: String,
+    key_pair: Ed25519KeyPair,
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecretKey {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read secret key from {}", path.display()))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid secret key in {}", path.display()))
+    }
+
+    // By name only, as with Nix matching a `Sig` against its trusted public keys
+    pub fn is_signer_of(&self, signature: &str) -> bool {
+        signature
+            .split_once(':')
+            .is_some_and(|(name, _)| name == self.name)
+    }
+
+    // In the `<name>:<base64>` form of a `Sig` value
+    pub fn sign(&self, message: &[u8]) -> String {
+        format!(
+            "{}:{}",
+            self.name,
+            base64::encode(self.key_pair.sign(message))
+        )
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, key) = match s.split_once(':') {
+            Some((name, key)) if !name.is_empty() => (name, key),
+            _ => anyhow::bail!("Missing key name"),
+        };
+
+        let key = base64::decode(key).context("Key is not valid base64")?;
+        if key.len() != 64 {
+            anyhow::bail!("Key is {} bytes, not 64", key.len());
+        }
+
+        let (seed, public_key) = key.split_at(32);
+        let key_pair = Ed25519KeyPair::from_seed_and_public_key(seed, public_key)
+            .map_err(|e| anyhow::anyhow!("Invalid ed25519 key: {e}"))?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            key_pair,
+        })
+    }
+}